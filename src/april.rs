@@ -1,46 +1,172 @@
 //! This module contains the parser for APRIL (AOSC Package Reconstruction Information Listing)
 
-use anyhow::{Result, bail};
 use deb822_lossless::{Deb822, Paragraph};
+use indexmap::IndexMap;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::{borrow::Cow, collections::HashMap};
+use std::{borrow::Cow, fmt::Display, path::Path};
+
+use crate::{
+    april_version::{check_version_compatibility, validate_version_expr},
+    error::{AprilError, Result},
+    relationship::{canonicalize_relationships, parse_relationships},
+};
 
 const fn default_false() -> bool {
     false
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum AprilTriggerKind {
+    Interest,
+    InterestAwait,
+    InterestNoawait,
+    Activate,
+    ActivateAwait,
+    ActivateNoawait,
+}
+
+impl AprilTriggerKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AprilTriggerKind::Interest => "interest",
+            AprilTriggerKind::InterestAwait => "interest-await",
+            AprilTriggerKind::InterestNoawait => "interest-noawait",
+            AprilTriggerKind::Activate => "activate",
+            AprilTriggerKind::ActivateAwait => "activate-await",
+            AprilTriggerKind::ActivateNoawait => "activate-noawait",
+        }
+    }
+}
+
+/// A single directive line in `DEBIAN/triggers`, see deb-triggers(5)
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[schemars(deny_unknown_fields)]
+pub struct AprilTriggerDirective {
+    /// The trigger directive
+    kind: AprilTriggerKind,
+    /// trigger name or an absolute path (for file triggers)
+    name: String,
+}
+
+/// Triggers can either be specified as raw file content or as a list of structured directives
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum AprilTriggersOverride {
+    Raw(String),
+    Directives(Vec<AprilTriggerDirective>),
+}
+
+impl AprilTriggersOverride {
+    /// Render the override into the content of `DEBIAN/triggers`
+    pub fn render(&self) -> String {
+        match self {
+            AprilTriggersOverride::Raw(content) => content.clone(),
+            AprilTriggersOverride::Directives(directives) => {
+                let mut content = String::new();
+                for directive in directives {
+                    content.push_str(directive.kind.as_str());
+                    content.push(' ');
+                    content.push_str(&directive.name);
+                    content.push('\n');
+                }
+                content
+            }
+        }
+    }
+}
+
+/// The new package scripts. Leaving script content an empty string to disable the script (if the original package has this script)
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[schemars(deny_unknown_fields)]
 pub struct AprilPackageScriptOverrides {
+    /// The new pre-removal script
     prerm: Option<String>,
+    /// The new post-removal script
     postrm: Option<String>,
+    /// The new pre-installation script
     preinst: Option<String>,
+    /// The new post-installation script
     postinst: Option<String>,
-    triggers: Option<String>,
+    /// The new triggers script, either as raw content or as a list of trigger directives
+    triggers: Option<AprilTriggersOverride>,
+    /// Content inserted before the body of the scripts (after the `#!` line), the scripts are created if missing
+    prepend: Option<AprilScriptPrepends>,
+    /// The phase during which the scripts are patched: unpack (before dpkg runs them) or postinst (after configuring the package)
+    #[serde(default = "default_unpack")]
+    phase: AprilFileOperationPhase,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Content prepended to the package scripts
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[schemars(deny_unknown_fields)]
+pub struct AprilScriptPrepends {
+    prerm: Option<String>,
+    postrm: Option<String>,
+    preinst: Option<String>,
+    postinst: Option<String>,
+}
+
+/// Overrides for the binary package
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[schemars(deny_unknown_fields)]
 pub struct AprilPackageOverrides {
+    /// The new package name
     name: Option<String>,
+    /// The package version to use after the patch
     version: Option<String>,
+    /// The overridden CPU architecture of the package
     arch: Option<String>,
+    /// True if the package is essential for the system (default: same as the original package, or false if total_conversion is true)
     essential: Option<bool>,
+    /// The size of the installed package in kilobytes
     installed_size: Option<u64>,
+    /// The new package section
     section: Option<String>,
+    /// The new package description
     description: Option<String>,
+    /// The new package maintainer
+    maintainer: Option<String>,
+    /// The new upstream project homepage
+    homepage: Option<String>,
+    /// The new source package name
+    source: Option<String>,
+    /// The new package origin
+    origin: Option<String>,
+    /// The new package required dependencies
     depends: Option<Vec<String>>,
+    /// The new package recommended dependencies
     recommends: Option<Vec<String>>,
+    /// The new package suggested dependencies
     suggests: Option<Vec<String>>,
+    /// The new package enhanced packages
     enhances: Option<Vec<String>>,
+    /// The new package pre-dependencies
     pre_depends: Option<Vec<String>>,
+    /// The new package dependency breaks
     breaks: Option<Vec<String>>,
+    /// The new package conflicts
     conflicts: Option<Vec<String>>,
+    /// The new package replaced dependencies
     replaces: Option<Vec<String>>,
+    /// The new package provided dependencies
     provides: Option<Vec<String>>,
     scripts: Option<AprilPackageScriptOverrides>,
+    /// The new list of configuration files. An empty array means untracking all configuration files.
     conffiles: Option<Vec<String>>,
+    /// When appending to `pre_depends`, `depends`, `recommends` or `suggests` a relationship on a package
+    /// already in the field, keep only the stricter version constraint instead of both (default: false)
+    #[serde(default = "default_false")]
+    merge_relations: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The phase during which the file operation should be performed: unpack (after preinst but before postinst) and postinst (after running postinst script)
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub enum AprilFileOperationPhase {
     #[serde(rename = "unpack")]
     Unpack,
@@ -52,7 +178,8 @@ const fn default_unpack() -> AprilFileOperationPhase {
     AprilFileOperationPhase::Unpack
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// The action to perform on the file, `arg` is the data or argument used by the file operation
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(tag = "action", content = "arg", rename_all = "kebab-case")]
 pub enum AprilFileOperationType {
     Remove,
@@ -65,38 +192,295 @@ pub enum AprilFileOperationType {
     Track,
     Overwrite(String),
     Add(String),
+    /// append the content of the resource to the file, the file is created if it does not exist
+    AppendContent(String),
     Chmod(u16),
+    /// empty the file, its mode and ownership are kept
+    Truncate,
+    /// change the owner and group of the file, the ownership is kept in the reconstructed package
+    Chown {
+        uid: u32,
+        gid: u32,
+    },
     Mkdir,
+    /// replace all the occurrences of `pattern` in the file with `replacement`, if `regex` is true
+    /// `pattern` is a regular expression and `replacement` can refer to its groups like `$1`
+    Substitute {
+        pattern: String,
+        replacement: String,
+        #[serde(default = "default_false")]
+        regex: bool,
+    },
+}
+
+/// A single file operation, or a list of file operations performed on the same path in order
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum AprilFileOperations {
+    Single(AprilFileOperation),
+    Multiple(Vec<AprilFileOperation>),
+}
+
+// Not derived with `#[serde(untagged)]`, which hides the error of the operation (e.g. a misspelled
+// field) behind "data did not match any variant"
+impl<'de> Deserialize<'de> for AprilFileOperations {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct OperationsVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for OperationsVisitor {
+            type Value = AprilFileOperations;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a file operation or a list of file operations")
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                map: A,
+            ) -> Result<Self::Value, A::Error> {
+                let deserializer = serde::de::value::MapAccessDeserializer::new(map);
+                AprilFileOperation::deserialize(deserializer).map(AprilFileOperations::Single)
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                seq: A,
+            ) -> Result<Self::Value, A::Error> {
+                let deserializer = serde::de::value::SeqAccessDeserializer::new(seq);
+                Vec::deserialize(deserializer).map(AprilFileOperations::Multiple)
+            }
+        }
+
+        deserializer.deserialize_any(OperationsVisitor)
+    }
+}
+
+impl AprilFileOperations {
+    fn iter(&self) -> std::slice::Iter<'_, AprilFileOperation> {
+        match self {
+            AprilFileOperations::Single(operation) => std::slice::from_ref(operation).iter(),
+            AprilFileOperations::Multiple(operations) => operations.iter(),
+        }
+    }
+
+    fn iter_mut(&mut self) -> std::slice::IterMut<'_, AprilFileOperation> {
+        match self {
+            AprilFileOperations::Single(operation) => std::slice::from_mut(operation).iter_mut(),
+            AprilFileOperations::Multiple(operations) => operations.iter_mut(),
+        }
+    }
+
+    /// The operations paired with the path they apply to, operations following a `move` apply
+    /// to the new location
+    fn with_paths<'a>(&'a self, path: &'a str) -> Vec<(&'a str, &'a AprilFileOperation)> {
+        let mut current = path;
+        self.iter()
+            .map(|operation| {
+                let target = current;
+                if let AprilFileOperationType::Move(dst) = &operation.operation {
+                    current = dst;
+                }
+                (target, operation)
+            })
+            .collect()
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[schemars(deny_unknown_fields)]
 pub struct AprilFileOperation {
     #[serde(default = "default_unpack")]
     phase: AprilFileOperationPhase,
     #[serde(flatten)]
     operation: AprilFileOperationType,
+    /// Do not fail if the path is a glob pattern that matches no files
+    #[serde(default = "default_false")]
+    allow_empty: bool,
+    /// Skip the operation (instead of failing) if the file does not exist, e.g. for files that only some builds of the package have
+    #[serde(default = "default_false")]
+    optional: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// AOSC Package Reconstruction Information Listing
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[schemars(deny_unknown_fields)]
 pub struct AprilPackage {
+    /// The schema version of this information listing. Currently only version 0 is supported
+    #[schemars(extend("enum" = ["0"]))]
     schema: String,
+    /// The name of the package to patch
     name: String,
+    /// The version expression that describes patch compatibility
     compatible_versions: String,
+    /// True to mark all package metadata as untrustworthy and re-construct the entire package from this information listing.
     #[serde(default = "default_false")]
     total_conversion: bool,
     overrides: AprilPackageOverrides,
-    files: Option<HashMap<String, AprilFileOperation>>,
+    /// Extra file operations to perform on the files inside specified binary package,
+    /// the operations of the same phase are performed in the order they are declared
+    files: Option<IndexMap<String, AprilFileOperations>>,
 }
 
-#[derive(Debug)]
+impl AprilPackage {
+    /// Call `f` on every value that may contain placeholders: the overrides (except the scripts),
+    /// and the destinations and resource URIs of the file operations
+    fn try_for_each_templated_value(
+        &mut self,
+        mut f: impl FnMut(&mut String) -> Result<()>,
+    ) -> Result<()> {
+        let overrides = &mut self.overrides;
+        for value in [
+            &mut overrides.name,
+            &mut overrides.version,
+            &mut overrides.arch,
+            &mut overrides.section,
+            &mut overrides.description,
+            &mut overrides.maintainer,
+            &mut overrides.homepage,
+            &mut overrides.source,
+            &mut overrides.origin,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            f(value)?;
+        }
+        for values in [
+            &mut overrides.depends,
+            &mut overrides.recommends,
+            &mut overrides.suggests,
+            &mut overrides.enhances,
+            &mut overrides.pre_depends,
+            &mut overrides.breaks,
+            &mut overrides.conflicts,
+            &mut overrides.replaces,
+            &mut overrides.provides,
+            &mut overrides.conffiles,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            for value in values {
+                f(value)?;
+            }
+        }
+        for operation in self
+            .files
+            .iter_mut()
+            .flat_map(|files| files.values_mut())
+            .flat_map(|operations| operations.iter_mut())
+        {
+            match &mut operation.operation {
+                AprilFileOperationType::Move(value)
+                | AprilFileOperationType::Copy(value)
+                | AprilFileOperationType::Link(value)
+                | AprilFileOperationType::Patch(value)
+                | AprilFileOperationType::BinaryPatch(value)
+                | AprilFileOperationType::Divert(value)
+                | AprilFileOperationType::Overwrite(value)
+                | AprilFileOperationType::Add(value)
+                | AprilFileOperationType::AppendContent(value) => f(value)?,
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Expand the placeholders like `${VERSION}` in `value` with `resolve`, `$${` is a literal `${`
+fn expand_placeholders(value: &str, resolve: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let (before, placeholder) = rest.split_at(start);
+        if let Some(before) = before.strip_suffix('$') {
+            expanded.push_str(before);
+            expanded.push_str("${");
+            rest = &placeholder[2..];
+            continue;
+        }
+        expanded.push_str(before);
+        let end = placeholder.find('}');
+        let resolved = end.and_then(|end| resolve(&placeholder[2..end]));
+        let (Some(end), Some(resolved)) = (end, resolved) else {
+            return Err(AprilError::UnresolvedPlaceholder {
+                placeholder: end
+                    .map_or(placeholder, |end| &placeholder[..=end])
+                    .to_string(),
+                value: value.to_string(),
+            });
+        };
+        expanded.push_str(&resolved);
+        rest = &placeholder[end + 1..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+/// Whether the value is templated (and can only be checked after the placeholders are substituted)
+fn has_placeholders(value: &str) -> bool {
+    value.contains("${")
+}
+
+/// Substitute the placeholders in the configuration values: `${VERSION}` is the version of the
+/// original package and `${env:NAME}` is the value of the environment variable `NAME`, looked up
+/// with `env` (e.g. `|name| std::env::var(name).ok()`). The substituted values are validated again.
+pub fn substitute_placeholders(
+    data: &mut AprilPackage,
+    version: &str,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<()> {
+    data.try_for_each_templated_value(|value| {
+        *value = expand_placeholders(value, |name| match name {
+            "VERSION" => Some(version.to_string()),
+            _ => env(name.strip_prefix("env:")?),
+        })?;
+        Ok(())
+    })?;
+
+    check_april_data(data, false)
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum AprilActionType {
     Append,
+    /// insert before the existing content (after the shebang line for scripts)
+    Prepend,
     Replace,
     Remove,
 }
 
+/// The action on a control field, relationship fields can also have relations merged into them
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AprilFieldActionType {
+    Append,
+    Prepend,
+    /// append a relation, replacing the existing relations on the same package
+    Merge,
+    Replace,
+    Remove,
+}
+
+impl From<AprilActionType> for AprilFieldActionType {
+    fn from(action: AprilActionType) -> Self {
+        match action {
+            AprilActionType::Append => AprilFieldActionType::Append,
+            AprilActionType::Prepend => AprilFieldActionType::Prepend,
+            AprilActionType::Replace => AprilFieldActionType::Replace,
+            AprilActionType::Remove => AprilFieldActionType::Remove,
+        }
+    }
+}
+
 /// Planned actions to be taken on the package (contains internal details)
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum AprilAction {
     /// run pre-configuration scripts (before running any dpkg commands)
     PreconfigPackage,
@@ -112,7 +496,9 @@ pub enum AprilAction {
     PatchField {
         field: Cow<'static, str>,
         value: String,
-        action: AprilActionType,
+        action: AprilFieldActionType,
+        /// the `Package` name of the control paragraph to patch, the first paragraph is patched if not specified
+        package: Option<String>,
     },
     /// clear all control fields and scripts in package
     DropControlData,
@@ -125,17 +511,205 @@ pub enum AprilAction {
         content: Option<String>,
         action: AprilActionType,
     },
-    /// manipulate specified file with specified action
+    /// manipulate specified file with specified action, `path` may be a glob pattern
+    /// (`*`, `?` and `[...]` within a path component) matching multiple files
     PatchFile {
         path: String,
         action: AprilFileOperationType,
+        /// whether a glob pattern matching no files is allowed
+        allow_empty: bool,
+        /// whether the operation is skipped if the file does not exist
+        optional: bool,
     },
 }
 
+impl AprilAction {
+    /// Manipulate the file with the action, the file must exist and a glob pattern must match
+    /// some files
+    pub fn patch_file(path: impl Into<String>, action: AprilFileOperationType) -> Self {
+        AprilAction::PatchFile {
+            path: path.into(),
+            action,
+            allow_empty: false,
+            optional: false,
+        }
+    }
+}
+
+impl Display for AprilAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AprilAction::PreconfigPackage => write!(f, "run pre-configuration scripts"),
+            AprilAction::UnpackPackage => write!(f, "unpack the package"),
+            AprilAction::ExtractPackage => write!(f, "extract the package"),
+            AprilAction::ConfigurePackage => write!(f, "configure the package"),
+            AprilAction::InstallPackage => write!(f, "install the package"),
+            AprilAction::PatchField {
+                field,
+                value,
+                action,
+                ..
+            } => match action {
+                AprilFieldActionType::Append => {
+                    write!(f, "append '{}' to field {}", value, field)
+                }
+                AprilFieldActionType::Prepend => {
+                    write!(f, "prepend '{}' to field {}", value, field)
+                }
+                AprilFieldActionType::Merge => write!(f, "merge '{}' into field {}", value, field),
+                AprilFieldActionType::Replace if value.is_empty() => {
+                    write!(f, "remove field {}", field)
+                }
+                AprilFieldActionType::Replace => write!(f, "set field {} to '{}'", field, value),
+                AprilFieldActionType::Remove => {
+                    write!(f, "remove '{}' from field {}", value, field)
+                }
+            },
+            AprilAction::DropControlData => write!(f, "drop all control fields and scripts"),
+            AprilAction::PutControlChunk { .. } => write!(f, "add control paragraphs"),
+            AprilAction::PatchScript { file, action, .. } => match action {
+                AprilActionType::Append => write!(f, "append to DEBIAN/{}", file),
+                AprilActionType::Prepend => write!(f, "prepend to DEBIAN/{}", file),
+                AprilActionType::Replace => write!(f, "replace DEBIAN/{}", file),
+                AprilActionType::Remove => write!(f, "remove DEBIAN/{}", file),
+            },
+            AprilAction::PatchFile { path, action, .. } => match action {
+                AprilFileOperationType::Remove => write!(f, "remove {}", path),
+                AprilFileOperationType::Move(dst) => write!(f, "move {} to {}", path, dst),
+                AprilFileOperationType::Copy(dst) => write!(f, "copy {} to {}", path, dst),
+                AprilFileOperationType::Link(dst) => write!(f, "link {} to {}", dst, path),
+                AprilFileOperationType::Patch(uri) => write!(f, "patch {} with {}", path, uri),
+                AprilFileOperationType::BinaryPatch(uri) => {
+                    write!(f, "binary patch {} with {}", path, uri)
+                }
+                AprilFileOperationType::Divert(dst) => write!(f, "divert {} to {}", path, dst),
+                AprilFileOperationType::Track => write!(f, "track {}", path),
+                AprilFileOperationType::Truncate => write!(f, "truncate {}", path),
+                AprilFileOperationType::Overwrite(uri) => {
+                    write!(f, "overwrite {} with {}", path, uri)
+                }
+                AprilFileOperationType::Add(uri) => write!(f, "add {} from {}", path, uri),
+                AprilFileOperationType::AppendContent(uri) => {
+                    write!(f, "append {} to {}", uri, path)
+                }
+                AprilFileOperationType::Chmod(mode) => write!(f, "chmod {:o} {}", mode, path),
+                AprilFileOperationType::Chown { uid, gid } => {
+                    write!(f, "chown {}:{} {}", uid, gid, path)
+                }
+                AprilFileOperationType::Mkdir => write!(f, "create directory {}", path),
+                AprilFileOperationType::Substitute {
+                    pattern,
+                    replacement,
+                    ..
+                } => write!(
+                    f,
+                    "substitute '{}' with '{}' in {}",
+                    pattern, replacement, path
+                ),
+            },
+        }
+    }
+}
+
+/// Generate the JSON schema describing APRIL configuration files
+pub fn april_json_schema() -> serde_json::Value {
+    schemars::schema_for!(AprilPackage).to_value()
+}
+
+/// Select the APRIL package entry whose `compatible_versions` matches the given package version
+pub fn select_april_package<'a>(
+    packages: &'a [AprilPackage],
+    version: &str,
+    package_path: Option<&Path>,
+) -> Result<&'a AprilPackage> {
+    let mut matched = Vec::new();
+    for package in packages {
+        if check_version_compatibility(&package.compatible_versions, version, package_path)? {
+            matched.push(package);
+        }
+    }
+
+    match matched.as_slice() {
+        [] => Err(AprilError::NoCompatibleConfig(version.to_string())),
+        [package] => Ok(package),
+        _ => Err(AprilError::AmbiguousConfig {
+            version: version.to_string(),
+            candidates: matched
+                .iter()
+                .map(|p| format!("'{}'", p.compatible_versions))
+                .collect::<Vec<_>>()
+                .join(", "),
+        }),
+    }
+}
+
+/// Known dpkg architecture names (Debian and AOSC OS ports)
+const KNOWN_ARCHITECTURES: &[&str] = &[
+    "all",
+    "alpha",
+    "amd64",
+    "arm64",
+    "armel",
+    "armhf",
+    "hppa",
+    "hurd-amd64",
+    "hurd-i386",
+    "i386",
+    "ia64",
+    "loong64",
+    "loongarch64",
+    "loongson3",
+    "m68k",
+    "mips64el",
+    "mips64r6el",
+    "mipsel",
+    "powerpc",
+    "ppc64",
+    "ppc64el",
+    "riscv64",
+    "s390x",
+    "sh4",
+    "sparc64",
+    "x32",
+];
+
+/// All the problems found in an APRIL configuration
+#[derive(Debug)]
+pub struct ValidationReport(pub Vec<String>);
+
+impl Display for ValidationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let [error] = self.0.as_slice() {
+            return f.write_str(error);
+        }
+        write!(f, "{} problems found in APRIL configuration:", self.0.len())?;
+        for error in &self.0 {
+            write!(f, "\n  - {}", error)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationReport {}
+
+/// Validate the APRIL configuration, all the problems are collected into a `ValidationReport`
 pub fn validate_april_data(data: &AprilPackage) -> Result<()> {
+    check_april_data(data, true)
+}
+
+/// Validate the APRIL configuration, the placeholders are only checked if `placeholders` is true
+/// (they are already substituted otherwise, and `$${` may have left a literal `${`)
+fn check_april_data(data: &AprilPackage, placeholders: bool) -> Result<()> {
+    let mut errors = Vec::new();
+
     // validate schema
     if data.schema != "0" {
-        bail!("Invalid schema version, expected 0");
+        errors.push("Invalid schema version, expected 0".to_string());
+    }
+
+    if let Err(e) = validate_version_expr(&data.compatible_versions) {
+        errors.push(e.to_string());
     }
 
     // for total_conversion data, all mandatory fields should be present
@@ -148,11 +722,85 @@ pub fn validate_april_data(data: &AprilPackage) -> Result<()> {
             || data.overrides.description.is_none()
             || data.overrides.depends.is_none()
         {
-            bail!("Missing mandatory fields in total_conversion package");
+            errors.push("Missing mandatory fields in total_conversion package".to_string());
+        }
+    }
+
+    // placeholders are resolved at plan time, but unknown ones can be caught here
+    if placeholders {
+        data.clone().try_for_each_templated_value(|value| {
+            let known = |name: &str| {
+                (name == "VERSION" || name.strip_prefix("env:").is_some_and(|var| !var.is_empty()))
+                    .then(String::new)
+            };
+            if let Err(e) = expand_placeholders(value, known) {
+                errors.push(e.to_string());
+            }
+            Ok(())
+        })?;
+    }
+
+    // trigger names can not contain whitespaces, otherwise the rendered directive would be ambiguous
+    if let Some(AprilTriggersOverride::Directives(directives)) = data
+        .overrides
+        .scripts
+        .as_ref()
+        .and_then(|s| s.triggers.as_ref())
+    {
+        for directive in directives {
+            if directive.name.is_empty() || directive.name.contains(char::is_whitespace) {
+                errors.push(format!(
+                    "Invalid trigger name in {} directive: '{}'",
+                    directive.kind.as_str(),
+                    directive.name
+                ));
+            }
+        }
+    }
+
+    // relationship fields should be valid dpkg relationships (with optional +/- modifiers)
+    for (field, values) in [
+        ("depends", &data.overrides.depends),
+        ("recommends", &data.overrides.recommends),
+        ("suggests", &data.overrides.suggests),
+        ("enhances", &data.overrides.enhances),
+        ("pre_depends", &data.overrides.pre_depends),
+        ("breaks", &data.overrides.breaks),
+        ("conflicts", &data.overrides.conflicts),
+        ("replaces", &data.overrides.replaces),
+        ("provides", &data.overrides.provides),
+    ] {
+        for value in values
+            .iter()
+            .flatten()
+            .filter(|value| !value.is_empty() && !has_placeholders(value))
+        {
+            let relation = value.strip_prefix(['+', '-', '=']).unwrap_or(value);
+            if let Err(e) = parse_relationships(relation) {
+                errors.push(format!("Invalid {} override: {}", field, e));
+            }
+        }
+    }
+
+    if let Some(arch) = &data.overrides.arch
+        && !has_placeholders(arch)
+        && !KNOWN_ARCHITECTURES.contains(&arch.as_str())
+    {
+        errors.push(format!("Unknown architecture: {}", arch));
+    }
+
+    for conffile in data.overrides.conffiles.iter().flatten() {
+        if !conffile.starts_with('/') {
+            errors.push(format!(
+                "Configuration file path must be absolute: {}",
+                conffile
+            ));
         }
     }
 
-    // TODO: validate other fields as well
+    if !errors.is_empty() {
+        return Err(AprilError::InvalidConfig(ValidationReport(errors)));
+    }
 
     Ok(())
 }
@@ -161,41 +809,63 @@ fn add_fields_patch_action(
     actions: &mut Vec<AprilAction>,
     values: &Option<Vec<String>>,
     name: &'static str,
-) {
+    append: AprilFieldActionType,
+) -> Result<()> {
     if let Some(v) = values {
         if v.is_empty() {
             actions.push(AprilAction::PatchField {
                 field: Cow::Borrowed(name),
                 value: String::new(),
-                action: AprilActionType::Replace,
+                action: AprilFieldActionType::Replace,
+                package: None,
             });
         } else {
+            // `=` entries set the field to exactly these relations in order, the `+`/`-` entries apply after that
+            let exact = v
+                .iter()
+                .filter_map(|f| f.strip_prefix('='))
+                .map(canonicalize_relationships)
+                .collect::<Result<Vec<_>>>()?;
+            if !exact.is_empty() {
+                actions.push(AprilAction::PatchField {
+                    field: Cow::Borrowed(name),
+                    value: exact.join(", "),
+                    action: AprilFieldActionType::Replace,
+                    package: None,
+                });
+            }
             for f in v {
                 if f.is_empty() {
                     continue;
                 }
                 if let Some((modifier, value)) = f.split_at_checked(1) {
                     match modifier {
+                        "=" => (),
                         "+" => actions.push(AprilAction::PatchField {
                             field: Cow::Borrowed(name),
-                            value: value.to_string(),
-                            action: AprilActionType::Append,
+                            value: canonicalize_relationships(value)?,
+                            action: append,
+                            package: None,
                         }),
                         "-" => actions.push(AprilAction::PatchField {
                             field: Cow::Borrowed(name),
-                            value: value.to_string(),
-                            action: AprilActionType::Remove,
+                            value: canonicalize_relationships(value)?,
+                            action: AprilFieldActionType::Remove,
+                            package: None,
                         }),
                         _ => actions.push(AprilAction::PatchField {
                             field: Cow::Borrowed(name),
-                            value: f.to_string(),
-                            action: AprilActionType::Append,
+                            value: canonicalize_relationships(f)?,
+                            action: append,
+                            package: None,
                         }),
                     }
                 }
             }
         }
     }
+
+    Ok(())
 }
 
 fn add_field_patch_action(field: &Option<String>, name: &'static str) -> Option<AprilAction> {
@@ -204,13 +874,15 @@ fn add_field_patch_action(field: &Option<String>, name: &'static str) -> Option<
             Some(AprilAction::PatchField {
                 field: Cow::Borrowed(name),
                 value: String::new(),
-                action: AprilActionType::Replace,
+                action: AprilFieldActionType::Replace,
+                package: None,
             })
         } else {
             Some(AprilAction::PatchField {
                 field: Cow::Borrowed(name),
                 value: field.clone(),
-                action: AprilActionType::Replace,
+                action: AprilFieldActionType::Replace,
+                package: None,
             })
         }
     } else {
@@ -218,6 +890,51 @@ fn add_field_patch_action(field: &Option<String>, name: &'static str) -> Option<
     }
 }
 
+/// The actions replacing (or removing) the package script, and then prepending to it
+fn script_patch_actions(
+    scripts: &AprilPackageScriptOverrides,
+    file: &'static str,
+) -> Vec<AprilAction> {
+    let prepend = scripts.prepend.as_ref();
+    let (content, prepended) = match file {
+        "preinst" => (&scripts.preinst, prepend.and_then(|p| p.preinst.as_ref())),
+        "prerm" => (&scripts.prerm, prepend.and_then(|p| p.prerm.as_ref())),
+        "postinst" => (&scripts.postinst, prepend.and_then(|p| p.postinst.as_ref())),
+        "postrm" => (&scripts.postrm, prepend.and_then(|p| p.postrm.as_ref())),
+        _ => unreachable!(),
+    };
+    let mut actions = Vec::new();
+    if let Some(content) = content {
+        actions.push(script_patch_action(file, content));
+    }
+    if let Some(prepended) = prepended {
+        actions.push(AprilAction::PatchScript {
+            file,
+            content: Some(prepended.clone()),
+            action: AprilActionType::Prepend,
+        });
+    }
+
+    actions
+}
+
+/// Replace the package script with the content, or remove it if the content is empty
+fn script_patch_action(file: &'static str, content: &str) -> AprilAction {
+    if content.is_empty() {
+        AprilAction::PatchScript {
+            file,
+            content: None,
+            action: AprilActionType::Remove,
+        }
+    } else {
+        AprilAction::PatchScript {
+            file,
+            content: Some(content.to_string()),
+            action: AprilActionType::Replace,
+        }
+    }
+}
+
 pub fn plan_actions_from_april_data(data: &AprilPackage) -> Result<Vec<AprilAction>> {
     let mut actions = Vec::with_capacity(10);
 
@@ -227,58 +944,34 @@ pub fn plan_actions_from_april_data(data: &AprilPackage) -> Result<Vec<AprilActi
     }
 
     // First, collect all the pre-remove/pre-inst script patches, these need to be applied before any other actions
-    if let Some(scripts) = &data.overrides.scripts {
-        if let Some(preinst) = &scripts.preinst {
-            actions.push(if preinst.is_empty() {
-                AprilAction::PatchScript {
-                    file: "preinst",
-                    content: None,
-                    action: AprilActionType::Remove,
-                }
-            } else {
-                AprilAction::PatchScript {
-                    file: "preinst",
-                    content: Some(preinst.clone()),
-                    action: AprilActionType::Replace,
-                }
-            });
-        }
-        if let Some(prerm) = &scripts.prerm {
-            actions.push(if prerm.is_empty() {
-                AprilAction::PatchScript {
-                    file: "prerm",
-                    content: None,
-                    action: AprilActionType::Remove,
-                }
-            } else {
-                AprilAction::PatchScript {
-                    file: "prerm",
-                    content: Some(prerm.clone()),
-                    action: AprilActionType::Replace,
-                }
-            });
-        }
+    let scripts = data.overrides.scripts.as_ref();
+    let (early_scripts, late_scripts) = match scripts.map(|scripts| &scripts.phase) {
+        Some(AprilFileOperationPhase::Postinst) => (None, scripts),
+        _ => (scripts, None),
+    };
+    if let Some(scripts) = early_scripts {
+        actions.extend(script_patch_actions(scripts, "preinst"));
+        actions.extend(script_patch_actions(scripts, "prerm"));
 
         // triggers patching also needs to be applied before any other actions
         if let Some(triggers) = &scripts.triggers {
-            actions.push(if triggers.is_empty() {
-                AprilAction::PatchScript {
-                    file: "triggers",
-                    content: None,
-                    action: AprilActionType::Remove,
-                }
-            } else {
-                AprilAction::PatchScript {
-                    file: "triggers",
-                    content: Some(triggers.clone()),
-                    action: AprilActionType::Replace,
-                }
-            });
+            actions.push(script_patch_action("triggers", &triggers.render()));
         }
     }
 
+    let append = if data.overrides.merge_relations {
+        AprilFieldActionType::Merge
+    } else {
+        AprilFieldActionType::Append
+    };
+
     // Pre-Depends patching needs to be applied before pre-configure phase
-    add_fields_patch_action(&mut actions, &data.overrides.pre_depends, "Pre-Depends");
+    add_fields_patch_action(
+        &mut actions,
+        &data.overrides.pre_depends,
+        "Pre-Depends",
+        append,
+    )?;
     if let Some(action) = add_field_patch_action(&data.overrides.arch, "Architecture") {
         actions.push(action);
     }
@@ -295,18 +988,22 @@ pub fn plan_actions_from_april_data(data: &AprilPackage) -> Result<Vec<AprilActi
     // Then, we need to do a preconfigure on the package
     actions.push(AprilAction::PreconfigPackage);
 
-    // confflies patching needs to be applied before extraction phase
-    if let Some(confflies) = &data.overrides.conffiles {
-        let new_list = confflies.join("\n");
+    // conffiles patching needs to be applied before extraction phase
+    if let Some(conffiles) = &data.overrides.conffiles {
+        // dpkg-deb requires the final newline
+        let new_list = conffiles
+            .iter()
+            .map(|conffile| format!("{}\n", conffile))
+            .collect::<String>();
         if new_list.is_empty() {
             actions.push(AprilAction::PatchScript {
-                file: "confflies",
+                file: "conffiles",
                 content: None,
                 action: AprilActionType::Remove,
             });
         } else {
             actions.push(AprilAction::PatchScript {
-                file: "confflies",
+                file: "conffiles",
                 content: Some(new_list),
                 action: AprilActionType::Replace,
             });
@@ -316,13 +1013,39 @@ pub fn plan_actions_from_april_data(data: &AprilPackage) -> Result<Vec<AprilActi
     // After that, we extra the package to the root directory
     actions.push(AprilAction::ExtractPackage);
 
-    add_fields_patch_action(&mut actions, &data.overrides.depends, "Depends");
-    add_fields_patch_action(&mut actions, &data.overrides.recommends, "Recommends");
-    add_fields_patch_action(&mut actions, &data.overrides.conflicts, "Conflicts");
-    add_fields_patch_action(&mut actions, &data.overrides.suggests, "Suggests");
-    add_fields_patch_action(&mut actions, &data.overrides.breaks, "Breaks");
-    add_fields_patch_action(&mut actions, &data.overrides.replaces, "Replaces");
-    add_fields_patch_action(&mut actions, &data.overrides.provides, "Provides");
+    add_fields_patch_action(&mut actions, &data.overrides.depends, "Depends", append)?;
+    add_fields_patch_action(
+        &mut actions,
+        &data.overrides.recommends,
+        "Recommends",
+        append,
+    )?;
+    add_fields_patch_action(&mut actions, &data.overrides.suggests, "Suggests", append)?;
+    // a stricter constraint means something else for these fields, they are not merged
+    add_fields_patch_action(
+        &mut actions,
+        &data.overrides.conflicts,
+        "Conflicts",
+        AprilFieldActionType::Append,
+    )?;
+    add_fields_patch_action(
+        &mut actions,
+        &data.overrides.breaks,
+        "Breaks",
+        AprilFieldActionType::Append,
+    )?;
+    add_fields_patch_action(
+        &mut actions,
+        &data.overrides.replaces,
+        "Replaces",
+        AprilFieldActionType::Append,
+    )?;
+    add_fields_patch_action(
+        &mut actions,
+        &data.overrides.provides,
+        "Provides",
+        AprilFieldActionType::Append,
+    )?;
     if let Some(action) = add_field_patch_action(&data.overrides.version, "Version") {
         actions.push(action);
     }
@@ -332,6 +1055,18 @@ pub fn plan_actions_from_april_data(data: &AprilPackage) -> Result<Vec<AprilActi
     if let Some(action) = add_field_patch_action(&data.overrides.section, "Section") {
         actions.push(action);
     }
+    if let Some(action) = add_field_patch_action(&data.overrides.maintainer, "Maintainer") {
+        actions.push(action);
+    }
+    if let Some(action) = add_field_patch_action(&data.overrides.homepage, "Homepage") {
+        actions.push(action);
+    }
+    if let Some(action) = add_field_patch_action(&data.overrides.source, "Source") {
+        actions.push(action);
+    }
+    if let Some(action) = add_field_patch_action(&data.overrides.origin, "Origin") {
+        actions.push(action);
+    }
     if let Some(action) = add_field_patch_action(
         &data.overrides.essential.map(|v| {
             if v {
@@ -347,12 +1082,17 @@ pub fn plan_actions_from_april_data(data: &AprilPackage) -> Result<Vec<AprilActi
 
     // If there are files to be patched after the extraction phase (unpack phase), we need to patch them here
     if let Some(files) = &data.files {
-        for (path, operation) in files {
+        for (path, operation) in files
+            .iter()
+            .flat_map(|(path, operations)| operations.with_paths(path))
+        {
             match operation.phase {
                 AprilFileOperationPhase::Unpack => {
                     actions.push(AprilAction::PatchFile {
-                        path: path.clone(),
+                        path: path.to_string(),
                         action: operation.operation.clone(),
+                        allow_empty: operation.allow_empty,
+                        optional: operation.optional,
                     });
                 }
                 _ => {}
@@ -361,50 +1101,37 @@ pub fn plan_actions_from_april_data(data: &AprilPackage) -> Result<Vec<AprilActi
     }
 
     // Then we patch the post-installation/post-remove scripts
-    if let Some(scripts) = &data.overrides.scripts {
-        if let Some(postinst) = &scripts.postinst {
-            actions.push(if postinst.is_empty() {
-                AprilAction::PatchScript {
-                    file: "postinst",
-                    content: None,
-                    action: AprilActionType::Remove,
-                }
-            } else {
-                AprilAction::PatchScript {
-                    file: "postinst",
-                    content: Some(postinst.clone()),
-                    action: AprilActionType::Replace,
-                }
-            });
-        }
-        if let Some(postrm) = &scripts.postrm {
-            actions.push(if postrm.is_empty() {
-                AprilAction::PatchScript {
-                    file: "postrm",
-                    content: None,
-                    action: AprilActionType::Remove,
-                }
-            } else {
-                AprilAction::PatchScript {
-                    file: "postrm",
-                    content: Some(postrm.clone()),
-                    action: AprilActionType::Replace,
-                }
-            });
-        }
+    if let Some(scripts) = early_scripts {
+        actions.extend(script_patch_actions(scripts, "postinst"));
+        actions.extend(script_patch_actions(scripts, "postrm"));
     }
 
     // After that, we configure the package
     actions.push(AprilAction::ConfigurePackage);
 
+    // The scripts can also be patched after the configuration phase (postinst phase)
+    if let Some(scripts) = late_scripts {
+        for file in ["preinst", "prerm", "postinst", "postrm"] {
+            actions.extend(script_patch_actions(scripts, file));
+        }
+        if let Some(triggers) = &scripts.triggers {
+            actions.push(script_patch_action("triggers", &triggers.render()));
+        }
+    }
+
     // If there are files to be patched after the configuration phase (postinst phase), we need to patch them here
     if let Some(files) = &data.files {
-        for (path, operation) in files {
+        for (path, operation) in files
+            .iter()
+            .flat_map(|(path, operations)| operations.with_paths(path))
+        {
             match operation.phase {
                 AprilFileOperationPhase::Postinst => {
                     actions.push(AprilAction::PatchFile {
-                        path: path.clone(),
+                        path: path.to_string(),
                         action: operation.operation.clone(),
+                        allow_empty: operation.allow_empty,
+                        optional: operation.optional,
                     });
                 }
                 _ => {}
@@ -412,11 +1139,165 @@ pub fn plan_actions_from_april_data(data: &AprilPackage) -> Result<Vec<AprilActi
         }
     }
 
-    // Return the planned actions
+    log::info!("Planned {} actions for {}", actions.len(), data.name);
+    for action in &actions {
+        log::debug!("Planned action: {}", action);
+    }
 
     Ok(actions)
 }
 
+/// Combine the actions planned from several configurations applied to the same package in order
+/// (e.g. a base configuration and site overrides). The actions are kept in their phases, and in
+/// each phase the actions of a configuration come after those of the previous configurations,
+/// so that later configurations see the results of the earlier ones.
+pub fn layer_planned_actions(plans: Vec<Vec<AprilAction>>) -> Vec<AprilAction> {
+    let is_phase_marker = |action: &AprilAction| {
+        matches!(
+            action,
+            AprilAction::PreconfigPackage
+                | AprilAction::UnpackPackage
+                | AprilAction::ExtractPackage
+                | AprilAction::ConfigurePackage
+                | AprilAction::InstallPackage
+        )
+    };
+    let mut markers = Vec::new();
+    let mut phases: Vec<Vec<AprilAction>> = Vec::new();
+    for (layer, plan) in plans.into_iter().enumerate() {
+        let mut phase = 0;
+        for action in plan {
+            if is_phase_marker(&action) {
+                // all the plans go through the same phases
+                if layer == 0 {
+                    markers.push(action);
+                }
+                phase += 1;
+                continue;
+            }
+            if phases.len() <= phase {
+                phases.resize_with(phase + 1, Vec::new);
+            }
+            phases[phase].push(action);
+        }
+    }
+
+    let mut actions = Vec::new();
+    let mut markers = markers.into_iter();
+    for phase in phases {
+        actions.extend(phase);
+        actions.extend(markers.next());
+    }
+    actions.extend(markers);
+
+    actions
+}
+
+/// Map a dpkg architecture name to its multiarch tuple (see `dpkg-architecture -qDEB_HOST_MULTIARCH`)
+pub fn multiarch_tuple(arch: &str) -> Option<&'static str> {
+    Some(match arch {
+        "amd64" => "x86_64-linux-gnu",
+        "arm64" => "aarch64-linux-gnu",
+        "armel" => "arm-linux-gnueabi",
+        "armhf" => "arm-linux-gnueabihf",
+        "i386" => "i386-linux-gnu",
+        "loong64" | "loongarch64" => "loongarch64-linux-gnu",
+        "mips64el" => "mips64el-linux-gnuabi64",
+        "ppc64" => "powerpc64-linux-gnu",
+        "ppc64el" => "powerpc64le-linux-gnu",
+        "riscv64" => "riscv64-linux-gnu",
+        "s390x" => "s390x-linux-gnu",
+        _ => return None,
+    })
+}
+
+const KNOWN_MULTIARCH_TUPLES: &[&str] = &[
+    "x86_64-linux-gnu",
+    "aarch64-linux-gnu",
+    "arm-linux-gnueabi",
+    "arm-linux-gnueabihf",
+    "i386-linux-gnu",
+    "loongarch64-linux-gnu",
+    "mips64el-linux-gnuabi64",
+    "powerpc64-linux-gnu",
+    "powerpc64le-linux-gnu",
+    "riscv64-linux-gnu",
+    "s390x-linux-gnu",
+];
+
+fn rewrite_multiarch_path(path: &mut String, tuple: &str) {
+    let rewritten = path
+        .split('/')
+        .map(|component| {
+            if KNOWN_MULTIARCH_TUPLES.contains(&component) {
+                tuple
+            } else {
+                component
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+    *path = rewritten;
+}
+
+/// Adjust the planned actions for reconstructing the package for `target_arch`.
+/// This sets the `Architecture` field, and if `rewrite_multiarch` is true, also replaces
+/// multiarch path components (e.g. `/usr/lib/x86_64-linux-gnu`) in file operations with the ones for `target_arch`.
+pub fn retarget_actions_arch(
+    actions: &mut Vec<AprilAction>,
+    target_arch: &str,
+    rewrite_multiarch: bool,
+) -> Result<()> {
+    let mut arch_patched = false;
+    for action in actions.iter_mut() {
+        if let AprilAction::PatchField { field, value, .. } = action
+            && field == "Architecture"
+        {
+            *value = target_arch.to_string();
+            arch_patched = true;
+        }
+    }
+    if !arch_patched {
+        // Architecture patching needs to be applied before pre-configure phase
+        let position = actions
+            .iter()
+            .position(|action| matches!(action, AprilAction::PreconfigPackage))
+            .unwrap_or(actions.len());
+        actions.insert(
+            position,
+            AprilAction::PatchField {
+                field: Cow::Borrowed("Architecture"),
+                value: target_arch.to_string(),
+                action: AprilFieldActionType::Replace,
+                package: None,
+            },
+        );
+    }
+
+    if !rewrite_multiarch {
+        return Ok(());
+    }
+    let Some(tuple) = multiarch_tuple(target_arch) else {
+        return Err(AprilError::UnknownArchitecture(target_arch.to_string()));
+    };
+    for action in actions.iter_mut() {
+        if let AprilAction::PatchFile { path, action, .. } = action {
+            rewrite_multiarch_path(path, tuple);
+            match action {
+                AprilFileOperationType::Move(target)
+                | AprilFileOperationType::Copy(target)
+                | AprilFileOperationType::Link(target)
+                | AprilFileOperationType::Divert(target) => {
+                    rewrite_multiarch_path(target, tuple);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
 #[test]
 fn test_april_package_parsing_simple() {
     let input = r#"{
@@ -437,3 +1318,640 @@ fn test_april_package_parsing_example_1() {
     let plan = plan_actions_from_april_data(&data).unwrap();
     dbg!(plan);
 }
+
+#[test]
+fn test_render_trigger_directives() {
+    let input = r#"{
+        "schema": "0",
+        "name": "libfoo",
+        "compatible_versions": ">=1.0",
+        "overrides": {
+            "scripts": {
+                "triggers": [
+                    { "kind": "interest", "name": "/usr/share/icons/hicolor" },
+                    { "kind": "activate", "name": "ldconfig" },
+                    { "kind": "interest-noawait", "name": "libfoo-reload" }
+                ]
+            }
+        }
+}"#;
+    let data: AprilPackage = serde_json::from_str(input).unwrap();
+    validate_april_data(&data).unwrap();
+    let triggers = data.overrides.scripts.unwrap().triggers.unwrap();
+    assert_eq!(
+        triggers.render(),
+        "interest /usr/share/icons/hicolor\nactivate ldconfig\ninterest-noawait libfoo-reload\n"
+    );
+
+    let input = r#"{ "triggers": [{ "kind": "interested", "name": "foo" }] }"#;
+    assert!(serde_json::from_str::<AprilPackageScriptOverrides>(input).is_err());
+}
+
+#[test]
+fn test_april_json_schema() {
+    let schema = april_json_schema();
+    let validator = jsonschema::validator_for(&schema).unwrap();
+
+    let example: serde_json::Value =
+        toml::from_str(include_str!("../examples/sunloginclient.toml")).unwrap();
+    assert!(validator.is_valid(&example));
+
+    let mut unknown_field = example.clone();
+    unknown_field["overrides"]["dependes"] = serde_json::json!(["foo"]);
+    assert!(!validator.is_valid(&unknown_field));
+}
+
+#[test]
+fn test_select_april_package() {
+    let input = r#"[
+        { "schema": "0", "name": "libfoo", "compatible_versions": "< 1.0", "overrides": {} },
+        { "schema": "0", "name": "libfoo", "compatible_versions": ">= 1.0 && < 2.0", "overrides": {} },
+        { "schema": "0", "name": "libfoo", "compatible_versions": ">= 1.5", "overrides": {} }
+]"#;
+    let data: Vec<AprilPackage> = serde_json::from_str(input).unwrap();
+    let selected = select_april_package(&data, "0.9", None).unwrap();
+    assert_eq!(selected.compatible_versions, "< 1.0");
+    let selected = select_april_package(&data, "1.2", None).unwrap();
+    assert_eq!(selected.compatible_versions, ">= 1.0 && < 2.0");
+    let err = select_april_package(&data, "1.5", None).unwrap_err();
+    assert!(err.to_string().starts_with("Multiple APRIL configurations"));
+    assert!(select_april_package(&data[..2], "2.0", None).is_err());
+}
+
+#[test]
+fn test_validate_compatible_versions_hash() {
+    let input = r#"{
+        "schema": "0",
+        "name": "libfoo",
+        "compatible_versions": ">=1.0 && sha256sum(0123abcd)",
+        "overrides": {}
+}"#;
+    let data: AprilPackage = serde_json::from_str(input).unwrap();
+    let err = validate_april_data(&data).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Invalid SHA256 sum in version expression: 0123abcd"
+    );
+}
+
+#[test]
+fn test_retarget_actions_arch() {
+    let input = r#"{
+        "schema": "0",
+        "name": "libfoo",
+        "compatible_versions": "*",
+        "overrides": {},
+        "files": {
+            "/usr/lib/x86_64-linux-gnu/libfoo.so.1": { "action": "link", "arg": "/usr/lib/x86_64-linux-gnu/libfoo.so" }
+        }
+}"#;
+    let data: AprilPackage = serde_json::from_str(input).unwrap();
+    let mut actions = plan_actions_from_april_data(&data).unwrap();
+    retarget_actions_arch(&mut actions, "arm64", true).unwrap();
+    assert!(actions.iter().any(|action| matches!(
+        action,
+        AprilAction::PatchField { field, value, .. } if field == "Architecture" && value == "arm64"
+    )));
+    assert!(actions.iter().any(|action| matches!(
+        action,
+        AprilAction::PatchFile { path, action: AprilFileOperationType::Link(target), .. }
+            if path == "/usr/lib/aarch64-linux-gnu/libfoo.so.1" && target == "/usr/lib/aarch64-linux-gnu/libfoo.so"
+    )));
+    assert!(retarget_actions_arch(&mut actions, "pdp11", true).is_err());
+}
+
+#[test]
+fn test_relationship_overrides() {
+    let input = r#"{
+        "schema": "0",
+        "name": "libfoo",
+        "compatible_versions": "*",
+        "overrides": {
+            "depends": ["+bar(>=1.0)", "-baz"],
+            "breaks": ["+bar (<< 1.0)"],
+            "merge_relations": true
+        }
+}"#;
+    let data: AprilPackage = serde_json::from_str(input).unwrap();
+    validate_april_data(&data).unwrap();
+    let actions = plan_actions_from_april_data(&data).unwrap();
+    assert!(actions.iter().any(|action| matches!(
+        action,
+        AprilAction::PatchField { field, value, action: AprilFieldActionType::Merge, .. }
+            if field == "Depends" && value == "bar (>= 1.0)"
+    )));
+    assert!(actions.iter().any(|action| matches!(
+        action,
+        AprilAction::PatchField { field, action: AprilFieldActionType::Append, .. }
+            if field == "Breaks"
+    )));
+
+    let input = r#"{
+        "schema": "0",
+        "name": "libfoo",
+        "compatible_versions": "*",
+        "overrides": { "depends": ["+bar(>=1.0)"] }
+}"#;
+    let data: AprilPackage = serde_json::from_str(input).unwrap();
+    let actions = plan_actions_from_april_data(&data).unwrap();
+    assert!(actions.iter().any(|action| matches!(
+        action,
+        AprilAction::PatchField { field, value, action: AprilFieldActionType::Append, .. }
+            if field == "Depends" && value == "bar (>= 1.0)"
+    )));
+
+    let input = r#"{
+        "schema": "0",
+        "name": "libfoo",
+        "compatible_versions": "*",
+        "overrides": { "depends": ["+bar (>= )"] }
+}"#;
+    let data: AprilPackage = serde_json::from_str(input).unwrap();
+    assert!(validate_april_data(&data).is_err());
+}
+
+#[test]
+fn test_plan_maintainer_homepage_overrides() {
+    let input = r#"{
+        "schema": "0",
+        "name": "libfoo",
+        "compatible_versions": "*",
+        "overrides": {
+            "maintainer": "AOSC OS Maintainers <maintainers@aosc.io>",
+            "homepage": "https://example.com/",
+            "source": "foo",
+            "origin": ""
+        }
+}"#;
+    let data: AprilPackage = serde_json::from_str(input).unwrap();
+    let actions = plan_actions_from_april_data(&data).unwrap();
+    let patched = actions
+        .iter()
+        .filter_map(|action| match action {
+            AprilAction::PatchField {
+                field,
+                value,
+                action: AprilFieldActionType::Replace,
+                ..
+            } => Some((field.as_ref(), value.as_str())),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        patched,
+        [
+            ("Maintainer", "AOSC OS Maintainers <maintainers@aosc.io>"),
+            ("Homepage", "https://example.com/"),
+            ("Source", "foo"),
+            ("Origin", ""),
+        ]
+    );
+}
+
+#[test]
+fn test_plan_conffiles_override() {
+    let input = r#"{
+        "schema": "0",
+        "name": "libfoo",
+        "compatible_versions": "*",
+        "overrides": { "conffiles": ["/etc/foo.conf", "/etc/bar.conf"] }
+}"#;
+    let data: AprilPackage = serde_json::from_str(input).unwrap();
+    let actions = plan_actions_from_april_data(&data).unwrap();
+    assert!(actions.iter().any(|action| matches!(
+        action,
+        AprilAction::PatchScript { file: "conffiles", content: Some(content), action: AprilActionType::Replace }
+            if content == "/etc/foo.conf\n/etc/bar.conf\n"
+    )));
+
+    let input = r#"{
+        "schema": "0",
+        "name": "libfoo",
+        "compatible_versions": "*",
+        "overrides": { "conffiles": [] }
+}"#;
+    let data: AprilPackage = serde_json::from_str(input).unwrap();
+    let actions = plan_actions_from_april_data(&data).unwrap();
+    assert!(actions.iter().any(|action| matches!(
+        action,
+        AprilAction::PatchScript {
+            file: "conffiles",
+            content: None,
+            action: AprilActionType::Remove
+        }
+    )));
+}
+
+#[test]
+fn test_plan_script_phase() {
+    let input = r#"{
+        "schema": "0",
+        "name": "foo",
+        "compatible_versions": "*",
+        "overrides": { "scripts": { "postinst": "exit 0", "postrm": "" } },
+        "files": { "/etc/foo.conf": { "action": "remove", "phase": "postinst" } }
+    }"#;
+    let data: AprilPackage = serde_json::from_str(input).unwrap();
+    let planned = |data: &AprilPackage| {
+        plan_actions_from_april_data(data)
+            .unwrap()
+            .iter()
+            .filter(|action| {
+                matches!(
+                    action,
+                    AprilAction::PatchScript { .. }
+                        | AprilAction::PatchFile { .. }
+                        | AprilAction::ConfigurePackage
+                )
+            })
+            .map(|action| action.to_string())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        planned(&data),
+        [
+            "replace DEBIAN/postinst",
+            "remove DEBIAN/postrm",
+            "configure the package",
+            "remove /etc/foo.conf",
+        ]
+    );
+
+    let input = input.replace(r#""postrm": """#, r#""postrm": "", "phase": "postinst""#);
+    let data: AprilPackage = serde_json::from_str(&input).unwrap();
+    assert_eq!(
+        planned(&data),
+        [
+            "configure the package",
+            "replace DEBIAN/postinst",
+            "remove DEBIAN/postrm",
+            "remove /etc/foo.conf",
+        ]
+    );
+}
+
+#[test]
+fn test_plan_files_in_declaration_order() {
+    let expected = [
+        "create directory /opt/foo",
+        "add /opt/foo/foo.conf from file::data:,foo",
+        "move /usr/share/foo/old.conf to /opt/foo/old.conf",
+        "chmod 640 /opt/foo/old.conf",
+        "remove /opt/bar",
+    ];
+    let input = r#"
+        schema = "0"
+        name = "foo"
+        compatible_versions = "*"
+        [overrides]
+        [files]
+        "/opt/foo" = { action = "mkdir" }
+        "/opt/bar" = { action = "remove", phase = "postinst" }
+        "/opt/foo/foo.conf" = { action = "add", arg = "file::data:,foo" }
+        "/usr/share/foo/old.conf" = { action = "move", arg = "/opt/foo/old.conf" }
+        "/opt/foo/old.conf" = { action = "chmod", arg = 416 }
+    "#;
+    let planned = |data: &AprilPackage| {
+        plan_actions_from_april_data(data)
+            .unwrap()
+            .iter()
+            .filter(|action| matches!(action, AprilAction::PatchFile { .. }))
+            .map(|action| action.to_string())
+            .collect::<Vec<_>>()
+    };
+    let data: AprilPackage = toml::from_str(input).unwrap();
+    assert_eq!(planned(&data), expected);
+    // the order is also kept in JSON
+    let data: AprilPackage = serde_json::from_str(&serde_json::to_string(&data).unwrap()).unwrap();
+    assert_eq!(planned(&data), expected);
+}
+
+#[test]
+fn test_plan_multiple_file_operations() {
+    let input = r#"{
+        "schema": "0",
+        "name": "foo",
+        "compatible_versions": "*",
+        "overrides": {},
+        "files": {
+            "/usr/share/foo/foo": [
+                { "action": "move", "arg": "/usr/bin/foo" },
+                { "action": "chmod", "arg": 493 },
+                { "action": "remove", "phase": "postinst" }
+            ],
+            "/usr/bin/bar": { "action": "link", "arg": "/usr/bin/baz" }
+        }
+    }"#;
+    let data: AprilPackage = serde_json::from_str(input).unwrap();
+    let planned = plan_actions_from_april_data(&data)
+        .unwrap()
+        .iter()
+        .filter(|action| matches!(action, AprilAction::PatchFile { .. }))
+        .map(|action| action.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        planned,
+        [
+            "move /usr/share/foo/foo to /usr/bin/foo",
+            "chmod 755 /usr/bin/foo",
+            "link /usr/bin/baz to /usr/bin/bar",
+            "remove /usr/bin/foo",
+        ]
+    );
+}
+
+#[test]
+fn test_substitute_placeholders() {
+    let input = r#"{
+        "schema": "0",
+        "name": "foo",
+        "compatible_versions": "*",
+        "overrides": {
+            "depends": ["+foo-data (= ${VERSION})"],
+            "scripts": { "postinst": "echo ${HOME}" }
+        },
+        "files": {
+            "/usr/bin/foo": { "action": "patch", "arg": "${env:APRIL_TEST_MIRROR}/foo-${VERSION}.patch" },
+            "/usr/bin/bar": { "action": "move", "arg": "/usr/bin/$${bar}" }
+        }
+    }"#;
+    let mut data: AprilPackage = serde_json::from_str(input).unwrap();
+    validate_april_data(&data).unwrap();
+    let env = |name: &str| {
+        (name == "APRIL_TEST_MIRROR").then(|| "https://mirror.example.com".to_string())
+    };
+    substitute_placeholders(&mut data, "1.2.3-1", env).unwrap();
+    let planned = plan_actions_from_april_data(&data)
+        .unwrap()
+        .iter()
+        .map(|action| action.to_string())
+        .collect::<Vec<_>>();
+    assert!(planned.contains(&"append 'foo-data (= 1.2.3-1)' to field Depends".to_string()));
+    assert!(planned.contains(
+        &"patch /usr/bin/foo with https://mirror.example.com/foo-1.2.3-1.patch".to_string()
+    ));
+    assert!(planned.contains(&"move /usr/bin/bar to /usr/bin/${bar}".to_string()));
+    // scripts are left alone
+    assert_eq!(
+        data.overrides.scripts.unwrap().postinst.unwrap(),
+        "echo ${HOME}"
+    );
+
+    let input = r#"{
+        "schema": "0",
+        "name": "foo",
+        "compatible_versions": "*",
+        "overrides": { "homepage": "https://example.com/${env:APRIL_TEST_UNSET}" },
+        "files": { "/usr/bin/foo": { "action": "copy", "arg": "/usr/bin/${VERSON}" } }
+    }"#;
+    let mut data: AprilPackage = serde_json::from_str(input).unwrap();
+    let Err(AprilError::InvalidConfig(report)) = validate_april_data(&data) else {
+        panic!("unknown placeholder is not reported");
+    };
+    assert_eq!(
+        report.0,
+        ["Unresolved placeholder ${VERSON} in '/usr/bin/${VERSON}'"]
+    );
+    assert!(matches!(
+        substitute_placeholders(&mut data, "1.0", env),
+        Err(AprilError::UnresolvedPlaceholder { placeholder, .. }) if placeholder == "${env:APRIL_TEST_UNSET}"
+    ));
+
+    // the substituted values are checked like the literal ones
+    let input = r#"{
+        "schema": "0",
+        "name": "foo",
+        "compatible_versions": "*",
+        "overrides": { "depends": ["+foo-data (= ${VERSION})"] }
+    }"#;
+    let mut data: AprilPackage = serde_json::from_str(input).unwrap();
+    let Err(AprilError::InvalidConfig(report)) =
+        substitute_placeholders(&mut data, "1.0) | (bar", env)
+    else {
+        panic!("invalid substituted relation is not reported");
+    };
+    assert!(
+        report.0[0].starts_with("Invalid depends override"),
+        "{:?}",
+        report.0
+    );
+    assert!(expand_placeholders("${VERSION", |_| Some(String::new())).is_err());
+}
+
+#[test]
+fn test_display_actions() {
+    let action = AprilAction::PatchField {
+        field: Cow::Borrowed("Depends"),
+        value: "foo (>= 1.0)".to_string(),
+        action: AprilFieldActionType::Append,
+        package: None,
+    };
+    assert_eq!(action.to_string(), "append 'foo (>= 1.0)' to field Depends");
+    let action =
+        AprilAction::patch_file("/usr/lib/libfoo.so.1", AprilFileOperationType::Chmod(0o755));
+    assert_eq!(action.to_string(), "chmod 755 /usr/lib/libfoo.so.1");
+}
+
+#[test]
+fn test_serialize_actions() {
+    let actions = [
+        AprilAction::DropControlData,
+        AprilAction::PatchField {
+            field: Cow::Borrowed("Depends"),
+            value: "foo (>= 1.0)".to_string(),
+            action: AprilFieldActionType::Append,
+            package: None,
+        },
+        AprilAction::patch_file(
+            "/usr/lib/libfoo.so.1",
+            AprilFileOperationType::Move("/usr/lib/libfoo.so".to_string()),
+        ),
+    ];
+    assert_eq!(
+        serde_json::to_value(actions).unwrap(),
+        serde_json::json!([
+            { "type": "drop_control_data" },
+            {
+                "type": "patch_field",
+                "field": "Depends",
+                "value": "foo (>= 1.0)",
+                "action": "append",
+                "package": null
+            },
+            {
+                "type": "patch_file",
+                "path": "/usr/lib/libfoo.so.1",
+                "action": { "action": "move", "arg": "/usr/lib/libfoo.so" },
+                "allow_empty": false,
+                "optional": false
+            }
+        ])
+    );
+}
+
+#[test]
+fn test_truncate_file_operation_serde() {
+    let input = r#"{"action": "truncate", "phase": "postinst"}"#;
+    let operation: AprilFileOperation = serde_json::from_str(input).unwrap();
+    assert!(matches!(
+        operation.operation,
+        AprilFileOperationType::Truncate
+    ));
+    assert!(matches!(operation.phase, AprilFileOperationPhase::Postinst));
+    let serialized = serde_json::to_value(&operation).unwrap();
+    assert_eq!(serialized["action"], "truncate");
+    assert!(serialized.get("arg").is_none());
+    let operation: AprilFileOperation = serde_json::from_value(serialized).unwrap();
+    assert!(matches!(
+        operation.operation,
+        AprilFileOperationType::Truncate
+    ));
+}
+
+#[test]
+fn test_plan_append_content() {
+    let input = r#"
+        schema = "0"
+        name = "foo"
+        compatible_versions = "*"
+        [overrides]
+        [files]
+        "/etc/foo.conf" = { action = "append-content", arg = "file::data:,bar=1%0A" }
+        "/etc/foo.d/bar.conf" = { action = "append-content", arg = "file::data:,baz=1%0A", phase = "postinst" }
+    "#;
+    let data: AprilPackage = toml::from_str(input).unwrap();
+    let actions = plan_actions_from_april_data(&data).unwrap();
+    let position = |description: &str| {
+        actions
+            .iter()
+            .position(|action| action.to_string() == description)
+            .unwrap()
+    };
+    let configure = actions
+        .iter()
+        .position(|action| matches!(action, AprilAction::ConfigurePackage))
+        .unwrap();
+    assert!(position("append file::data:,bar=1%0A to /etc/foo.conf") < configure);
+    assert!(position("append file::data:,baz=1%0A to /etc/foo.d/bar.conf") > configure);
+}
+
+#[test]
+fn test_chown_file_operation_serde() {
+    let input = r#"{"action": "chown", "arg": {"uid": 0, "gid": 42}}"#;
+    let operation: AprilFileOperation = serde_json::from_str(input).unwrap();
+    assert!(matches!(
+        operation.operation,
+        AprilFileOperationType::Chown { uid: 0, gid: 42 }
+    ));
+    let serialized = serde_json::to_value(&operation).unwrap();
+    assert_eq!(serialized["action"], "chown");
+    assert_eq!(serialized["arg"], serde_json::json!({"uid": 0, "gid": 42}));
+    let operation: AprilFileOperation = serde_json::from_value(serialized).unwrap();
+    assert!(matches!(
+        operation.operation,
+        AprilFileOperationType::Chown { uid: 0, gid: 42 }
+    ));
+    assert!(
+        serde_json::from_str::<AprilFileOperation>(r#"{"action": "chown", "arg": 0}"#).is_err()
+    );
+    assert!(!operation.allow_empty);
+
+    let input = r#"{"action": "remove", "allow_empty": true}"#;
+    let operation: AprilFileOperation = serde_json::from_str(input).unwrap();
+    assert!(operation.allow_empty);
+    assert!(!operation.optional);
+    let input = r#"{"action": "remove", "allow-empty": true}"#;
+    assert!(serde_json::from_str::<AprilFileOperation>(input).is_err());
+    let input = r#"{"action": "remove", "optional": true}"#;
+    let operation: AprilFileOperation = serde_json::from_str(input).unwrap();
+    assert!(operation.optional);
+}
+
+#[test]
+fn test_reject_unknown_fields() {
+    let input = r#"
+        schema = "0"
+        name = "foo"
+        compatible_versions = "*"
+        [overrides]
+        dependes = ["+bar"]
+    "#;
+    let err = toml::from_str::<AprilPackage>(input).unwrap_err();
+    assert!(err.to_string().contains("unknown field `dependes`"));
+
+    let input = r#"{"schema": "0", "name": "foo", "compatible_version": "*", "overrides": {}}"#;
+    assert!(serde_json::from_str::<AprilPackage>(input).is_err());
+    let input = r#"{"postinstall": "exit 0"}"#;
+    assert!(serde_json::from_str::<AprilPackageScriptOverrides>(input).is_err());
+    let input = r#"{"action": "chmod", "arg": 493, "phase": "unpack", "allow_emtpy": true}"#;
+    assert!(serde_json::from_str::<AprilFileOperation>(input).is_err());
+    let input = r#"{"action": "chmod", "arg": 493, "phase": "unpack", "allow_empty": true}"#;
+    assert!(serde_json::from_str::<AprilFileOperation>(input).is_ok());
+    let input = r#"{"action": "remove", "optinal": true}"#;
+    let err = serde_json::from_str::<AprilFileOperation>(input).unwrap_err();
+    assert!(err.to_string().contains("optinal"), "{}", err);
+    // the error of the operation is reported, in both forms of the file operations
+    for files in [
+        r#"files."/usr/bin/foo" = { action = "chmod", arg = 493, allow_emtpy = true }"#,
+        r#"files."/usr/bin/foo" = [{ action = "chmod", arg = 493, allow_emtpy = true }]"#,
+    ] {
+        let input = format!(
+            "schema = \"0\"\nname = \"foo\"\ncompatible_versions = \"*\"\n{}\n",
+            files
+        );
+        let err = toml::from_str::<AprilPackage>(&input).unwrap_err();
+        assert!(err.to_string().contains("allow_emtpy"), "{}", err);
+    }
+
+    let input = r#"{"kind": "interest", "name": "/usr/lib/foo"}"#;
+    assert!(serde_json::from_str::<AprilTriggerDirective>(input).is_ok());
+    let input = r#"{"kind": "interest", "name": "/usr/lib/foo", "await": false}"#;
+    let err = serde_json::from_str::<AprilTriggerDirective>(input).unwrap_err();
+    assert!(err.to_string().contains("unknown field `await`"), "{}", err);
+    let input = r#"
+        schema = "0"
+        name = "foo"
+        compatible_versions = "*"
+        [overrides.scripts]
+        triggers = [{ kind = "interest", nmae = "/usr/lib/foo" }]
+    "#;
+    assert!(toml::from_str::<AprilPackage>(input).is_err());
+}
+
+#[test]
+fn test_validate_compatible_versions() {
+    let input = r#"{
+        "schema": "0",
+        "name": "foo",
+        "compatible_versions": ">=1.0 &&",
+        "overrides": {}
+    }"#;
+    let data: AprilPackage = serde_json::from_str(input).unwrap();
+    let Err(AprilError::InvalidConfig(report)) = validate_april_data(&data) else {
+        panic!("malformed compatible_versions is not reported");
+    };
+    assert_eq!(report.0, ["Invalid operands for '&&' at position 6..8"]);
+}
+
+#[test]
+fn test_validation_report() {
+    let input = r#"{
+        "schema": "0",
+        "name": "libfoo",
+        "compatible_versions": "*",
+        "overrides": {
+            "arch": "x86-64",
+            "depends": ["foo (>= 1.0", "+bar"],
+            "conffiles": ["etc/foo.conf"]
+        }
+}"#;
+    let data: AprilPackage = serde_json::from_str(input).unwrap();
+    let err = validate_april_data(&data).unwrap_err();
+    let AprilError::InvalidConfig(report) = &err else {
+        panic!("unexpected error: {}", err);
+    };
+    assert_eq!(report.0.len(), 3);
+    assert!(report.0[0].starts_with("Invalid depends override"));
+    assert_eq!(report.0[1], "Unknown architecture: x86-64");
+    assert!(err.to_string().starts_with("3 problems found"));
+}
@@ -1,7 +1,9 @@
-use std::fmt::Display;
+use std::{fmt::Display, fs::File, ops::Range, path::Path};
 
-use anyhow::{Result, anyhow};
 use logos::{Lexer, Logos};
+use sha2::Digest;
+
+use crate::error::{AprilError, Result};
 
 fn parse_function_call<'a>(lex: &mut Lexer<'a, VersionToken<'a>>) -> Option<&'a str> {
     if !lex
@@ -43,6 +45,12 @@ enum VersionToken<'source> {
     Gt,
     #[token("<")]
     Lt,
+    /// strictly greater, as in Debian relationship fields
+    #[token(">>")]
+    GtGt,
+    /// strictly less, as in Debian relationship fields
+    #[token("<<")]
+    LtLt,
     #[token("||")]
     Or,
     #[token("&&")]
@@ -51,6 +59,8 @@ enum VersionToken<'source> {
     LParen,
     #[token(")")]
     RParen,
+    #[token("*")]
+    Any,
     #[regex(r"sha256sum", parse_function_call)]
     Sha256Sum(&'source str),
     #[regex(r"[a-fA-F0-9]+", priority = 3)]
@@ -69,10 +79,13 @@ impl<'source> Display for VersionToken<'source> {
             VersionToken::LtEq => write!(f, "<="),
             VersionToken::Gt => write!(f, ">"),
             VersionToken::Lt => write!(f, "<"),
+            VersionToken::GtGt => write!(f, ">>"),
+            VersionToken::LtLt => write!(f, "<<"),
             VersionToken::Or => write!(f, "||"),
             VersionToken::And => write!(f, "&&"),
             VersionToken::LParen => write!(f, "("),
             VersionToken::RParen => write!(f, ")"),
+            VersionToken::Any => write!(f, "*"),
             VersionToken::Sha256Sum(hex) => write!(f, "sha256sum({})", hex),
             VersionToken::Hexadecimal(hex) => write!(f, "{}", hex),
             VersionToken::VersionNumber(version) => write!(f, "{}", version),
@@ -90,6 +103,8 @@ impl<'source> VersionToken<'source> {
             | VersionToken::LtEq
             | VersionToken::Gt
             | VersionToken::Lt
+            | VersionToken::GtGt
+            | VersionToken::LtLt
             | VersionToken::Or
             | VersionToken::And => true,
             _ => false,
@@ -104,7 +119,9 @@ impl<'source> VersionToken<'source> {
             | VersionToken::GtEq
             | VersionToken::LtEq
             | VersionToken::Gt
-            | VersionToken::Lt => true,
+            | VersionToken::Lt
+            | VersionToken::GtGt
+            | VersionToken::LtLt => true,
             _ => false,
         }
     }
@@ -112,11 +129,15 @@ impl<'source> VersionToken<'source> {
     pub fn precedence(&self) -> u8 {
         match self {
             VersionToken::Eq
+            | VersionToken::EqEq
             | VersionToken::GtEq
             | VersionToken::LtEq
             | VersionToken::Gt
             | VersionToken::Lt
+            | VersionToken::GtGt
+            | VersionToken::LtLt
             | VersionToken::Sha256Sum(_)
+            | VersionToken::Any
             | VersionToken::NotEq => 10,
             VersionToken::Or | VersionToken::And => 1,
             _ => 0, // invalid operator
@@ -128,7 +149,6 @@ const ZERO_STRING: &'static str = "0";
 const VERSION_PLACEHOLDER: &'static str = "$VER";
 const VERSION_PLACEHOLDER_TOKEN: VersionToken = VersionToken::VersionNumber(VERSION_PLACEHOLDER);
 
-#[derive(PartialEq)]
 struct DebVersion<'a> {
     epoch: u32,
     version: &'a [u8],
@@ -136,7 +156,7 @@ struct DebVersion<'a> {
 }
 
 impl<'a> DebVersion<'a> {
-    fn parse(input: &str) -> Option<DebVersion> {
+    fn parse(input: &str) -> Result<DebVersion> {
         let input_bytes = input.as_bytes();
         let mut first_colon = 0usize;
         let mut last_dash = input_bytes.len();
@@ -156,11 +176,18 @@ impl<'a> DebVersion<'a> {
         }
 
         let epoch = if first_colon > 0 {
-            u32::from_str_radix(
-                unsafe { str::from_utf8_unchecked(&input_bytes[0..first_colon - 1]) },
-                10,
-            )
-            .ok()?
+            let invalid_epoch = |reason: &str| AprilError::InvalidVersion {
+                version: input.to_string(),
+                reason: reason.to_string(),
+            };
+            // the colon is ASCII, so the slice is always on a character boundary
+            let epoch = &input[0..first_colon - 1];
+            if epoch.is_empty() || !epoch.bytes().all(|c| c.is_ascii_digit()) {
+                return Err(invalid_epoch("epoch must be an unsigned integer"));
+            }
+            epoch
+                .parse()
+                .map_err(|_| invalid_epoch("epoch is out of range"))?
         } else {
             0
         };
@@ -172,7 +199,7 @@ impl<'a> DebVersion<'a> {
         };
         let release = &input_bytes[release_idx..];
 
-        Some(DebVersion {
+        Ok(DebVersion {
             epoch,
             version,
             release,
@@ -180,71 +207,93 @@ impl<'a> DebVersion<'a> {
     }
 }
 
-fn get_version_sort_priority(c: u8) -> i16 {
-    if c.is_ascii_digit() {
-        return 0;
-    }
-    if c.is_ascii_alphabetic() {
-        return c.into();
-    }
-    if c == b'~' {
-        return -1;
+impl Display for DebVersion<'_> {
+    /// Render the version in the canonical form, the epoch is omitted if it is 0
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.epoch != 0 {
+            write!(f, "{}:", self.epoch)?;
+        }
+        f.write_str(&String::from_utf8_lossy(self.version))?;
+        if !self.release.is_empty() {
+            write!(f, "-{}", String::from_utf8_lossy(self.release))?;
+        }
+
+        Ok(())
     }
+}
 
-    (c as i16) + 0x100
+/// Sort priority of a character in the non-digit part of a version, `None` is the end of the string.
+/// The end of the string sorts like a digit: before everything but `~`.
+fn get_version_sort_priority(c: Option<u8>) -> i16 {
+    match c {
+        None => 0,
+        Some(c) if c.is_ascii_digit() => 0,
+        Some(c) if c.is_ascii_alphabetic() => c.into(),
+        Some(b'~') => -1,
+        Some(c) => (c as i16) + 0x100,
+    }
 }
 
+/// Compare the upstream versions (or the revisions) like dpkg: alternating non-digit parts
+/// compared by `get_version_sort_priority` and digit parts compared numerically
 fn version_string_cmp(a: &[u8], b: &[u8]) -> std::cmp::Ordering {
-    let mut a_cursor = 0usize;
-    let mut b_cursor = 0usize;
-    let a_len = a.len();
-    let b_len = b.len();
-
-    while a_cursor <= a_len || b_cursor <= b_len {
-        let mut first_diff = std::cmp::Ordering::Equal;
-        while (a_cursor < a_len && !a[a_cursor].is_ascii_digit())
-            || (b_cursor < b_len && !b[b_cursor].is_ascii_digit())
-        {
-            let ac = get_version_sort_priority(a[a_cursor]);
-            let bc = get_version_sort_priority(b[b_cursor]);
+    use std::cmp::Ordering;
+
+    let is_digit = |c: Option<&u8>| c.is_some_and(u8::is_ascii_digit);
+    let is_non_digit = |c: Option<&u8>| c.is_some_and(|c| !c.is_ascii_digit());
+    let (mut i, mut j) = (0usize, 0usize);
 
+    while i < a.len() || j < b.len() {
+        // the non-digit parts, until both sides reach a digit (or the end)
+        while is_non_digit(a.get(i)) || is_non_digit(b.get(j)) {
+            let ac = get_version_sort_priority(a.get(i).copied());
+            let bc = get_version_sort_priority(b.get(j).copied());
             if ac != bc {
                 return ac.cmp(&bc);
             }
-
-            a_cursor += 1;
-            b_cursor += 1;
+            i += 1;
+            j += 1;
         }
 
-        while a[a_cursor] == b'0' {
-            a_cursor += 1;
+        // the digit parts: leading zeros are insignificant, then the longer number is greater,
+        // and numbers of the same length are decided by the first differing digit
+        while a.get(i) == Some(&b'0') {
+            i += 1;
         }
-
-        while b[b_cursor] == b'0' {
-            b_cursor += 1;
+        while b.get(j) == Some(&b'0') {
+            j += 1;
         }
-
-        while a[a_cursor].is_ascii_digit() && b[b_cursor].is_ascii_digit() {
-            if first_diff == std::cmp::Ordering::Equal {
-                first_diff = a[a_cursor].cmp(&b[b_cursor]);
+        let mut first_diff = Ordering::Equal;
+        while let (Some(ac), Some(bc)) = (
+            a.get(i).filter(|c| c.is_ascii_digit()),
+            b.get(j).filter(|c| c.is_ascii_digit()),
+        ) {
+            if first_diff == Ordering::Equal {
+                first_diff = ac.cmp(bc);
             }
-
-            a_cursor += 1;
-            b_cursor += 1;
+            i += 1;
+            j += 1;
         }
-
-        if a[a_cursor].is_ascii_digit() {
-            return std::cmp::Ordering::Greater;
+        if is_digit(a.get(i)) {
+            return Ordering::Greater;
         }
-        if b[b_cursor].is_ascii_digit() {
-            return std::cmp::Ordering::Less;
+        if is_digit(b.get(j)) {
+            return Ordering::Less;
         }
-        if first_diff != std::cmp::Ordering::Equal {
+        if first_diff != Ordering::Equal {
             return first_diff;
         }
     }
 
-    std::cmp::Ordering::Equal
+    Ordering::Equal
+}
+
+/// Versions are equal if dpkg considers them equal, not if they are written the same,
+/// e.g. `1.0` and `1.0-0` are equal (a missing revision compares like `0`), while `1:1.0` and `1.0` are not
+impl PartialEq for DebVersion<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(std::cmp::Ordering::Equal)
+    }
 }
 
 impl PartialOrd for DebVersion<'_> {
@@ -268,20 +317,48 @@ impl PartialOrd for DebVersion<'_> {
     }
 }
 
+/// Compare two package versions like dpkg does
+pub(crate) fn compare_versions(a: &str, b: &str) -> Result<std::cmp::Ordering> {
+    let (a, b) = (DebVersion::parse(a)?, DebVersion::parse(b)?);
+
+    Ok(a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal))
+}
+
 fn parse_version_expr(input: &str) -> Result<Vec<VersionToken>> {
+    Ok(parse_version_expr_with_spans(input)?
+        .into_iter()
+        .map(|(token, _)| token)
+        .collect())
+}
+
+/// Like `parse_version_expr`, but every token comes with its position in the input
+/// (the comparison operands of the checked version take the position of the operator)
+fn parse_version_expr_with_spans(input: &str) -> Result<Vec<(VersionToken<'_>, Range<usize>)>> {
     let mut lexer = VersionToken::lexer(input);
-    let mut stack: Vec<VersionToken> = Vec::with_capacity(8);
-    let mut operators: Vec<VersionToken> = Vec::with_capacity(8);
+    let mut stack: Vec<(VersionToken, Range<usize>)> = Vec::with_capacity(8);
+    let mut operators: Vec<(VersionToken, Range<usize>)> = Vec::with_capacity(8);
     let mut prev_is_op = false;
+    let mut prev_is_cmp_op = false;
 
     // convert infix notation to RPN
     while let Some(maybe_token) = lexer.next() {
-        let token = maybe_token
-            .map_err(|_| anyhow!("Invalid version expression at position {:?}", lexer.span()))?;
+        let token = maybe_token.map_err(|_| {
+            AprilError::InvalidVersionExpr(format!(
+                "Invalid version expression at position {:?}",
+                lexer.span()
+            ))
+        })?;
+        // purely numeric versions (e.g. `2`) are also valid hexadecimal strings
+        let token = match token {
+            VersionToken::Hexadecimal(s) if s.starts_with(|c: char| c.is_ascii_digit()) => {
+                VersionToken::VersionNumber(s)
+            }
+            _ => token,
+        };
         if token.is_cmp_op() {
             // since we use a very simplified expression format, we don't have a LHS in our "binary expression"
             // we will push a dummy VERSION_PLACEHOLDER_TOKEN to the stack, and later replace it with the actual version
-            stack.push(VERSION_PLACEHOLDER_TOKEN);
+            stack.push((VERSION_PLACEHOLDER_TOKEN, lexer.span()));
         }
 
         match token {
@@ -292,66 +369,312 @@ fn parse_version_expr(input: &str) -> Result<Vec<VersionToken>> {
             | VersionToken::LtEq
             | VersionToken::Gt
             | VersionToken::Lt
+            | VersionToken::GtGt
+            | VersionToken::LtLt
             | VersionToken::Or
             | VersionToken::And => {
-                if let Some(last_op) = operators.last() {
-                    if last_op.precedence() >= token.precedence() {
-                        let last = operators.pop().unwrap();
-                        stack.push(last);
-                        operators.push(token);
-                        prev_is_op = token.is_op();
-                        continue;
+                // all the operators binding at least as tightly are applied first (so `&&` and `||`
+                // associate to the left), `(` has the lowest precedence and stops the draining
+                while let Some((last_op, _)) = operators.last() {
+                    if last_op.precedence() < token.precedence() {
+                        break;
                     }
+                    stack.extend(operators.pop());
                 }
-                operators.push(token);
+                operators.push((token, lexer.span()));
             }
-            VersionToken::LParen => operators.push(token),
+            VersionToken::LParen => operators.push((token, lexer.span())),
             VersionToken::RParen => {
                 // drain all operators and push them back to the output stack
-                while let Some(op) = operators.pop() {
+                let mut matched = false;
+                while let Some((op, span)) = operators.pop() {
                     if op == VersionToken::LParen {
+                        matched = true;
                         break;
                     }
-                    stack.push(op);
+                    stack.push((op, span));
+                }
+                if !matched {
+                    return Err(AprilError::InvalidVersionExpr(format!(
+                        "Unmatched ')' at position {:?}",
+                        lexer.span()
+                    )));
                 }
             }
             VersionToken::Hexadecimal(_) => {
-                return Err(anyhow!(
+                return Err(AprilError::InvalidVersionExpr(format!(
                     "Invalid version expression at position {:?}",
                     lexer.span()
-                ));
+                )));
+            }
+            VersionToken::Sha256Sum(_) | VersionToken::Any => {
+                // sha256sum() and * are predicates on their own, it can not be used as a comparison operand
+                if prev_is_cmp_op {
+                    return Err(AprilError::InvalidVersionExpr(format!(
+                        "Unexpected string '{}' at position {:?}",
+                        token,
+                        lexer.span()
+                    )));
+                }
+                stack.push((token, lexer.span()));
             }
-            VersionToken::Sha256Sum(_) | VersionToken::VersionNumber(_) => {
+            VersionToken::VersionNumber(_) => {
                 if !prev_is_op {
-                    return Err(anyhow!(
+                    return Err(AprilError::InvalidVersionExpr(format!(
                         "Unexpected string '{}' at position {:?}",
                         token,
                         lexer.span()
-                    ));
+                    )));
                 }
-                stack.push(token);
+                stack.push((token, lexer.span()));
             }
         }
 
         prev_is_op = token.is_op();
+        prev_is_cmp_op = token.is_cmp_op();
     }
 
     // drain all remaining operators and add them to the output stack
-    while let Some(op) = operators.pop() {
+    while let Some((op, span)) = operators.pop() {
         if op == VersionToken::LParen {
-            return Err(anyhow!("Unmatched '(' at position {:?}", lexer.span()));
+            return Err(AprilError::InvalidVersionExpr(format!(
+                "Unmatched '(' at position {:?}",
+                span
+            )));
         }
-        stack.push(op);
+        stack.push((op, span));
     }
 
     Ok(stack)
 }
 
+enum EvalValue<'a> {
+    Version(&'a str),
+    Bool(bool),
+}
+
+fn validate_sha256sum(digest: &str) -> Result<()> {
+    if digest.len() != 64 || !digest.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Err(AprilError::InvalidVersionExpr(format!(
+            "Invalid SHA256 sum in version expression: {}",
+            digest
+        )));
+    }
+
+    Ok(())
+}
+
+fn check_sha256sum(expected: &str, actual: &str) -> Result<bool> {
+    validate_sha256sum(expected)?;
+
+    Ok(expected.eq_ignore_ascii_case(actual))
+}
+
+/// Check that the version expression can be parsed and all the `sha256sum(...)` terms are well-formed,
+/// the operands of the operators are also checked like in `evaluate_version_expr` (`true` for booleans)
+pub fn validate_version_expr(expr: &str) -> Result<()> {
+    let mut stack = Vec::new();
+    for (token, span) in parse_version_expr_with_spans(expr)? {
+        match token {
+            VersionToken::VersionNumber(VERSION_PLACEHOLDER) => stack.push(false),
+            VersionToken::VersionNumber(version) => {
+                DebVersion::parse(version)?;
+                stack.push(false);
+            }
+            VersionToken::Any => stack.push(true),
+            VersionToken::Sha256Sum(digest) => {
+                validate_sha256sum(digest)?;
+                stack.push(true);
+            }
+            VersionToken::And | VersionToken::Or => {
+                if (stack.pop(), stack.pop()) != (Some(true), Some(true)) {
+                    return Err(AprilError::InvalidVersionExpr(format!(
+                        "Invalid operands for '{}' at position {:?}",
+                        token, span
+                    )));
+                }
+                stack.push(true);
+            }
+            _ if token.is_cmp_op() => {
+                if (stack.pop(), stack.pop()) != (Some(false), Some(false)) {
+                    return Err(AprilError::InvalidVersionExpr(format!(
+                        "Invalid operands for '{}' at position {:?}",
+                        token, span
+                    )));
+                }
+                stack.push(true);
+            }
+            _ => {
+                return Err(AprilError::InvalidVersionExpr(format!(
+                    "Unexpected token '{}' at position {:?}",
+                    token, span
+                )));
+            }
+        }
+    }
+    if stack != [true] {
+        return Err(AprilError::InvalidVersionExpr(format!(
+            "Invalid version expression: {}",
+            expr
+        )));
+    }
+
+    Ok(())
+}
+
+fn compute_package_sha256(package_path: &Path) -> Result<String> {
+    let mut hasher = sha2::Sha256::new();
+    let mut file = File::open(package_path)?;
+    std::io::copy(&mut file, &mut hasher)?;
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Check if the given package version satisfies the version expression.
+///
+/// Comparisons follow the dpkg ordering of full versions (epoch, upstream version and revision),
+/// so `=1.2.3` does not match `1.2.3-1`, and `!=` is always the negation of `=`/`==`.
+///
+/// `package_path` is required to resolve `sha256sum(...)` terms; the digest of the package file
+/// is computed lazily, only if the expression contains such terms.
 pub fn check_version_compatibility(
     required_version_expr: &str,
     version_to_check: &str,
+    package_path: Option<&Path>,
+) -> Result<bool> {
+    evaluate_version_expr(required_version_expr, version_to_check, package_path, None)
+}
+
+/// Evaluate the version expression, recording each evaluation step into `trace` if given
+fn evaluate_version_expr(
+    required_version_expr: &str,
+    version_to_check: &str,
+    package_path: Option<&Path>,
+    mut trace: Option<&mut Vec<String>>,
 ) -> Result<bool> {
-    todo!()
+    let tokens = parse_version_expr(required_version_expr)?;
+    let mut stack: Vec<EvalValue> = Vec::with_capacity(tokens.len());
+    let mut package_sha256 = None;
+
+    for token in tokens {
+        match token {
+            VersionToken::VersionNumber(VERSION_PLACEHOLDER) => {
+                stack.push(EvalValue::Version(version_to_check))
+            }
+            VersionToken::VersionNumber(version) => stack.push(EvalValue::Version(version)),
+            VersionToken::Any => {
+                if let Some(trace) = trace.as_deref_mut() {
+                    trace.push(format!("{} => true", token));
+                }
+                stack.push(EvalValue::Bool(true));
+            }
+            VersionToken::Sha256Sum(expected) => {
+                if package_sha256.is_none() {
+                    let package_path = package_path.ok_or_else(|| {
+                        AprilError::InvalidVersionExpr(
+                            "sha256sum() requires the package file to be available".to_string(),
+                        )
+                    })?;
+                    package_sha256 = Some(compute_package_sha256(package_path)?);
+                }
+                let actual = package_sha256.as_deref().unwrap_or_default();
+                let result = check_sha256sum(expected, actual)?;
+                if let Some(trace) = trace.as_deref_mut() {
+                    trace.push(format!("{} => {}", token, result));
+                }
+                stack.push(EvalValue::Bool(result));
+            }
+            VersionToken::And | VersionToken::Or => {
+                let (Some(EvalValue::Bool(rhs)), Some(EvalValue::Bool(lhs))) =
+                    (stack.pop(), stack.pop())
+                else {
+                    return Err(AprilError::InvalidVersionExpr(format!(
+                        "Invalid operands for '{}'",
+                        token
+                    )));
+                };
+                let result = if token == VersionToken::And {
+                    lhs && rhs
+                } else {
+                    lhs || rhs
+                };
+                if let Some(trace) = trace.as_deref_mut() {
+                    trace.push(format!("{} {} {} => {}", lhs, token, rhs, result));
+                }
+                stack.push(EvalValue::Bool(result));
+            }
+            _ if token.is_cmp_op() => {
+                let (Some(EvalValue::Version(rhs)), Some(EvalValue::Version(lhs))) =
+                    (stack.pop(), stack.pop())
+                else {
+                    return Err(AprilError::InvalidVersionExpr(format!(
+                        "Invalid operands for '{}'",
+                        token
+                    )));
+                };
+                let (lhs_str, rhs_str) = (lhs, rhs);
+                let lhs = DebVersion::parse(lhs)?;
+                let rhs = DebVersion::parse(rhs)?;
+                let ordering = lhs.partial_cmp(&rhs).ok_or_else(|| {
+                    AprilError::InvalidVersionExpr("Can not compare versions".to_string())
+                })?;
+                let result = match token {
+                    VersionToken::Eq | VersionToken::EqEq => ordering.is_eq(),
+                    VersionToken::NotEq => ordering.is_ne(),
+                    VersionToken::GtEq => ordering.is_ge(),
+                    VersionToken::LtEq => ordering.is_le(),
+                    VersionToken::Gt | VersionToken::GtGt => ordering.is_gt(),
+                    VersionToken::Lt | VersionToken::LtLt => ordering.is_lt(),
+                    _ => unreachable!(),
+                };
+                if let Some(trace) = trace.as_deref_mut() {
+                    trace.push(format!("{} {} {} => {}", lhs_str, token, rhs_str, result));
+                }
+                stack.push(EvalValue::Bool(result));
+            }
+            _ => {
+                return Err(AprilError::InvalidVersionExpr(format!(
+                    "Unexpected token '{}' in version expression",
+                    token
+                )));
+            }
+        }
+    }
+
+    match (stack.pop(), stack.is_empty()) {
+        (Some(EvalValue::Bool(result)), true) => Ok(result),
+        _ => Err(AprilError::InvalidVersionExpr(format!(
+            "Invalid version expression: {}",
+            required_version_expr
+        ))),
+    }
+}
+
+/// Explain how the version expression is parsed (as a sequence of tokens in reverse Polish notation),
+/// and if a version is given, how it is evaluated against the version step by step
+pub fn explain_version_expr(expr: &str, version: Option<&str>) -> Result<String> {
+    let tokens = parse_version_expr(expr)?;
+    let mut explanation = format!(
+        "RPN: {}\n",
+        tokens
+            .iter()
+            .map(|token| token.to_string())
+            .collect::<Vec<_>>()
+            .join(" ")
+    );
+    let Some(version) = version else {
+        return Ok(explanation);
+    };
+
+    let mut trace = Vec::new();
+    let result = evaluate_version_expr(expr, version, None, Some(&mut trace))?;
+    explanation.push_str(&format!("Evaluation against {}:\n", version));
+    for step in trace {
+        explanation.push_str(&format!("  {}\n", step));
+    }
+    explanation.push_str(&format!("Result: {}\n", result));
+
+    Ok(explanation)
 }
 
 #[test]
@@ -366,6 +689,45 @@ fn test_lexer() {
     let lexer = VersionToken::lexer(input);
     let token = lexer.map(|t| t.unwrap()).collect::<Vec<_>>();
     assert_eq!(token, vec![VersionToken::Sha256Sum("012345abc")]);
+
+    let input = ">>1.0 && <<2.0";
+    let lexer = VersionToken::lexer(input);
+    let token = lexer.map(|t| t.unwrap()).collect::<Vec<_>>();
+    assert_eq!(
+        token,
+        vec![
+            VersionToken::GtGt,
+            VersionToken::VersionNumber("1.0"),
+            VersionToken::And,
+            VersionToken::LtLt,
+            VersionToken::VersionNumber("2.0"),
+        ]
+    );
+}
+
+#[test]
+fn test_strict_version_ops() {
+    let tokens = parse_version_expr(">>1.0 && <<2.0").unwrap();
+    assert_eq!(
+        tokens,
+        vec![
+            VERSION_PLACEHOLDER_TOKEN,
+            VersionToken::VersionNumber("1.0"),
+            VersionToken::GtGt,
+            VERSION_PLACEHOLDER_TOKEN,
+            VersionToken::VersionNumber("2.0"),
+            VersionToken::LtLt,
+            VersionToken::And,
+        ]
+    );
+    assert_eq!(
+        tokens.iter().map(|t| t.to_string()).collect::<Vec<_>>(),
+        ["$VER", "1.0", ">>", "$VER", "2.0", "<<", "&&"]
+    );
+    assert!(check_version_compatibility(">>1.0 && <<2.0", "1.5", None).unwrap());
+    assert!(!check_version_compatibility(">>1.0 && <<2.0", "1.0", None).unwrap());
+    assert!(!check_version_compatibility(">>1.0 && <<2.0", "2.0", None).unwrap());
+    assert!(check_version_compatibility("<< 2.0~rc1", "2.0~beta1", None).unwrap());
 }
 
 #[test]
@@ -385,8 +747,8 @@ fn test_parser_simple() {
             VERSION_PLACEHOLDER_TOKEN,
             VersionToken::VersionNumber("7.8.9"),
             VersionToken::Lt,
-            VersionToken::Sha256Sum("012345abc"),
             VersionToken::And,
+            VersionToken::Sha256Sum("012345abc"),
             VersionToken::And,
         ]
     );
@@ -419,6 +781,35 @@ fn test_deb_parsing() {
     assert_eq!(deb_version.release, b"");
 }
 
+#[test]
+fn test_deb_version_display() {
+    for (input, expected) in [
+        ("1:1.2.3-4", "1:1.2.3-4"),
+        ("1.2.3", "1.2.3"),
+        ("0:1.0", "1.0"),
+        ("1.0-2-3", "1.0-2-3"),
+    ] {
+        assert_eq!(DebVersion::parse(input).unwrap().to_string(), expected);
+    }
+}
+
+#[test]
+fn test_deb_parsing_epoch() {
+    let deb_version = DebVersion::parse("1:1.0").unwrap();
+    assert_eq!(deb_version.epoch, 1);
+    assert_eq!(deb_version.version, b"1.0");
+
+    let err = DebVersion::parse("abc:1.0").err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        "Invalid version abc:1.0: epoch must be an unsigned integer"
+    );
+    assert!(DebVersion::parse("-1:1.0").is_err());
+    assert!(DebVersion::parse(":1.0").is_err());
+    assert!(DebVersion::parse("99999999999:1.0").is_err());
+    assert!(check_version_compatibility(">= 1.0", "abc:1.0", None).is_err());
+}
+
 
 #[test]
 fn test_version_cmp() {
@@ -437,4 +828,440 @@ fn test_version_cmp() {
     // let a = "1.2.3-4";
     // let b = "1.2.3";
     // assert!(version_cmp(a, b) == std::cmp::Ordering::Less);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_tilde_version_cmp() {
+    let cmp = |a: &str, b: &str| {
+        DebVersion::parse(a)
+            .unwrap()
+            .partial_cmp(&DebVersion::parse(b).unwrap())
+            .unwrap()
+    };
+    assert!(cmp("1.0~rc1", "1.0").is_lt());
+    assert!(cmp("1.0~~", "1.0~").is_lt());
+    assert!(cmp("1.0", "1.0a").is_lt());
+    // one side ends right after the tilde
+    assert!(cmp("1.0~", "1.0").is_lt());
+    assert!(cmp("1.0", "1.0~").is_gt());
+    assert!(cmp("1.0~", "1.0~rc1").is_lt());
+    assert!(cmp("1.0~", "1.0~").is_eq());
+    // also in the revision
+    assert!(cmp("1.0-1~bpo1", "1.0-1").is_lt());
+    assert!(cmp("1.0-1~", "1.0-1").is_lt());
+    assert!(cmp("1.0~rc1-1", "1.0-0").is_lt());
+}
+
+/// `verrevcmp` of dpkg, transliterated as literally as possible over NUL-terminated strings
+#[cfg(test)]
+fn reference_verrevcmp(a: &[u8], b: &[u8]) -> i32 {
+    fn order(c: u8) -> i32 {
+        if c.is_ascii_digit() {
+            0
+        } else if c.is_ascii_alphabetic() {
+            c as i32
+        } else if c == b'~' {
+            -1
+        } else if c != 0 {
+            c as i32 + 256
+        } else {
+            0
+        }
+    }
+
+    let a = [a, b"\0"].concat();
+    let b = [b, b"\0"].concat();
+    let (mut i, mut j) = (0, 0);
+    while a[i] != 0 || b[j] != 0 {
+        let mut first_diff = 0;
+        while (a[i] != 0 && !a[i].is_ascii_digit()) || (b[j] != 0 && !b[j].is_ascii_digit()) {
+            let ac = order(a[i]);
+            let bc = order(b[j]);
+            if ac != bc {
+                return ac - bc;
+            }
+            i += 1;
+            j += 1;
+        }
+        while a[i] == b'0' {
+            i += 1;
+        }
+        while b[j] == b'0' {
+            j += 1;
+        }
+        while a[i].is_ascii_digit() && b[j].is_ascii_digit() {
+            if first_diff == 0 {
+                first_diff = a[i] as i32 - b[j] as i32;
+            }
+            i += 1;
+            j += 1;
+        }
+        if a[i].is_ascii_digit() {
+            return 1;
+        }
+        if b[j].is_ascii_digit() {
+            return -1;
+        }
+        if first_diff != 0 {
+            return first_diff;
+        }
+    }
+
+    0
+}
+
+#[test]
+fn test_version_string_cmp_randomized() {
+    // xorshift, so that failures are reproducible
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    // small alphabet, so that equal prefixes and runs of zeros are common
+    const ALPHABET: &[u8] = b"00019~~.+-aZ";
+    let mut random_version = || {
+        let len = next() % 8;
+        (0..len)
+            .map(|_| ALPHABET[(next() % ALPHABET.len() as u64) as usize])
+            .collect::<Vec<_>>()
+    };
+
+    for _ in 0..20000 {
+        let (a, b) = (random_version(), random_version());
+        let expected = reference_verrevcmp(&a, &b).cmp(&0);
+        assert_eq!(
+            version_string_cmp(&a, &b),
+            expected,
+            "{:?} vs {:?}",
+            String::from_utf8_lossy(&a),
+            String::from_utf8_lossy(&b)
+        );
+        assert_eq!(version_string_cmp(&b, &a), expected.reverse());
+    }
+}
+
+#[test]
+fn test_version_cmp_dpkg_vectors() {
+    let mut failures = Vec::new();
+    for (line_number, line) in include_str!("../testdata/dpkg-version-cmp.txt")
+        .lines()
+        .enumerate()
+    {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let [a, b, expected] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+            panic!("Invalid test vector at line {}: {}", line_number + 1, line);
+        };
+        let expected = match expected {
+            "-1" => std::cmp::Ordering::Less,
+            "0" => std::cmp::Ordering::Equal,
+            "1" => std::cmp::Ordering::Greater,
+            _ => panic!(
+                "Invalid expected result at line {}: {}",
+                line_number + 1,
+                line
+            ),
+        };
+        let (a_version, b_version) = (DebVersion::parse(a), DebVersion::parse(b));
+        let (Ok(a_version), Ok(b_version)) = (&a_version, &b_version) else {
+            failures.push(format!(
+                "line {}: {} {} (can not parse)",
+                line_number + 1,
+                a,
+                b
+            ));
+            continue;
+        };
+        let results = (
+            a_version.partial_cmp(b_version),
+            b_version.partial_cmp(a_version),
+        );
+        if results != (Some(expected), Some(expected.reverse())) {
+            failures.push(format!(
+                "line {}: {} {} (expected {:?}, got {:?})",
+                line_number + 1,
+                a,
+                b,
+                expected,
+                results.0
+            ));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn test_check_sha256sum() {
+    let dir = tempfile::tempdir().unwrap();
+    let package_path = dir.path().join("foo.deb");
+    std::fs::write(&package_path, b"Hello, world!").unwrap();
+    let digest = "315f5bdb76d078c43b8ac0064e4a0164612b1fce77c869345bfc94c75894edd3";
+
+    let expr = format!("sha256sum({})", digest);
+    assert!(check_version_compatibility(&expr, "1.0", Some(&package_path)).unwrap());
+    let expr = format!("sha256sum({})", digest.to_ascii_uppercase());
+    assert!(check_version_compatibility(&expr, "1.0", Some(&package_path)).unwrap());
+    let expr = format!(">=1.0 && sha256sum({})", digest.replace('3', "4"));
+    assert!(!check_version_compatibility(&expr, "1.0", Some(&package_path)).unwrap());
+    let expr = format!("<1.0 || sha256sum({})", digest);
+    assert!(check_version_compatibility(&expr, "1.0", Some(&package_path)).unwrap());
+
+    // malformed digest
+    assert!(check_version_compatibility("sha256sum(abc)", "1.0", Some(&package_path)).is_err());
+    // no package to check against
+    let expr = format!("sha256sum({})", digest);
+    assert!(check_version_compatibility(&expr, "1.0", None).is_err());
+}
+
+#[test]
+fn test_validate_version_expr() {
+    validate_version_expr("*").unwrap();
+    validate_version_expr(">= 1.0 && < 2.0").unwrap();
+    validate_version_expr("= 1.0-1 || (!= 2.0 && == 2.0~rc1)").unwrap();
+    validate_version_expr(&format!("sha256sum({}) || == 2.0.0", "0".repeat(64))).unwrap();
+    assert!(validate_version_expr(">= 1.0 &&").is_err());
+    assert_eq!(
+        validate_version_expr(">=1.0 &&").unwrap_err().to_string(),
+        "Invalid operands for '&&' at position 6..8"
+    );
+    assert_eq!(
+        validate_version_expr("|| < 2.0").unwrap_err().to_string(),
+        "Invalid operands for '||' at position 0..2"
+    );
+    assert!(validate_version_expr("&& >= 1.0").is_err());
+    assert!(validate_version_expr(">= a:1.0").is_err());
+    assert!(validate_version_expr("sha256sum(0000abcd)").is_err());
+}
+
+#[test]
+fn test_unmatched_parens() {
+    let err = parse_version_expr("=1.0)").unwrap_err();
+    assert_eq!(err.to_string(), "Unmatched ')' at position 4..5");
+    let err = parse_version_expr("(=1.0))").unwrap_err();
+    assert_eq!(err.to_string(), "Unmatched ')' at position 6..7");
+    assert!(parse_version_expr("(=1.0").is_err());
+    assert!(parse_version_expr("(=1.0)").is_ok());
+}
+
+#[test]
+fn test_explain_version_expr() {
+    assert_eq!(
+        explain_version_expr(">= 1.0 && < 2.0", None).unwrap(),
+        "RPN: $VER 1.0 >= $VER 2.0 < &&\n"
+    );
+    assert_eq!(
+        explain_version_expr(">= 1.0 && < 2.0", Some("2.0-1")).unwrap(),
+        "RPN: $VER 1.0 >= $VER 2.0 < &&
+Evaluation against 2.0-1:
+  2.0-1 >= 1.0 => true
+  2.0-1 < 2.0 => false
+  true && false => false
+Result: false
+"
+    );
+    assert!(explain_version_expr("(>= 1.0", None).is_err());
+}
+
+#[test]
+fn test_eq_eq_version() {
+    let tokens = parse_version_expr("==1.0 && >0.5").unwrap();
+    assert_eq!(
+        tokens,
+        vec![
+            VERSION_PLACEHOLDER_TOKEN,
+            VersionToken::VersionNumber("1.0"),
+            VersionToken::EqEq,
+            VERSION_PLACEHOLDER_TOKEN,
+            VersionToken::VersionNumber("0.5"),
+            VersionToken::Gt,
+            VersionToken::And,
+        ]
+    );
+    for expr in ["=2.1.1+b3", "==2.1.1+b3"] {
+        assert!(check_version_compatibility(expr, "2.1.1+b3", None).unwrap());
+        assert!(!check_version_compatibility(expr, "2.1.1+b3-1", None).unwrap());
+    }
+    for expr in ["=1.0 || =2.0", "==1.0 || ==2.0"] {
+        assert!(check_version_compatibility(expr, "2.0", None).unwrap());
+        assert!(!check_version_compatibility(expr, "3.0", None).unwrap());
+    }
+}
+
+#[test]
+fn test_not_eq_version() {
+    assert!(!check_version_compatibility("!=1.2.3", "1.2.3", None).unwrap());
+    assert!(check_version_compatibility("!=1.2.3", "1.2.3-1", None).unwrap());
+    assert!(check_version_compatibility("!=1.2.3", "1.2.4", None).unwrap());
+    assert!(!check_version_compatibility("!=1.2.3", "0:1.2.3", None).unwrap());
+    assert!(check_version_compatibility(">=1.0 && !=1.2.3", "1.5", None).unwrap());
+    assert!(!check_version_compatibility(">=1.0 && !=1.2.3", "1.2.3", None).unwrap());
+}
+
+#[test]
+fn test_eq_version_semantics() {
+    let parse = |version| DebVersion::parse(version).unwrap();
+    // a missing revision compares equal to `-0`, like in dpkg
+    assert!(parse("1.0") == parse("1.0-0"));
+    assert!(parse("0:1.0") == parse("1.0"));
+    assert!(parse("1:1.0") != parse("0:1.0"));
+    assert!(parse("1.0") != parse("1.0-1"));
+
+    for expr in ["=1.0", "==1.0"] {
+        assert!(check_version_compatibility(expr, "1.0-0", None).unwrap());
+        assert!(check_version_compatibility(expr, "0:1.0", None).unwrap());
+        assert!(!check_version_compatibility(expr, "1:1.0", None).unwrap());
+    }
+    assert!(check_version_compatibility("=1.0-0", "1.0", None).unwrap());
+    assert!(!check_version_compatibility("!=1.0", "1.0-0", None).unwrap());
+    assert!(check_version_compatibility("!=1.0", "1:1.0", None).unwrap());
+}
+
+#[test]
+fn test_check_version_compatibility() {
+    assert!(check_version_compatibility("*", "1.0", None).unwrap());
+    assert!(check_version_compatibility(">= 1.0 && < 2.0", "1.5-1", None).unwrap());
+    assert!(!check_version_compatibility(">= 1.0 && < 2.0", "2.0", None).unwrap());
+    assert!(check_version_compatibility("<1.0 || >1:0", "1:0.1", None).unwrap());
+    assert!(check_version_compatibility("(=1.2.3 || =4.5.6) && <5", "4.5.6", None).unwrap());
+    // `&&` and `||` associate to the left, also after a comparison
+    assert!(check_version_compatibility("* && =0 && =0 || *", "0~rc1", None).unwrap());
+    assert!(!check_version_compatibility("* || * && =0", "1.0", None).unwrap());
+}
+
+/// A well-formed version expression for the randomized tests, with its own evaluation
+#[cfg(test)]
+#[derive(Debug, Clone)]
+enum TestExpr {
+    Any,
+    Cmp(&'static str, String),
+    And(Box<TestExpr>, Box<TestExpr>),
+    Or(Box<TestExpr>, Box<TestExpr>),
+}
+
+#[cfg(test)]
+impl TestExpr {
+    fn render(&self) -> String {
+        match self {
+            TestExpr::Any => "*".to_string(),
+            TestExpr::Cmp(op, version) => format!("{} {}", op, version),
+            TestExpr::And(lhs, rhs) => format!("({} && {})", lhs.render(), rhs.render()),
+            TestExpr::Or(lhs, rhs) => format!("({} || {})", lhs.render(), rhs.render()),
+        }
+    }
+
+    fn evaluate(&self, version: &str) -> bool {
+        match self {
+            TestExpr::Any => true,
+            TestExpr::Cmp(op, operand) => {
+                let ordering = DebVersion::parse(version)
+                    .unwrap()
+                    .partial_cmp(&DebVersion::parse(operand).unwrap())
+                    .unwrap();
+                match *op {
+                    "=" | "==" => ordering.is_eq(),
+                    "!=" => ordering.is_ne(),
+                    ">=" => ordering.is_ge(),
+                    "<=" => ordering.is_le(),
+                    ">" | ">>" => ordering.is_gt(),
+                    "<" | "<<" => ordering.is_lt(),
+                    _ => unreachable!(),
+                }
+            }
+            TestExpr::And(lhs, rhs) => lhs.evaluate(version) && rhs.evaluate(version),
+            TestExpr::Or(lhs, rhs) => lhs.evaluate(version) || rhs.evaluate(version),
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_version_strategy() -> impl proptest::strategy::Strategy<Value = String> {
+    // few distinct components, so that equal versions are common
+    proptest::string::string_regex("([12]:)?[0-2](\\.[0-2]){0,2}(~rc[12])?(-[01])?").unwrap()
+}
+
+#[cfg(test)]
+fn test_expr_strategy() -> impl proptest::strategy::Strategy<Value = TestExpr> {
+    use proptest::prelude::*;
+
+    let leaf = prop_oneof![
+        1 => Just(TestExpr::Any),
+        8 => (
+            proptest::sample::select(&["=", "==", "!=", ">=", "<=", ">", "<", ">>", "<<"][..]),
+            test_version_strategy(),
+        )
+            .prop_map(|(op, version)| TestExpr::Cmp(op, version)),
+    ];
+    leaf.prop_recursive(4, 16, 2, |inner| {
+        prop_oneof![
+            (inner.clone(), inner.clone())
+                .prop_map(|(lhs, rhs)| TestExpr::And(Box::new(lhs), Box::new(rhs))),
+            (inner.clone(), inner)
+                .prop_map(|(lhs, rhs)| TestExpr::Or(Box::new(lhs), Box::new(rhs))),
+        ]
+    })
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_version_expr_never_panics(
+        expr in proptest::prop_oneof![
+            proptest::string::string_regex("\\PC{0,24}").unwrap(),
+            // mostly the characters of the expression syntax, to get past the lexer
+            proptest::string::string_regex(
+                "([0-9a-f.~+:()<>=!|&* -]|sha256sum|\\$VER|&&|\\|\\|){0,24}"
+            )
+            .unwrap(),
+        ],
+        version in test_version_strategy(),
+    ) {
+        let _ = parse_version_expr(&expr);
+        let _ = validate_version_expr(&expr);
+        let _ = check_version_compatibility(&expr, &version, None);
+        let _ = explain_version_expr(&expr, Some(&version));
+    }
+
+    #[test]
+    fn test_version_expr_evaluation(expr in test_expr_strategy(), version in test_version_strategy()) {
+        let rendered = expr.render();
+        proptest::prop_assert!(validate_version_expr(&rendered).is_ok(), "{}", rendered);
+        proptest::prop_assert_eq!(
+            check_version_compatibility(&rendered, &version, None).unwrap(),
+            expr.evaluate(&version),
+            "{} against {}",
+            rendered,
+            version
+        );
+    }
+
+    /// `&&` and `||` have the same precedence and associate to the left
+    #[test]
+    fn test_version_expr_left_associative(
+        first in test_expr_strategy(),
+        rest in proptest::collection::vec((proptest::bool::ANY, test_expr_strategy()), 0..4),
+        version in test_version_strategy(),
+    ) {
+        let mut rendered = first.render();
+        let mut expected = first.evaluate(&version);
+        for (and, expr) in &rest {
+            rendered.push_str(if *and { " && " } else { " || " });
+            rendered.push_str(&expr.render());
+            expected = if *and {
+                expected && expr.evaluate(&version)
+            } else {
+                expected || expr.evaluate(&version)
+            };
+        }
+        proptest::prop_assert_eq!(
+            check_version_compatibility(&rendered, &version, None).unwrap(),
+            expected,
+            "{} against {}",
+            rendered,
+            version
+        );
+    }
+}
@@ -0,0 +1,151 @@
+//! This module contains the error type shared by all APRIL operations
+
+use std::{process::ExitStatus, time::Duration};
+
+use thiserror::Error;
+
+use crate::april::ValidationReport;
+
+pub type Result<T, E = AprilError> = std::result::Result<T, E>;
+
+#[derive(Debug, Error)]
+pub enum AprilError {
+    /// the APRIL configuration has problems
+    #[error(transparent)]
+    InvalidConfig(#[from] ValidationReport),
+    #[error("{0}")]
+    InvalidVersionExpr(String),
+    #[error("Invalid version {version}: {reason}")]
+    InvalidVersion { version: String, reason: String },
+    #[error("{0}")]
+    InvalidRelationship(String),
+    #[error("No APRIL configuration is compatible with version {0}")]
+    NoCompatibleConfig(String),
+    #[error("Multiple APRIL configurations are compatible with version {version}: {candidates}")]
+    AmbiguousConfig { version: String, candidates: String },
+    #[error("Unknown multiarch tuple for architecture {0}")]
+    UnknownArchitecture(String),
+    /// the path escapes from the package root (or does not have a valid form)
+    #[error("Invalid file path: {0}")]
+    PathEscape(String),
+    #[error("{0}")]
+    InvalidResourceUri(String),
+    #[error("Unsupported scheme in resource URI: {0}")]
+    UnsupportedScheme(String),
+    /// plain `http` resources are only fetched when explicitly allowed
+    #[error("Refusing to fetch resource over insecure http: {0}")]
+    InsecureResourceUri(String),
+    #[error("{algorithm} sum mismatch for resource: {resource}, expected {expected}, got {actual}")]
+    ResourceDigestMismatch {
+        algorithm: &'static str,
+        resource: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("Failed to fetch resource: {url} ({reason})")]
+    FetchFailed { url: String, reason: String },
+    #[error("Resource {url} exceeds the download size limit ({limit} bytes)")]
+    ResourceTooLarge { url: String, limit: u64 },
+    /// the resource can not be located (missing bundle entry, outside of the resource directory, etc.)
+    #[error("{0}")]
+    ResourceUnavailable(String),
+    /// `dpkg-deb` exited with an error
+    #[error("Failed to {operation}: {detail}")]
+    DpkgCommandFailed {
+        operation: &'static str,
+        detail: String,
+    },
+    /// the built-in patch applier can not parse or apply the patch
+    #[error("Failed to apply patch to {path}: {reason}")]
+    PatchRejected { path: String, reason: String },
+    /// an external program (`patch`, `xdelta3`, hooks) exited with an error
+    #[error("Failed to {operation}: {status}")]
+    CommandFailed {
+        operation: &'static str,
+        status: ExitStatus,
+    },
+    /// changing file ownership requires root privileges (or running under `fakeroot`)
+    #[error(
+        "Insufficient privileges to change the owner of {path} to {uid}:{gid} (try running as root or under fakeroot)"
+    )]
+    ChownNotPermitted { path: String, uid: u32, gid: u32 },
+    /// an external program applying a patch exited with an error, `output` is what it printed
+    #[error("Failed to {operation}: {status}{}", indent_output(.output))]
+    ToolFailed {
+        operation: &'static str,
+        status: ExitStatus,
+        output: String,
+    },
+    /// an external program applying a patch did not finish in time and was killed
+    #[error("Failed to {operation}: timed out after {timeout:?}")]
+    ToolTimedOut {
+        operation: &'static str,
+        timeout: Duration,
+    },
+    /// the external programs needed by the planned actions can not be found
+    #[error("Required programs not found in PATH: {}", .0.join(", "))]
+    MissingTools(Vec<&'static str>),
+    #[error("{0}")]
+    InvalidPackage(String),
+    #[error("Invalid control data: {0}")]
+    InvalidControlData(String),
+    #[error("{0}")]
+    SignatureVerification(String),
+    /// a planned action failed, `action` describes the action (see the `Display` of `AprilAction`)
+    #[error("Failed to {action}: {source}")]
+    ActionFailed {
+        action: String,
+        source: Box<AprilError>,
+    },
+    /// several actions failed when continuing after errors
+    #[error("{} actions failed:{}", .0.len(), bullet_list(.0))]
+    ActionsFailed(Vec<AprilError>),
+    /// file operations modify files registered as conffiles, see `ReconstructOptions::deny_conffile_edits`
+    #[error("File operations modify conffiles, which breaks the conffile handling of dpkg:{}", bullet_list(.0))]
+    ConffileEdits(Vec<String>),
+    /// the verification after applying the actions failed, see `ReconstructOptions::verify`
+    #[error("The patched package does not match the actions:{}", bullet_list(.0))]
+    VerificationFailed(Vec<String>),
+    #[error("Reconstructed package is too large: {size} bytes (limit: {limit} bytes)")]
+    OutputTooLarge { size: u64, limit: u64 },
+    #[error("{0}")]
+    InvalidAction(String),
+    /// the reconstruction options are not valid
+    #[error("{0}")]
+    InvalidOption(String),
+    #[error("No files match the pattern {0}")]
+    NoGlobMatch(String),
+    #[error("Pattern '{pattern}' does not match anything in {path}")]
+    SubstitutionNoMatch { path: String, pattern: String },
+    /// the placeholder in a templated configuration value is unknown or can not be resolved
+    #[error("Unresolved placeholder {placeholder} in '{value}'")]
+    UnresolvedPlaceholder { placeholder: String, value: String },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Http(#[from] ureq::Error),
+    #[error(transparent)]
+    Url(#[from] url::ParseError),
+    #[error(transparent)]
+    Base64(#[from] base64::DecodeError),
+    #[error(transparent)]
+    Utf8(#[from] std::str::Utf8Error),
+    #[error(transparent)]
+    FromUtf8(#[from] std::string::FromUtf8Error),
+    #[error(transparent)]
+    Zip(#[from] zip::result::ZipError),
+}
+
+/// Put each line of the program output on its own indented line
+fn indent_output(output: &str) -> String {
+    output
+        .trim_end()
+        .lines()
+        .map(|line| format!("\n  {}", line))
+        .collect()
+}
+
+/// Put each item on its own indented line, as a bulleted list
+fn bullet_list(items: &[impl std::fmt::Display]) -> String {
+    items.iter().map(|item| format!("\n  - {}", item)).collect()
+}
@@ -0,0 +1,25 @@
+//! APRIL (AOSC Package Reconstruction Information Listing) support library
+//!
+//! This crate parses APRIL configurations, plans the actions needed to patch a dpkg package
+//! and applies them to reconstruct the package.
+
+pub mod april;
+mod april_version;
+pub mod error;
+pub mod reconstruct;
+mod relationship;
+pub mod signature;
+
+pub use april::{
+    AprilAction, AprilPackage, ValidationReport, april_json_schema, layer_planned_actions,
+    plan_actions_from_april_data, select_april_package, validate_april_data,
+};
+pub use april_version::explain_version_expr;
+pub use deb822_lossless::{Deb822, Paragraph};
+pub use error::{AprilError, Result};
+pub use reconstruct::{
+    ExternalResource, FetchConfig, ReconstructOptions, apply_actions_for_reconstruct,
+    apply_actions_for_reconstruct_with, apply_actions_to_tree, check_action_resources,
+    external_resources, read_control_field, reconstruct_plan,
+};
+pub use signature::{GpgVerifier, SignatureVerifier};
@@ -1,39 +1,587 @@
-mod april;
-mod april_version;
-mod reconstruct;
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
+};
 
-use std::fs::File;
+use anyhow::{Context, Result, bail};
 
+use appam::{
+    AprilError, april, check_action_resources, explain_version_expr, external_resources,
+    reconstruct, signature,
+};
 use argh::FromArgs;
 
 /// Command-line tool for applying APRIL patches to dpkg packages.
 #[derive(FromArgs, Debug)]
 struct Args {
-    /// path to the dpkg package
+    /// show more details of what is being done (repeat for every action, e.g. `-v -v`)
+    #[argh(switch, short = 'v')]
+    verbose: u8,
+    #[argh(subcommand)]
+    command: Command,
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand)]
+enum Command {
+    Apply(ApplyArgs),
+    Check(CheckArgs),
+    Plan(PlanArgs),
+    Resources(ResourcesArgs),
+    ShowField(ShowFieldArgs),
+    Schema(SchemaArgs),
+    ExplainVersion(ExplainVersionArgs),
+}
+
+/// Apply APRIL patches to a dpkg package.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "apply")]
+struct ApplyArgs {
+    /// path to the dpkg package (not needed with --root)
     #[argh(positional)]
-    package_path: String,
-    /// path to the APRIL configuration file
+    package_path: Option<String>,
+    /// path to the APRIL configuration file, repeat to apply several configurations in order
     #[argh(option, short = 'c', long = "config")]
-    april_config_path: String,
+    april_config_path: Vec<String>,
     /// reconstruction mode (repack the package instead of installing it, default: false)
     #[argh(switch, short = 'r', long = "reconstruct")]
     reconstruction: bool,
+    /// apply the actions in place to a package already extracted (by `dpkg-deb -R`) to the directory,
+    /// instead of reconstructing the package
+    #[argh(option, long = "root")]
+    root: Option<PathBuf>,
+    /// path of the reconstructed package (default: <name>.repacked.deb next to the original package)
+    #[argh(option, short = 'o', long = "output")]
+    output: Option<PathBuf>,
+    /// print the planned actions without applying them
+    #[argh(switch, long = "dry-run")]
+    dry_run: bool,
+    /// verify the original package signature against the specified keyring before reconstruction
+    #[argh(option, long = "verify-keyring")]
+    verify_keyring: Option<PathBuf>,
+    /// umask (in octal) for files and directories created by file operations (default: 022)
+    #[argh(option, long = "umask", from_str_fn(parse_octal))]
+    umask: Option<u32>,
+    /// reconstruct the package for the specified dpkg architecture
+    #[argh(option, long = "target-arch")]
+    target_arch: Option<String>,
+    /// also rewrite multiarch path components in file operations when using --target-arch
+    #[argh(switch, long = "rewrite-multiarch")]
+    rewrite_multiarch: bool,
+    /// refuse to produce the reconstructed package if it would be larger than the specified size (in bytes)
+    #[argh(option, long = "max-output-size")]
+    max_output_size: Option<u64>,
+    /// tar or zip archive containing the resources referenced with the `bundle:` scheme
+    #[argh(option, long = "resource-bundle")]
+    resource_bundle: Option<PathBuf>,
+    /// directory containing local resources (default: the directory of the APRIL configuration files)
+    #[argh(option, long = "resource-dir")]
+    resource_dir: Option<PathBuf>,
+    /// shell command to run after a successful reconstruction ({output}, {name} and {version} are substituted)
+    #[argh(option, long = "after")]
+    after: Option<String>,
+    /// timeout (in seconds) for the external programs applying patches (default: 600)
+    #[argh(option, long = "tool-timeout")]
+    tool_timeout: Option<u64>,
+    /// timeout (in seconds) for fetching external resources (default: 30)
+    #[argh(option, long = "fetch-timeout")]
+    fetch_timeout: Option<u64>,
+    /// how many times to retry fetching external resources after transient failures (default: 3)
+    #[argh(option, long = "fetch-retries")]
+    fetch_retries: Option<u32>,
+    /// abort downloading external resources larger than the specified size (in bytes, default: 512 MiB)
+    #[argh(option, long = "max-download-size")]
+    max_download_size: Option<u64>,
+    /// allow fetching external resources over plain http (only https is allowed by default)
+    #[argh(switch, long = "allow-insecure-http")]
+    allow_insecure_http: bool,
+    /// apply patches with the built-in unified diff applier instead of the external `patch` command
+    #[argh(switch, long = "builtin-patch")]
+    builtin_patch: bool,
+    /// keep the directory the package is extracted to, for diagnosing the reconstruction
+    #[argh(switch, long = "keep-temp")]
+    keep_temp: bool,
+    /// directory to extract the package in (default: $TMPDIR, or the directory of the package)
+    #[argh(option, long = "temp-dir")]
+    temp_dir: Option<PathBuf>,
+    /// compressor for the reconstructed package (gzip, xz, zstd or none, default: dpkg-deb default)
+    #[argh(option, long = "compression")]
+    compression: Option<String>,
+    /// compression level for the reconstructed package (default: dpkg-deb default)
+    #[argh(option, long = "compression-level")]
+    compression_level: Option<u32>,
+    /// produce identical packages from identical inputs, the timestamps are clamped to $SOURCE_DATE_EPOCH
+    /// (default: the latest modification time in the original package)
+    #[argh(switch, long = "reproducible")]
+    reproducible: bool,
+    /// how many file operations to apply concurrently (default: 1)
+    #[argh(option, short = 'j', long = "jobs")]
+    jobs: Option<usize>,
+    /// abort unless the package has the specified SHA256 digest
+    #[argh(option, long = "expected-sha256")]
+    expected_sha256: Option<String>,
+    /// keep applying the remaining actions after an action fails, and report all the failures at the end
+    #[argh(switch, long = "continue-on-error")]
+    continue_on_error: bool,
+    /// refuse to apply file operations modifying conffiles of the original package, instead of only warning about them
+    #[argh(switch, long = "deny-conffile-edits")]
+    deny_conffile_edits: bool,
+    /// append to package scripts even if they already contain the appended content
+    #[argh(switch, long = "force-append")]
+    force_append: bool,
+    /// check that the field and script actions have their intended effects after applying them
+    #[argh(switch, long = "verify")]
+    verify: bool,
+    /// write a JSON manifest of the applied actions, resources, touched files and changed fields
+    #[argh(option, long = "manifest")]
+    manifest: Option<PathBuf>,
+    /// proxy for fetching resources (default: from the https_proxy, http_proxy and all_proxy environment variables, honoring no_proxy)
+    #[argh(option, long = "proxy")]
+    proxy: Option<String>,
+    /// do not cache downloaded resources (in $XDG_CACHE_HOME/april)
+    #[argh(switch, long = "no-cache")]
+    no_cache: bool,
 }
 
-fn main() {
-    let args: Args = argh::from_env();
+fn parse_octal(value: &str) -> Result<u32, String> {
+    u32::from_str_radix(value, 8).map_err(|e| format!("invalid octal number: {}", e))
+}
+
+/// Check an APRIL configuration file for problems without applying it.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "check")]
+struct CheckArgs {
+    /// path to the APRIL configuration file
+    #[argh(option, short = 'c', long = "config")]
+    april_config_path: String,
+    /// tar or zip archive containing the resources referenced with the `bundle:` scheme
+    #[argh(option, long = "resource-bundle")]
+    resource_bundle: Option<PathBuf>,
+    /// directory containing local resources (default: the directory of the APRIL configuration file)
+    #[argh(option, long = "resource-dir")]
+    resource_dir: Option<PathBuf>,
+    /// allow external resources over plain http
+    #[argh(switch, long = "allow-insecure-http")]
+    allow_insecure_http: bool,
+}
+
+/// Print the actions planned for a dpkg package without applying them.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "plan")]
+struct PlanArgs {
+    /// path to the dpkg package
+    #[argh(positional)]
+    package_path: String,
+    /// path to the APRIL configuration file, repeat to plan several configurations applied in order
+    #[argh(option, short = 'c', long = "config")]
+    april_config_path: Vec<String>,
+    /// output format (text or json, default: text)
+    #[argh(
+        option,
+        long = "format",
+        from_str_fn(parse_plan_format),
+        default = "PlanFormat::Text"
+    )]
+    format: PlanFormat,
+    /// plan the actions for the specified dpkg architecture
+    #[argh(option, long = "target-arch")]
+    target_arch: Option<String>,
+    /// also rewrite multiarch path components in file operations when using --target-arch
+    #[argh(switch, long = "rewrite-multiarch")]
+    rewrite_multiarch: bool,
+}
+
+#[derive(Debug)]
+enum PlanFormat {
+    Text,
+    Json,
+}
+
+fn parse_plan_format(value: &str) -> Result<PlanFormat, String> {
+    match value {
+        "text" => Ok(PlanFormat::Text),
+        "json" => Ok(PlanFormat::Json),
+        _ => Err(format!("unknown format: {} (expected text or json)", value)),
+    }
+}
+
+/// Print the external resources (URL and pinned digest) needed to apply the APRIL configuration to a dpkg package.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "resources")]
+struct ResourcesArgs {
+    /// path to the dpkg package
+    #[argh(positional)]
+    package_path: String,
+    /// path to the APRIL configuration file, repeat to list the resources of several configurations
+    #[argh(option, short = 'c', long = "config")]
+    april_config_path: Vec<String>,
+}
+
+/// Print the current value of a control field of a dpkg package.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "show-field")]
+struct ShowFieldArgs {
+    /// path to the dpkg package
+    #[argh(positional)]
+    package_path: String,
+    /// name of the control field, e.g. Depends
+    #[argh(positional)]
+    field: String,
+}
 
-    let april_file =
-        File::open(&args.april_config_path).expect("Failed to open APRIL configuration file");
-    let april_data: Vec<april::AprilPackage> =
-        serde_json::from_reader(april_file).expect("Failed to parse APRIL configuration file");
-    // TODO: version selection not yet implemented
-    let actions = april::plan_actions_from_april_data(&april_data[0])
-        .expect("Failed to plan actions from APRIL data");
-    if args.reconstruction {
-        reconstruct::apply_actions_for_reconstruct(args.package_path, &actions)
-            .expect("Failed to apply actions for reconstruct");
+/// Print the JSON schema of APRIL configuration files.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "schema")]
+struct SchemaArgs {}
+
+/// Show how a version expression is parsed and evaluated.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "explain-version")]
+struct ExplainVersionArgs {
+    /// the version expression (as in `compatible_versions`)
+    #[argh(positional)]
+    expr: String,
+    /// the package version to evaluate the expression against
+    #[argh(positional)]
+    version: Option<String>,
+}
+
+/// The directory local resources are resolved against without --resource-dir: the directory of the
+/// APRIL configuration files, which must all be in the same directory
+fn config_resource_dir(april_config_paths: &[String]) -> Result<Option<PathBuf>> {
+    // `parent()` of a bare file name is an empty path
+    let dirs = april_config_paths
+        .iter()
+        .filter_map(|path| Path::new(path).parent())
+        .map(|dir| Path::new(".").join(dir))
+        .collect::<Vec<_>>();
+    let same_dir = |a: &PathBuf, b: &PathBuf| match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    };
+    if let Some(dir) = dirs.iter().find(|dir| !same_dir(dir, &dirs[0])) {
+        bail!(
+            "APRIL configuration files in different directories ({} and {}) need --resource-dir to resolve local resources",
+            dirs[0].display(),
+            dir.display()
+        );
+    }
+
+    Ok(dirs.into_iter().next())
+}
+
+/// Select the compatible APRIL configuration for the package from each configuration file and
+/// plan the actions from them, the configurations are applied in the order they are given
+fn plan_actions(
+    april_config_paths: &[String],
+    package_version: &str,
+    package_path: Option<&Path>,
+    target_arch: Option<&str>,
+    rewrite_multiarch: bool,
+) -> Result<Vec<april::AprilAction>> {
+    if april_config_paths.is_empty() {
+        bail!("At least one APRIL configuration file is required (-c)");
+    }
+    let mut plans = Vec::new();
+    for april_config_path in april_config_paths {
+        let april_file = File::open(april_config_path).with_context(|| {
+            format!(
+                "Failed to open APRIL configuration file {}",
+                april_config_path
+            )
+        })?;
+        let april_data: Vec<april::AprilPackage> = serde_json::from_reader(april_file)
+            .with_context(|| {
+                format!(
+                    "Failed to parse APRIL configuration file {}",
+                    april_config_path
+                )
+            })?;
+        for package in &april_data {
+            april::validate_april_data(package).context("Invalid APRIL configuration")?;
+        }
+        let mut april_package =
+            april::select_april_package(&april_data, package_version, package_path)
+                .context("Failed to select APRIL configuration")?
+                .clone();
+        april::substitute_placeholders(&mut april_package, package_version, |name| {
+            std::env::var(name).ok()
+        })
+        .context("Failed to substitute placeholders in APRIL configuration")?;
+        plans.push(
+            april::plan_actions_from_april_data(&april_package)
+                .context("Failed to plan actions from APRIL data")?,
+        );
+    }
+    let mut actions = april::layer_planned_actions(plans);
+    if let Some(target_arch) = target_arch {
+        april::retarget_actions_arch(&mut actions, target_arch, rewrite_multiarch)
+            .context("Failed to retarget package architecture")?;
+    }
+
+    Ok(actions)
+}
+
+fn apply(args: ApplyArgs) -> Result<()> {
+    let package_path = args.package_path.as_deref().map(Path::new);
+    let package_version = match (&args.root, package_path) {
+        (Some(root), _) => reconstruct::read_tree_package_version(root),
+        (None, Some(package_path)) => reconstruct::read_package_version(package_path),
+        (None, None) => bail!("A package path is required unless --root is given"),
+    }
+    .context("Failed to read package version")?;
+    let actions = plan_actions(
+        &args.april_config_path,
+        &package_version,
+        package_path,
+        args.target_arch.as_deref(),
+        args.rewrite_multiarch,
+    )?;
+    if args.reconstruction || args.root.is_some() {
+        let default_fetch = reconstruct::FetchConfig::default();
+        let options = reconstruct::ReconstructOptions {
+            signature_verifier: args.verify_keyring.map(|keyring| {
+                Box::new(signature::GpgVerifier { keyring })
+                    as Box<dyn signature::SignatureVerifier>
+            }),
+            umask: args.umask.unwrap_or(reconstruct::DEFAULT_UMASK),
+            max_output_size: args.max_output_size,
+            resource_bundle: args.resource_bundle,
+            resource_base_dir: match args.resource_dir {
+                Some(resource_dir) => Some(resource_dir),
+                None => config_resource_dir(&args.april_config_path)?,
+            },
+            post_reconstruct_hook: args.after,
+            output: args.output,
+            dry_run: args.dry_run,
+            builtin_patch: args.builtin_patch,
+            keep_temp: args.keep_temp,
+            temp_dir: args.temp_dir,
+            compression: args.compression,
+            compression_level: args.compression_level,
+            reproducible: args.reproducible,
+            jobs: args.jobs.unwrap_or(1).max(1),
+            continue_on_error: args.continue_on_error,
+            expected_sha256: args.expected_sha256,
+            manifest: args.manifest,
+            deny_conffile_edits: args.deny_conffile_edits,
+            force_append: args.force_append,
+            verify: args.verify,
+            tool_timeout: args
+                .tool_timeout
+                .map(Duration::from_secs)
+                .unwrap_or(reconstruct::DEFAULT_TOOL_TIMEOUT),
+            fetch: reconstruct::FetchConfig {
+                timeout: args
+                    .fetch_timeout
+                    .map(Duration::from_secs)
+                    .unwrap_or(default_fetch.timeout),
+                retries: args.fetch_retries.unwrap_or(default_fetch.retries),
+                max_download_size: args
+                    .max_download_size
+                    .unwrap_or(default_fetch.max_download_size),
+                allow_insecure_http: args.allow_insecure_http,
+                proxy: args.proxy,
+                cache_dir: if args.no_cache {
+                    None
+                } else {
+                    reconstruct::default_cache_dir()
+                },
+                ..default_fetch
+            },
+        };
+        if args.dry_run {
+            let plan = match (&args.root, package_path) {
+                (Some(root), _) => {
+                    reconstruct::apply_actions_to_tree(root, &actions, &options)
+                        .context("Failed to apply actions to the package tree")?;
+                    actions.iter().map(|action| action.to_string()).collect()
+                }
+                (None, Some(package_path)) => {
+                    reconstruct::reconstruct_plan(package_path, &actions, &options)?
+                }
+                (None, None) => unreachable!(),
+            };
+            for line in plan {
+                println!("{}", line);
+            }
+            return Ok(());
+        }
+        match (&args.root, package_path) {
+            (Some(root), _) => reconstruct::apply_actions_to_tree(root, &actions, &options)
+                .context("Failed to apply actions to the package tree")?,
+            (None, Some(package_path)) => {
+                reconstruct::apply_actions_for_reconstruct(package_path, &actions, &options)
+                    .context("Failed to apply actions for reconstruct")?
+            }
+            (None, None) => unreachable!(),
+        }
     } else {
-        unimplemented!("Direct installation mode not yet implemented");
+        bail!("Direct installation mode is not implemented yet, use --reconstruct");
+    }
+
+    Ok(())
+}
+
+fn plan(args: PlanArgs) -> Result<()> {
+    let package_version = reconstruct::read_package_version(&args.package_path)
+        .context("Failed to read package version")?;
+    let actions = plan_actions(
+        &args.april_config_path,
+        &package_version,
+        Some(Path::new(&args.package_path)),
+        args.target_arch.as_deref(),
+        args.rewrite_multiarch,
+    )?;
+    match args.format {
+        PlanFormat::Text => {
+            for action in &actions {
+                println!("{}", action);
+            }
+        }
+        PlanFormat::Json => {
+            let plan =
+                serde_json::to_string_pretty(&actions).context("Failed to serialize the plan")?;
+            println!("{}", plan);
+        }
+    }
+
+    Ok(())
+}
+
+fn resources(args: ResourcesArgs) -> Result<()> {
+    let package_version = reconstruct::read_package_version(&args.package_path)
+        .context("Failed to read package version")?;
+    let actions = plan_actions(
+        &args.april_config_path,
+        &package_version,
+        Some(Path::new(&args.package_path)),
+        None,
+        false,
+    )?;
+    for resource in external_resources(&actions).context("Failed to list the external resources")? {
+        println!(
+            "{} {}={}",
+            resource.url, resource.algorithm, resource.digest
+        );
+    }
+
+    Ok(())
+}
+
+/// Collect the problems of the configuration from a validation result
+fn collect_problems(problems: &mut Vec<String>, result: appam::Result<()>) {
+    match result {
+        Ok(()) => (),
+        Err(AprilError::InvalidConfig(report)) => problems.extend(report.0),
+        Err(e) => problems.push(e.to_string()),
+    }
+}
+
+fn check(args: CheckArgs) {
+    let april_data: Vec<april::AprilPackage> = match File::open(&args.april_config_path)
+        .map_err(|e| e.to_string())
+        .and_then(|file| serde_json::from_reader(file).map_err(|e| e.to_string()))
+    {
+        Ok(april_data) => april_data,
+        Err(e) => {
+            eprintln!("{}: {}", args.april_config_path, e);
+            std::process::exit(1);
+        }
+    };
+    let options = reconstruct::ReconstructOptions {
+        resource_bundle: args.resource_bundle,
+        resource_base_dir: args.resource_dir.or_else(|| {
+            Path::new(&args.april_config_path)
+                .parent()
+                .map(|dir| Path::new(".").join(dir))
+        }),
+        fetch: reconstruct::FetchConfig {
+            allow_insecure_http: args.allow_insecure_http,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let mut failed = false;
+    for (index, package) in april_data.iter().enumerate() {
+        let mut problems = Vec::new();
+        collect_problems(&mut problems, april::validate_april_data(package));
+        match april::plan_actions_from_april_data(package) {
+            Ok(actions) => {
+                collect_problems(&mut problems, check_action_resources(&actions, &options))
+            }
+            Err(e) => collect_problems(&mut problems, Err(e)),
+        }
+        for problem in &problems {
+            eprintln!(
+                "{}: configuration #{}: {}",
+                args.april_config_path,
+                index + 1,
+                problem
+            );
+        }
+        failed |= !problems.is_empty();
+    }
+    if failed {
+        std::process::exit(1);
+    }
+    println!(
+        "{}: {} configurations checked, no problems found",
+        args.april_config_path,
+        april_data.len()
+    );
+}
+
+fn run(args: Args) -> Result<()> {
+    let level = match args.verbose {
+        0 => "warn",
+        1 => "info",
+        _ => "debug",
+    };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level)).init();
+
+    match args.command {
+        Command::Apply(args) => apply(args)?,
+        Command::Check(args) => check(args),
+        Command::Plan(args) => plan(args)?,
+        Command::Resources(args) => resources(args)?,
+        Command::ShowField(args) => {
+            let value = reconstruct::read_control_field(&args.package_path, &args.field)
+                .context("Failed to read control field")?;
+            match value {
+                Some(value) => println!("{}", value),
+                None => {
+                    eprintln!("{}: field {} is not set", args.package_path, args.field);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Schema(_) => {
+            let schema = serde_json::to_string_pretty(&april::april_json_schema())
+                .context("Failed to serialize APRIL schema")?;
+            println!("{}", schema);
+        }
+        Command::ExplainVersion(args) => {
+            let explanation = explain_version_expr(&args.expr, args.version.as_deref())
+                .context("Failed to explain the version expression")?;
+            print!("{}", explanation);
+        }
+    }
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Args = argh::from_env();
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            // `{:#}` prints the whole chain of causes on one line
+            eprintln!("error: {:#}", e);
+            ExitCode::FAILURE
+        }
     }
 }
@@ -1,22 +1,322 @@
-use anyhow::{Result, anyhow};
 use base64::Engine;
 use deb822_lossless::{Deb822, Paragraph};
-use sha2::Digest;
+use serde::Serialize;
+use sha2::{Digest as _, digest::DynDigest};
 use std::{
     borrow::Cow,
-    io::Write,
-    path::{Path, PathBuf},
+    cell::OnceCell,
+    cmp::Ordering,
+    ffi::{OsStr, OsString},
+    fmt::Display,
+    fs::{File, Permissions},
+    io::{Read, Seek, Write},
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Component, Path, PathBuf},
     process::Command,
+    time::{Duration, Instant},
 };
-use tempfile::Builder;
+use tempfile::{Builder, NamedTempFile};
 use url::Url;
 
-use crate::april::{AprilAction, AprilActionType, AprilFileOperationType};
+use crate::{
+    april::{
+        AprilAction, AprilActionType, AprilFieldActionType, AprilFileOperationType,
+        ValidationReport,
+    },
+    april_version::compare_versions,
+    error::{AprilError, Result},
+    relationship::Relation,
+    signature::{SignatureVerifier, package_has_signature},
+};
+
+/// Default umask applied to files and directories created by file operations
+pub const DEFAULT_UMASK: u32 = 0o022;
+
+/// Default size limit of downloaded resources (512 MiB)
+pub const DEFAULT_MAX_DOWNLOAD_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Default time limit of the external programs applying patches (`patch`, `xdelta3`)
+pub const DEFAULT_TOOL_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Options controlling how external resources are fetched
+pub struct FetchConfig {
+    /// timeout for connecting to the server and for each phase of receiving the response
+    pub timeout: Duration,
+    /// how many times to retry after a transient failure (server errors, connection failures)
+    pub retries: u32,
+    /// delay before the first retry, doubled after each retry
+    pub retry_backoff: Duration,
+    /// directory for caching downloaded resources by their digests, `None` disables caching
+    pub cache_dir: Option<PathBuf>,
+    /// downloads larger than this size (in bytes) are aborted
+    pub max_download_size: u64,
+    /// allow fetching resources over plain `http`, otherwise only `https` is accepted
+    pub allow_insecure_http: bool,
+    /// proxy to fetch resources through, `None` uses the proxy environment variables, see `resolve_proxy`
+    pub proxy: Option<String>,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(30),
+            retries: 3,
+            retry_backoff: Duration::from_secs(1),
+            cache_dir: None,
+            max_download_size: DEFAULT_MAX_DOWNLOAD_SIZE,
+            allow_insecure_http: false,
+            proxy: None,
+        }
+    }
+}
+
+/// The default resource cache directory: `$XDG_CACHE_HOME/april` (or `~/.cache/april`)
+pub fn default_cache_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .map(|dir| dir.join("april"))
+}
+
+/// Options controlling how the package gets reconstructed
+pub struct ReconstructOptions {
+    /// verify the original signature of the package before reconstruction, abort if the verification fails
+    pub signature_verifier: Option<Box<dyn SignatureVerifier>>,
+    /// umask applied to newly created files and directories (instead of the process umask)
+    pub umask: u32,
+    /// refuse to produce the reconstructed package if it is larger than this size (in bytes)
+    pub max_output_size: Option<u64>,
+    /// tar or zip archive to read `bundle:` resources from
+    pub resource_bundle: Option<PathBuf>,
+    /// directory local resources are resolved against, they can not escape from this directory
+    pub resource_base_dir: Option<PathBuf>,
+    /// shell command to run after a successful reconstruction, see `run_post_reconstruct_hook` for the placeholders
+    pub post_reconstruct_hook: Option<String>,
+    /// how external resources are fetched (timeouts, retries, caching, size limit and proxy)
+    pub fetch: FetchConfig,
+    /// path of the reconstructed package (default: `<name>.repacked.deb` next to the original package)
+    pub output: Option<PathBuf>,
+    /// do not touch any file or the network, see `reconstruct_plan` for what would be done
+    pub dry_run: bool,
+    /// apply unified diffs with the built-in patch applier instead of the external `patch` command
+    pub builtin_patch: bool,
+    /// keep the directory the package is extracted to (for inspecting the reconstruction)
+    pub keep_temp: bool,
+    /// directory to extract the package in, see `extraction_parent_dir` for the default
+    pub temp_dir: Option<PathBuf>,
+    /// compressor for repacking the package (`gzip`, `xz`, `zstd` or `none`), `None` uses the `dpkg-deb` default
+    pub compression: Option<String>,
+    /// compression level for repacking the package, `None` uses the `dpkg-deb` default
+    pub compression_level: Option<u32>,
+    /// make the reconstructed package byte-for-byte reproducible, see `source_date_epoch` for the timestamp used
+    pub reproducible: bool,
+    /// how many file operations can be applied concurrently, see `schedule_file_actions`
+    pub jobs: usize,
+    /// keep applying the remaining actions after an action fails, all the failures are reported at the end
+    pub continue_on_error: bool,
+    /// the SHA256 digest the input package must have, checked before the package is extracted
+    pub expected_sha256: Option<String>,
+    /// write a JSON manifest of the applied changes to this path, see `ChangeManifest`
+    pub manifest: Option<PathBuf>,
+    /// refuse to apply file operations modifying conffiles of the original package, instead of only
+    /// warning about them
+    pub deny_conffile_edits: bool,
+    /// the external programs applying patches are killed if they run longer than this
+    pub tool_timeout: Duration,
+    /// append to package scripts even if they already contain the content (e.g. the package was already reconstructed)
+    pub force_append: bool,
+    /// re-read the control data and package scripts after applying the actions, and fail if any
+    /// field or script action does not have its intended effect (e.g. it is undone by a later action)
+    pub verify: bool,
+}
+
+impl Default for ReconstructOptions {
+    fn default() -> Self {
+        Self {
+            signature_verifier: None,
+            umask: DEFAULT_UMASK,
+            max_output_size: None,
+            resource_bundle: None,
+            resource_base_dir: None,
+            post_reconstruct_hook: None,
+            fetch: FetchConfig::default(),
+            output: None,
+            dry_run: false,
+            builtin_patch: false,
+            keep_temp: false,
+            temp_dir: None,
+            compression: None,
+            compression_level: None,
+            reproducible: false,
+            jobs: 1,
+            continue_on_error: false,
+            expected_sha256: None,
+            manifest: None,
+            deny_conffile_edits: false,
+            tool_timeout: DEFAULT_TOOL_TIMEOUT,
+            force_append: false,
+            verify: false,
+        }
+    }
+}
+
+/// Hash algorithms that can be used to pin a resource
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HashAlgorithm {
+    Sha256,
+    Sha512,
+    Sha1,
+    Blake2b,
+}
+
+impl HashAlgorithm {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sha256" => Some(HashAlgorithm::Sha256),
+            "sha512" => Some(HashAlgorithm::Sha512),
+            "sha1" => Some(HashAlgorithm::Sha1),
+            "blake2b" => Some(HashAlgorithm::Blake2b),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512 => "sha512",
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Blake2b => "blake2b",
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "SHA256",
+            HashAlgorithm::Sha512 => "SHA512",
+            HashAlgorithm::Sha1 => "SHA1",
+            HashAlgorithm::Blake2b => "BLAKE2b",
+        }
+    }
+
+    /// Length of the hex-encoded digest
+    fn hex_len(&self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 => 64,
+            HashAlgorithm::Sha512 | HashAlgorithm::Blake2b => 128,
+            HashAlgorithm::Sha1 => 40,
+        }
+    }
+
+    fn hasher(&self) -> Box<dyn DynDigest> {
+        match self {
+            HashAlgorithm::Sha256 => Box::new(sha2::Sha256::new()),
+            HashAlgorithm::Sha512 => Box::new(sha2::Sha512::new()),
+            HashAlgorithm::Sha1 => Box::new(sha1::Sha1::new()),
+            HashAlgorithm::Blake2b => Box::new(blake2::Blake2b512::new()),
+        }
+    }
+
+    fn hash(&self, content: &[u8]) -> String {
+        let mut hasher = self.hasher();
+        hasher.update(content);
+        hex::encode(hasher.finalize())
+    }
+}
+
+/// The digest a resource is pinned to, `hex` is the hex-encoded digest as written in the resource URI
+#[derive(Debug, Clone, PartialEq)]
+struct Digest {
+    algorithm: HashAlgorithm,
+    hex: String,
+}
+
+/// Compression format of a resource, specified with the `compression=` resource URI option
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Compression {
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "gzip" => Some(Compression::Gzip),
+            "xz" => Some(Compression::Xz),
+            "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    fn decoder<'a>(&self, reader: Box<dyn Read + 'a>) -> Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Compression::Gzip => Box::new(flate2::read::GzDecoder::new(reader)),
+            Compression::Xz => Box::new(xz2::read::XzDecoder::new(reader)),
+            Compression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        })
+    }
+}
+
+/// Format of a binary patch, specified with the `format=` resource URI option
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinaryPatchFormat {
+    /// VCDIFF deltas applied with the external `xdelta3` program (the default)
+    Xdelta3,
+    /// `BSDIFF40` patches (as produced by `bsdiff`) applied with the built-in implementation
+    Bsdiff,
+}
+
+impl BinaryPatchFormat {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "xdelta3" => Some(BinaryPatchFormat::Xdelta3),
+            "bsdiff" => Some(BinaryPatchFormat::Bsdiff),
+            _ => None,
+        }
+    }
+}
+
+/// Content of a fetched resource, downloaded resources are kept in a temporary file instead of in memory
+#[derive(Debug)]
+enum ResourceContent {
+    Memory(Vec<u8>),
+    File(NamedTempFile),
+    Cached(PathBuf),
+    /// compressed content, which is decompressed while reading
+    Compressed(Box<ResourceContent>, Compression),
+}
+
+impl ResourceContent {
+    fn reader(&self) -> Result<Box<dyn Read + '_>> {
+        Ok(match self {
+            ResourceContent::Memory(content) => Box::new(content.as_slice()),
+            ResourceContent::File(file) => Box::new(file.reopen()?),
+            ResourceContent::Cached(path) => Box::new(File::open(path)?),
+            ResourceContent::Compressed(content, compression) => {
+                compression.decoder(content.reader()?)?
+            }
+        })
+    }
+}
 
 #[derive(Debug, PartialEq)]
 enum AprilResourceType {
-    Inline { content: Vec<u8> },
-    External { url: String, sha256: String },
+    Inline {
+        content: Vec<u8>,
+    },
+    External {
+        url: String,
+        digest: Digest,
+    },
+    Bundled {
+        entry: String,
+        digest: Digest,
+    },
+    Local {
+        path: PathBuf,
+        digest: Option<Digest>,
+    },
 }
 
 fn remove_item_from_string_list(list: &str, item: &str) -> String {
@@ -28,19 +328,106 @@ fn remove_item_from_string_list(list: &str, item: &str) -> String {
     new_list.join(", ")
 }
 
+/// How a relation in a list relates to the relation merged into the list
+#[derive(Debug, PartialEq)]
+enum RelationMerge {
+    /// on another package, or constraining the version in the other direction
+    Unrelated,
+    /// at least as strict as the merged relation, which is not needed then
+    Stricter,
+    /// replaced by the merged relation
+    Replaced,
+}
+
+/// Compare the existing relation with the merged one, only relations on the same package (without
+/// alternatives) constraining the version in the same direction are merged. An exact (`=`)
+/// constraint replaces any other constraint, and an unversioned relation is implied by any versioned one.
+fn compare_relations(existing: &str, merged: &Relation) -> RelationMerge {
+    let Some(existing) = (!existing.contains('|'))
+        .then(|| Relation::parse(existing).ok())
+        .flatten()
+        .filter(|existing| {
+            existing.name == merged.name && existing.arch_qualifier == merged.arch_qualifier
+        })
+    else {
+        return RelationMerge::Unrelated;
+    };
+    let (existing_op, existing_version) = match &existing.constraint {
+        Some(constraint) => constraint,
+        None => return RelationMerge::Replaced,
+    };
+    let Some((op, version)) = &merged.constraint else {
+        return RelationMerge::Stricter;
+    };
+    if existing_op == "=" || op == "=" {
+        return RelationMerge::Replaced;
+    }
+    let lower = |op: &str| op.starts_with('>');
+    if lower(existing_op) != lower(op) {
+        return RelationMerge::Unrelated;
+    }
+    // e.g. `>= 2.0` is stricter than `>= 1.0`, and `>> 1.0` is stricter than `>= 1.0`
+    let strict = |op: &str| op == ">>" || op == "<<";
+    let stricter = match compare_versions(existing_version, version) {
+        Ok(Ordering::Equal) => strict(existing_op) || !strict(op),
+        Ok(ordering) => (ordering == Ordering::Greater) == lower(op),
+        Err(_) => false,
+    };
+    if stricter {
+        RelationMerge::Stricter
+    } else {
+        RelationMerge::Replaced
+    }
+}
+
+/// Append the relation to a relationship list, merging it with the existing relations on the same
+/// package (see `compare_relations`): looser relations are replaced by it, and it is left out if an
+/// existing relation is already at least as strict. Relations with alternatives are just appended.
+fn merge_relation_into_list(list: &str, relation: &str) -> String {
+    let merged_relation = (!relation.contains('|'))
+        .then(|| Relation::parse(relation).ok())
+        .flatten();
+    let mut new_list = Vec::new();
+    let mut merged = false;
+    for entry in list.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        let comparison = merged_relation
+            .as_ref()
+            .map_or(RelationMerge::Unrelated, |merged| {
+                compare_relations(entry, merged)
+            });
+        match comparison {
+            RelationMerge::Unrelated => new_list.push(entry),
+            RelationMerge::Stricter => {
+                new_list.push(entry);
+                merged = true;
+            }
+            RelationMerge::Replaced if !merged => {
+                new_list.push(relation);
+                merged = true;
+            }
+            RelationMerge::Replaced => (),
+        }
+    }
+    if !merged {
+        new_list.push(relation);
+    }
+    new_list.join(", ")
+}
+
 fn apply_field_patch(action: &AprilAction, paragraph: &mut Paragraph) {
     match action {
         AprilAction::PatchField {
             field,
             value,
             action,
+            ..
         } => {
             let field_value = paragraph.get(field).unwrap_or_default();
             match action {
-                AprilActionType::Remove => {
+                AprilFieldActionType::Remove => {
                     paragraph.set(field, &remove_item_from_string_list(&field_value, value))
                 }
-                AprilActionType::Append => {
+                AprilFieldActionType::Append => {
                     if field_value.is_empty() {
                         paragraph.set(field, value);
                     } else {
@@ -48,7 +435,18 @@ fn apply_field_patch(action: &AprilAction, paragraph: &mut Paragraph) {
                         paragraph.set(field, &new_value);
                     }
                 }
-                AprilActionType::Replace => {
+                AprilFieldActionType::Prepend => {
+                    if field_value.is_empty() {
+                        paragraph.set(field, value);
+                    } else {
+                        let new_value = format!("{}, {}", value, field_value);
+                        paragraph.set(field, &new_value);
+                    }
+                }
+                AprilFieldActionType::Merge => {
+                    paragraph.set(field, &merge_relation_into_list(&field_value, value))
+                }
+                AprilFieldActionType::Replace => {
                     if value.is_empty() {
                         paragraph.remove(field);
                     } else {
@@ -61,21 +459,485 @@ fn apply_field_patch(action: &AprilAction, paragraph: &mut Paragraph) {
     }
 }
 
+/// Fields holding comma-separated package relationships
+const RELATIONSHIP_FIELDS: &[&str] = &[
+    "Pre-Depends",
+    "Depends",
+    "Recommends",
+    "Suggests",
+    "Enhances",
+    "Breaks",
+    "Conflicts",
+    "Replaces",
+    "Provides",
+];
+
+/// Normalize a comma-separated list: trim the entries, drop the empty ones and the exact duplicates
+/// (keeping the first occurrence), then join them with `, `
+fn normalize_string_list(list: &str) -> String {
+    let mut entries: Vec<&str> = Vec::new();
+    for entry in list.split(',').map(|s| s.trim()) {
+        if !entry.is_empty() && !entries.contains(&entry) {
+            entries.push(entry);
+        }
+    }
+    entries.join(", ")
+}
+
+/// Normalize the relationship fields patched by the actions, so that repeated appends and removals
+/// do not leave duplicated entries or stray separators behind
+fn normalize_patched_relationship_fields(
+    actions: &[AprilAction],
+    control_data: &mut Deb822,
+) -> Result<()> {
+    for action in actions {
+        let AprilAction::PatchField { field, package, .. } = action else {
+            continue;
+        };
+        if !RELATIONSHIP_FIELDS
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(field))
+        {
+            continue;
+        }
+        let mut paragraph = select_control_paragraph(control_data, package.as_deref())?;
+        let Some(value) = paragraph.get(field) else {
+            continue;
+        };
+        let normalized = normalize_string_list(&value);
+        if normalized.is_empty() {
+            paragraph.remove(field);
+        } else if normalized != value {
+            paragraph.set(field, &normalized);
+        }
+    }
+
+    Ok(())
+}
+
+/// Find the control paragraph of the named package, or the first paragraph if no name is given
+/// (which is created if all the control data has been dropped)
+fn select_control_paragraph(control_data: &mut Deb822, package: Option<&str>) -> Result<Paragraph> {
+    let Some(package) = package else {
+        return Ok(match control_data.paragraphs().next() {
+            Some(paragraph) => paragraph,
+            None => control_data.add_paragraph(),
+        });
+    };
+
+    control_data
+        .paragraphs()
+        .find(|paragraph| paragraph.get("Package").as_deref() == Some(package))
+        .ok_or_else(|| {
+            AprilError::InvalidControlData(format!("No control paragraph for package {}", package))
+        })
+}
+
+/// Paths in APRIL configurations are relative to the package root, even if they start with `/`
+fn join_root(root: &Path, path: &str) -> PathBuf {
+    root.join(path.trim_start_matches('/'))
+}
+
+/// Resolve the path inside `root` following all the symlinks, the resolved path must stay inside `root`.
+/// Both sides are canonicalized, so a symlink in `root` itself does not defeat the check.
 fn resolve_path<'a, P: AsRef<Path>>(root: P, path: &'a str) -> Result<PathBuf> {
-    let root_path = root.as_ref();
-    let file_path = root_path.join(path).canonicalize()?;
-    if !file_path.starts_with(root_path) {
-        return Err(anyhow!("Invalid file path: {}", path));
+    let root_path = root.as_ref().canonicalize()?;
+    let file_path = join_root(&root_path, path).canonicalize()?;
+    if !file_path.starts_with(&root_path) {
+        return Err(AprilError::PathEscape(path.to_string()));
+    }
+
+    Ok(file_path)
+}
+
+/// Resolve the parent directory of the path like `resolve_path`, but keep the last component as is.
+/// Used for the operations acting on a symlink itself rather than on its target (remove, move).
+fn resolve_path_no_follow<P: AsRef<Path>>(root: P, path: &str) -> Result<PathBuf> {
+    let root_path = root.as_ref().canonicalize()?;
+    let joined_path = join_root(&root_path, path);
+    let (Some(parent), Some(file_name)) = (joined_path.parent(), joined_path.file_name()) else {
+        return Err(AprilError::PathEscape(path.to_string()));
+    };
+    let file_path = parent.canonicalize()?.join(file_name);
+    if !file_path.starts_with(&root_path) || file_path == root_path {
+        return Err(AprilError::PathEscape(path.to_string()));
     }
+    // fail like `resolve_path` if the file does not exist
+    file_path.symlink_metadata()?;
 
     Ok(file_path)
 }
 
-fn resolve_resource_uri(uri: &str) -> Result<AprilResourceType> {
+/// Like `resolve_path`, but the last component of the path does not need to exist
+fn resolve_path_allow_missing<P: AsRef<Path>>(root: P, path: &str) -> Result<PathBuf> {
+    let root_path = root.as_ref().canonicalize()?;
+    let joined_path = join_root(&root_path, path);
+    if joined_path.exists() {
+        return resolve_path(&root_path, path);
+    }
+    let (Some(parent), Some(file_name)) = (joined_path.parent(), joined_path.file_name()) else {
+        return Err(AprilError::PathEscape(path.to_string()));
+    };
+    let file_path = parent.canonicalize()?.join(file_name);
+    if !file_path.starts_with(&root_path) {
+        return Err(AprilError::PathEscape(path.to_string()));
+    }
+    // a dangling symlink is followed when the file is created, so its target must stay inside as well
+    let mut target = file_path.clone();
+    for _ in 0..MAX_SYMLINK_DEPTH {
+        if !target.is_symlink() {
+            return Ok(file_path);
+        }
+        let link_target = target
+            .parent()
+            .unwrap_or(&root_path)
+            .join(std::fs::read_link(&target)?);
+        let (Some(link_dir), Some(link_name)) = (link_target.parent(), link_target.file_name())
+        else {
+            return Err(AprilError::PathEscape(path.to_string()));
+        };
+        target = link_dir
+            .canonicalize()
+            .map_err(|_| AprilError::PathEscape(path.to_string()))?
+            .join(link_name);
+        if !target.starts_with(&root_path) {
+            return Err(AprilError::PathEscape(path.to_string()));
+        }
+    }
+
+    Err(AprilError::PathEscape(path.to_string()))
+}
+
+/// Same as the `ELOOP` limit of Linux
+const MAX_SYMLINK_DEPTH: usize = 40;
+
+fn is_glob_pattern(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+/// A token of a glob pattern component, each token except `Any` matches a single character
+#[derive(Debug, PartialEq)]
+enum GlobToken {
+    /// `*`
+    Any,
+    /// `?`
+    One,
+    /// `[...]`, `[!...]` or `[^...]`, with `a-z` style ranges
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+    /// any other character, `\` escapes the next character (e.g. `\[`)
+    Literal(char),
+}
+
+impl GlobToken {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            GlobToken::Any => true,
+            GlobToken::One => true,
+            GlobToken::Class { negated, ranges } => {
+                ranges
+                    .iter()
+                    .any(|(start, end)| (*start..=*end).contains(&c))
+                    != *negated
+            }
+            GlobToken::Literal(literal) => *literal == c,
+        }
+    }
+}
+
+fn parse_glob_component(pattern: &[char]) -> Vec<GlobToken> {
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < pattern.len() {
+        let token = match pattern[i] {
+            '\\' if i + 1 < pattern.len() => {
+                i += 1;
+                GlobToken::Literal(pattern[i])
+            }
+            // consecutive `*` match the same as a single one
+            '*' if tokens.last() == Some(&GlobToken::Any) => {
+                i += 1;
+                continue;
+            }
+            '*' => GlobToken::Any,
+            '?' => GlobToken::One,
+            '[' => {
+                // an unclosed `[` is matched literally, a `]` right after `[` is part of the class
+                let rest = &pattern[i + 1..];
+                let Some(end) = rest.iter().skip(1).position(|&c| c == ']').map(|i| i + 1) else {
+                    tokens.push(GlobToken::Literal('['));
+                    i += 1;
+                    continue;
+                };
+                let (negated, class) = match rest[..end].split_first() {
+                    Some(('!' | '^', class)) => (true, class),
+                    _ => (false, &rest[..end]),
+                };
+                let mut ranges = Vec::new();
+                let mut j = 0;
+                while j < class.len() {
+                    if j + 2 < class.len() && class[j + 1] == '-' {
+                        ranges.push((class[j], class[j + 2]));
+                        j += 3;
+                    } else {
+                        ranges.push((class[j], class[j]));
+                        j += 1;
+                    }
+                }
+                i += end + 1;
+                GlobToken::Class { negated, ranges }
+            }
+            c => GlobToken::Literal(c),
+        };
+        tokens.push(token);
+        i += 1;
+    }
+
+    tokens
+}
+
+/// The path component with the escapes removed, if it has no unescaped glob characters
+fn glob_literal(component: &str) -> Option<String> {
+    let mut literal = String::with_capacity(component.len());
+    let mut chars = component.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => literal.push(chars.next().unwrap_or('\\')),
+            '*' | '?' | '[' => return None,
+            c => literal.push(c),
+        }
+    }
+
+    Some(literal)
+}
+
+/// Match a single path component against a glob pattern component, a leading `.` is only
+/// matched explicitly like in the shell
+fn glob_match_component(pattern: &[char], name: &[char]) -> bool {
+    if name.first() == Some(&'.') && pattern.first() != Some(&'.') {
+        return false;
+    }
+    glob_match(&parse_glob_component(pattern), name)
+}
+
+/// Match the name against the tokens, when a later token does not match the name, only the
+/// last `*` is retried with one more character, so that the time is at most quadratic
+fn glob_match(tokens: &[GlobToken], name: &[char]) -> bool {
+    let (mut t, mut n) = (0, 0);
+    // the position after the last `*`, and the position in the name it is retried from
+    let mut retry = None;
+    while n < name.len() {
+        match tokens.get(t) {
+            Some(GlobToken::Any) => {
+                t += 1;
+                retry = Some((t, n));
+            }
+            Some(token) if token.matches(name[n]) => {
+                t += 1;
+                n += 1;
+            }
+            _ => {
+                let Some((retry_t, retry_n)) = retry else {
+                    return false;
+                };
+                t = retry_t;
+                n = retry_n + 1;
+                retry = Some((retry_t, n));
+            }
+        }
+    }
+
+    tokens[t..].iter().all(|token| *token == GlobToken::Any)
+}
+
+/// Expand the glob pattern against the files inside `root`, the matches are sorted.
+/// Each match still has to pass the containment check when it is resolved.
+fn expand_glob_pattern(root: &Path, pattern: &str) -> Result<Vec<String>> {
+    let mut matches = vec![String::new()];
+    for component in pattern.split('/').filter(|c| !c.is_empty() && *c != ".") {
+        if component == ".." {
+            return Err(AprilError::PathEscape(pattern.to_string()));
+        }
+        let mut expanded = Vec::new();
+        for parent in matches {
+            if let Some(component) = glob_literal(component) {
+                let path = format!("{}/{}", parent, component);
+                if join_root(root, &path).symlink_metadata().is_ok() {
+                    expanded.push(path);
+                }
+                continue;
+            }
+            let Ok(entries) = std::fs::read_dir(join_root(root, &parent)) else {
+                continue;
+            };
+            let component = component.chars().collect::<Vec<_>>();
+            for entry in entries {
+                let name = entry?.file_name();
+                let Some(name) = name.to_str() else {
+                    continue;
+                };
+                if glob_match_component(&component, &name.chars().collect::<Vec<_>>()) {
+                    expanded.push(format!("{}/{}", parent, name));
+                }
+            }
+        }
+        matches = expanded;
+    }
+    matches.sort();
+
+    Ok(matches)
+}
+
+fn is_mime_token(token: &str) -> bool {
+    !token.is_empty()
+        && token
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"!#$&-^_.+".contains(&c))
+}
+
+/// Decode the payload of a `data:` URI (RFC 2397), `data` is the part after `data:`
+fn decode_data_uri(url: &str, data: &str) -> Result<Vec<u8>> {
+    let invalid = |reason: &str| {
+        AprilError::InvalidResourceUri(format!("Invalid data URI ({}): {}", reason, url))
+    };
+    let (header, payload) = data.split_once(',').ok_or_else(|| invalid("missing ','"))?;
+    let mut params = header.split(';').peekable();
+    // the media type may be omitted, e.g. `data:;base64,...`, or even `data:base64,...`
+    let media_type = match params.peek() {
+        Some(&"base64") => "",
+        _ => params.next().unwrap_or_default(),
+    };
+    if !media_type.is_empty()
+        && !media_type
+            .split_once('/')
+            .is_some_and(|(kind, subtype)| is_mime_token(kind) && is_mime_token(subtype))
+    {
+        return Err(invalid("malformed media type"));
+    }
+    let mut is_base64 = false;
+    for param in params {
+        if param == "base64" {
+            is_base64 = true;
+        } else if is_base64
+            || !param
+                .split_once('=')
+                .is_some_and(|(key, _)| is_mime_token(key))
+        {
+            // `base64` can only be the last parameter
+            return Err(invalid("malformed parameter"));
+        }
+    }
+
+    if is_base64 {
+        // hand-edited payloads may be wrapped into lines, or use the URL-safe alphabet
+        let payload = payload
+            .bytes()
+            .filter(|c| !c.is_ascii_whitespace())
+            .map(|c| match c {
+                b'-' => b'+',
+                b'_' => b'/',
+                c => c,
+            })
+            .collect::<Vec<_>>();
+        // base64url payloads are usually unpadded
+        let engine = base64::engine::GeneralPurpose::new(
+            &base64::alphabet::STANDARD,
+            base64::engine::GeneralPurposeConfig::new()
+                .with_decode_padding_mode(base64::engine::DecodePaddingMode::Indifferent),
+        );
+        return engine
+            .decode(payload)
+            .map_err(|e| invalid(&format!("bad base64 payload: {}", e)));
+    }
+    let bytes = payload.as_bytes();
+    for (i, _) in payload.match_indices('%') {
+        if !bytes
+            .get(i + 1..i + 3)
+            .is_some_and(|hex| hex.iter().all(|c| c.is_ascii_hexdigit()))
+        {
+            return Err(invalid("bad percent-encoding in payload"));
+        }
+    }
+
+    Ok(percent_encoding::percent_decode(bytes).collect())
+}
+
+fn resolve_resource_uri(uri: &str) -> Result<(AprilResourceType, Option<Compression>)> {
+    let (resource, options) = parse_resource_uri(uri)?;
+
+    Ok((resource, options.compression))
+}
+
+/// The format of the binary patch referenced by the resource URI
+fn binary_patch_format(uri: &str) -> Result<BinaryPatchFormat> {
+    let (_, options) = parse_resource_uri(uri)?;
+
+    Ok(options.patch_format.unwrap_or(BinaryPatchFormat::Xdelta3))
+}
+
+/// Arguments of the `patch` invocation applying the patch referenced by the resource URI, the
+/// `fuzz=` option only takes numbers and `patch` is not run through a shell
+fn patch_args(uri: &str) -> Result<Vec<String>> {
+    let (_, options) = parse_resource_uri(uri)?;
+    let mut args = vec!["-Nt".to_string(), "-r-".to_string()];
+    if let Some(fuzz) = options.fuzz {
+        args.push(format!("-F{}", fuzz));
+    }
+
+    Ok(args)
+}
+
+/// Reject the resource URI options which have no effect on the file operation: `fuzz=` is only
+/// passed to the external `patch` program
+fn check_resource_uri_options(
+    operation: &AprilFileOperationType,
+    options: &ReconstructOptions,
+) -> Result<()> {
+    let uri = match operation {
+        AprilFileOperationType::Patch(uri)
+        | AprilFileOperationType::BinaryPatch(uri)
+        | AprilFileOperationType::Overwrite(uri)
+        | AprilFileOperationType::Add(uri)
+        | AprilFileOperationType::AppendContent(uri) => uri,
+        _ => return Ok(()),
+    };
+    let (_, uri_options) = parse_resource_uri(uri)?;
+    if uri_options.fuzz.is_none() {
+        return Ok(());
+    }
+    if !matches!(operation, AprilFileOperationType::Patch(_)) {
+        return Err(AprilError::InvalidResourceUri(format!(
+            "The fuzz option only applies to patch operations: {}",
+            uri
+        )));
+    }
+    if options.builtin_patch {
+        return Err(AprilError::InvalidResourceUri(format!(
+            "The fuzz option is not supported by the builtin patch implementation: {}",
+            uri
+        )));
+    }
+
+    Ok(())
+}
+
+/// Options of a resource URI other than the digest
+#[derive(Debug, Default)]
+struct ResourceUriOptions {
+    compression: Option<Compression>,
+    patch_format: Option<BinaryPatchFormat>,
+    /// the maximum fuzz factor for applying the patch (`patch -F`)
+    fuzz: Option<u32>,
+}
+
+fn parse_resource_uri(uri: &str) -> Result<(AprilResourceType, ResourceUriOptions)> {
     let uri_parts = uri.splitn(3, "::").collect::<Vec<&str>>();
     let resource_type;
     let url;
-    let mut sha256sum = None;
+    let mut digest = None;
+    let mut uri_options = ResourceUriOptions::default();
     match uri_parts.len() {
         2 => {
             resource_type = uri_parts[0];
@@ -86,181 +948,1150 @@ fn resolve_resource_uri(uri: &str) -> Result<AprilResourceType> {
             url = uri_parts[2];
             let options = uri_parts[1];
             for option in options.split(';') {
-                if option.starts_with("sha256=") {
-                    sha256sum = Some(option.split('=').last().unwrap());
+                let (name, value) = option.split_once('=').ok_or_else(|| {
+                    AprilError::InvalidResourceUri(format!(
+                        "Invalid option in resource URI: {}",
+                        option
+                    ))
+                })?;
+                if name == "compression" {
+                    let format = Compression::from_name(value).ok_or_else(|| {
+                        AprilError::InvalidResourceUri(format!(
+                            "Unsupported compression in resource URI: {}",
+                            value
+                        ))
+                    })?;
+                    if uri_options.compression.replace(format).is_some() {
+                        return Err(AprilError::InvalidResourceUri(format!(
+                            "Multiple compression options in resource URI: {}",
+                            uri
+                        )));
+                    }
+                    continue;
+                }
+                if name == "format" {
+                    let format = BinaryPatchFormat::from_name(value).ok_or_else(|| {
+                        AprilError::InvalidResourceUri(format!(
+                            "Unsupported binary patch format in resource URI: {} (expected bsdiff or xdelta3)",
+                            value
+                        ))
+                    })?;
+                    if uri_options.patch_format.replace(format).is_some() {
+                        return Err(AprilError::InvalidResourceUri(format!(
+                            "Multiple format options in resource URI: {}",
+                            uri
+                        )));
+                    }
+                    continue;
+                }
+                if name == "fuzz" {
+                    let number = value
+                        .parse::<u32>()
+                        .ok()
+                        .filter(|_| value.chars().all(|c| c.is_ascii_digit()))
+                        .ok_or_else(|| {
+                            AprilError::InvalidResourceUri(format!(
+                                "Invalid fuzz option in resource URI (expected a number): {}",
+                                value
+                            ))
+                        })?;
+                    if uri_options.fuzz.replace(number).is_some() {
+                        return Err(AprilError::InvalidResourceUri(format!(
+                            "Multiple fuzz options in resource URI: {}",
+                            uri
+                        )));
+                    }
+                    continue;
+                }
+                let algorithm = HashAlgorithm::from_name(name).ok_or_else(|| {
+                    AprilError::InvalidResourceUri(format!(
+                        "Unsupported hash algorithm in resource URI: {}",
+                        name
+                    ))
+                })?;
+                // catch malformed digests before downloading anything
+                if value.len() != algorithm.hex_len()
+                    || !value.chars().all(|c| c.is_ascii_hexdigit())
+                {
+                    return Err(AprilError::InvalidResourceUri(format!(
+                        "Invalid {} digest in resource URI (expected {} hex digits): {}",
+                        algorithm.name(),
+                        algorithm.hex_len(),
+                        value
+                    )));
+                }
+                if digest
+                    .replace(Digest {
+                        algorithm,
+                        hex: value.to_string(),
+                    })
+                    .is_some()
+                {
+                    return Err(AprilError::InvalidResourceUri(format!(
+                        "Multiple digests in resource URI: {}",
+                        uri
+                    )));
                 }
             }
         }
         _ => {
-            return Err(anyhow!("Invalid resource URI: {}", uri));
+            return Err(AprilError::InvalidResourceUri(format!(
+                "Invalid resource URI: {}",
+                uri
+            )));
         }
     }
 
     if resource_type != "file" {
         // we only support file resources for now
-        return Err(anyhow!("Unsupported resource type: {}", resource_type));
+        return Err(AprilError::InvalidResourceUri(format!(
+            "Unsupported resource type: {}",
+            resource_type
+        )));
     }
     // parse url
-    let parsed_url = Url::parse(url)?;
+    let parsed_url = match Url::parse(url) {
+        Ok(parsed_url) => parsed_url,
+        Err(url::ParseError::RelativeUrlWithoutBase) => {
+            // bare local paths are relative to the resource base directory
+            let resource = AprilResourceType::Local {
+                path: PathBuf::from(url),
+                digest,
+            };
+            return Ok((resource, uri_options));
+        }
+        Err(e) => return Err(e.into()),
+    };
 
-    match parsed_url.scheme() {
+    let resource = match parsed_url.scheme() {
         "http" | "https" => {
-            let sha256sum = sha256sum
-                .ok_or_else(|| anyhow!("Missing or invalid SHA256 sum in resource URI: {}", url))?;
+            let digest = digest.ok_or_else(|| {
+                AprilError::InvalidResourceUri(format!("Missing digest in resource URI: {}", url))
+            })?;
 
-            Ok(AprilResourceType::External {
+            AprilResourceType::External {
                 url: url.to_string(),
-                sha256: sha256sum.to_string(),
-            })
+                digest,
+            }
         }
-        "data" => {
-            let data = parsed_url.path();
-            let payload_start = data
-                .find(',')
-                .ok_or_else(|| anyhow!("Invalid data URI: {}", url))?;
-            let is_base64 =
-                (payload_start > 6) && &data[payload_start - 6..payload_start] == "base64";
-            let payload = if is_base64 {
-                base64::engine::general_purpose::STANDARD
-                    .decode(data[payload_start + 1..].as_bytes())?
-            } else {
-                percent_encoding::percent_decode(data[payload_start + 1..].as_bytes()).collect()
-            };
+        "data" => AprilResourceType::Inline {
+            content: decode_data_uri(url, parsed_url.path())?,
+        },
+        "bundle" => {
+            let digest = digest.ok_or_else(|| {
+                AprilError::InvalidResourceUri(format!("Missing digest in resource URI: {}", url))
+            })?;
+            let entry = percent_encoding::percent_decode_str(parsed_url.path()).decode_utf8()?;
+
+            AprilResourceType::Bundled {
+                entry: entry.to_string(),
+                digest,
+            }
+        }
+        "file" => {
+            let path = parsed_url.to_file_path().map_err(|_| {
+                AprilError::InvalidResourceUri(format!("Invalid local file URI: {}", url))
+            })?;
 
-            Ok(AprilResourceType::Inline { content: payload })
+            AprilResourceType::Local { path, digest }
         }
         _ => {
-            return Err(anyhow!("Unsupported scheme in resource URI: {}", url));
+            return Err(AprilError::UnsupportedScheme(url.to_string()));
         }
+    };
+
+    Ok((resource, uri_options))
+}
+
+fn check_resource_digest(
+    name: &str,
+    content: &[u8],
+    algorithm: HashAlgorithm,
+    expected: &str,
+) -> Result<()> {
+    verify_digest(name, algorithm, expected, &algorithm.hash(content))
+}
+
+fn verify_digest(
+    name: &str,
+    algorithm: HashAlgorithm,
+    expected: &str,
+    calculated: &str,
+) -> Result<()> {
+    if !calculated.eq_ignore_ascii_case(expected) {
+        return Err(AprilError::ResourceDigestMismatch {
+            algorithm: algorithm.name(),
+            resource: name.to_string(),
+            expected: expected.to_string(),
+            actual: calculated.to_string(),
+        });
     }
+
+    Ok(())
 }
 
-fn fetch_resource_uri(uri: &str) -> Result<Vec<u8>> {
-    let resolved_uri = resolve_resource_uri(uri)?;
-    match resolved_uri {
-        AprilResourceType::External { url, sha256 } => {
-            let mut response = ureq::get(&url).call()?;
-            if response.status().is_success() {
-                let response_content = response.body_mut().read_to_vec()?;
-                let mut hasher = sha2::Sha256::new();
-                hasher.update(&response_content);
-                let calculated_sha256 = hasher.finalize();
-                if hex::encode(calculated_sha256) == sha256 {
-                    Ok(response_content)
-                } else {
-                    return Err(anyhow!(
-                        "SHA256 sum mismatch for resource: {}, expected {}, got {}",
-                        url,
-                        sha256,
-                        hex::encode(calculated_sha256)
-                    ));
-                }
-            } else {
-                return Err(anyhow!(
-                    "Failed to fetch resource: {} (HTTP {})",
-                    url,
-                    response.status()
-                ));
-            }
-        }
-        AprilResourceType::Inline { content } => {
-            // no need to fetch inline resources
-            Ok(content)
+/// Read the specified entry from a tar or zip archive
+fn read_bundle_entry(bundle: &Path, entry: &str) -> Result<Vec<u8>> {
+    let mut file = File::open(bundle)?;
+    let mut magic = [0u8; 4];
+    let is_zip = file.read(&mut magic)? == magic.len() && &magic == b"PK\x03\x04";
+    file.rewind()?;
+
+    let mut content = Vec::new();
+    if is_zip {
+        let mut archive = zip::ZipArchive::new(file)?;
+        archive.by_name(entry)?.read_to_end(&mut content)?;
+        return Ok(content);
+    }
+
+    let mut archive = tar::Archive::new(file);
+    for archive_entry in archive.entries()? {
+        let mut archive_entry = archive_entry?;
+        let path = archive_entry.path()?.into_owned();
+        if path.strip_prefix(".").unwrap_or(&path) == Path::new(entry) {
+            archive_entry.read_to_end(&mut content)?;
+            return Ok(content);
         }
     }
+
+    Err(AprilError::ResourceUnavailable(format!(
+        "Resource {} not found in bundle {}",
+        entry,
+        bundle.display()
+    )))
 }
 
-fn apply_file_operation<P: AsRef<Path>>(
-    root: P,
-    path: &str,
-    action: &AprilFileOperationType,
-) -> Result<()> {
-    let file_path = resolve_path(&root, path)?;
+fn hash_reader(reader: &mut dyn Read, algorithm: HashAlgorithm) -> std::io::Result<String> {
+    let mut hasher = algorithm.hasher();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
 
-    match action {
-        AprilFileOperationType::Remove => Ok(std::fs::remove_file(&file_path)?),
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Copy the content into a temporary file while hashing it, returns the file and the hex digest.
+/// Fails with `ErrorKind::FileTooLarge` once more than `limit` bytes are read.
+fn stream_to_temp_file(
+    reader: &mut dyn Read,
+    algorithm: HashAlgorithm,
+    limit: u64,
+) -> std::io::Result<(NamedTempFile, String)> {
+    let mut file = NamedTempFile::new()?;
+    let mut hasher = algorithm.hasher();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut total = 0u64;
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        total += n as u64;
+        if total > limit {
+            return Err(std::io::ErrorKind::FileTooLarge.into());
+        }
+        hasher.update(&buffer[..n]);
+        file.write_all(&buffer[..n])?;
+    }
+    file.flush()?;
+
+    Ok((file, hex::encode(hasher.finalize())))
+}
+
+/// Whether the host matches an entry of `no_proxy`: `*` matches every host, other entries match
+/// the domain and its subdomains (a leading `.` or `*.` is ignored)
+fn is_no_proxy_host(host: &str, entry: &str) -> bool {
+    if entry == "*" {
+        return true;
+    }
+    let domain = entry
+        .trim_start_matches('*')
+        .trim_start_matches('.')
+        .to_ascii_lowercase();
+    let host = host.to_ascii_lowercase();
+
+    !domain.is_empty() && (host == domain || host.ends_with(&format!(".{}", domain)))
+}
+
+/// The proxy to fetch `url` through: the configured proxy, otherwise the `https_proxy` or `http_proxy`
+/// (by the scheme of the URL) or `all_proxy` environment variable, `env` looks up an environment
+/// variable by name. Both the lower and upper case variable names are accepted, the lower case ones take
+/// precedence like in curl. No proxy is used if the host is listed in `no_proxy`.
+fn resolve_proxy(
+    url: &Url,
+    configured: Option<&str>,
+    env: impl Fn(&str) -> Option<String>,
+) -> Option<String> {
+    let lookup = |name: &str| {
+        env(name)
+            .or_else(|| env(&name.to_ascii_uppercase()))
+            .filter(|value| !value.is_empty())
+    };
+    let proxy = configured
+        .map(|proxy| proxy.to_string())
+        .or_else(|| lookup(&format!("{}_proxy", url.scheme())))
+        .or_else(|| lookup("all_proxy"))?;
+    let host = url.host_str().unwrap_or_default();
+    let bypassed = lookup("no_proxy").is_some_and(|no_proxy| {
+        no_proxy
+            .split(',')
+            .any(|entry| is_no_proxy_host(host, entry.trim()))
+    });
+
+    (!bypassed).then_some(proxy)
+}
+
+/// Download the resource into a temporary file, retrying with exponential backoff on transient failures
+fn download_resource(
+    url: &str,
+    config: &FetchConfig,
+    algorithm: HashAlgorithm,
+) -> Result<(NamedTempFile, String)> {
+    let proxy = resolve_proxy(&Url::parse(url)?, config.proxy.as_deref(), |name| {
+        std::env::var(name).ok()
+    })
+    .map(|proxy| {
+        ureq::Proxy::new(&proxy)
+            .map_err(|_| AprilError::InvalidOption(format!("Invalid proxy URL: {}", proxy)))
+    })
+    .transpose()?;
+    if let Some(proxy) = &proxy {
+        log::debug!(
+            "Fetching {} through proxy {}:{}",
+            url,
+            proxy.host(),
+            proxy.port()
+        );
+    }
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        // the proxy is resolved above, `None` also stops ureq from reading the environment again
+        .proxy(proxy)
+        .http_status_as_error(false)
+        .timeout_connect(Some(config.timeout))
+        .timeout_recv_response(Some(config.timeout))
+        .timeout_recv_body(Some(config.timeout))
+        // also refuses redirects from `https` to `http`
+        .https_only(!config.allow_insecure_http)
+        .build()
+        .into();
+    let mut backoff = config.retry_backoff;
+    let mut attempt = 0;
+    loop {
+        let error = match agent.get(url).call() {
+            Ok(mut response) if response.status().is_success() => {
+                let too_large = || AprilError::ResourceTooLarge {
+                    url: url.to_string(),
+                    limit: config.max_download_size,
+                };
+                // reject early if the server tells us the size
+                if response
+                    .body()
+                    .content_length()
+                    .is_some_and(|length| length > config.max_download_size)
+                {
+                    return Err(too_large());
+                }
+                match stream_to_temp_file(
+                    &mut response.body_mut().as_reader(),
+                    algorithm,
+                    config.max_download_size,
+                ) {
+                    Ok(downloaded) => return Ok(downloaded),
+                    Err(e) if e.kind() == std::io::ErrorKind::FileTooLarge => {
+                        return Err(too_large());
+                    }
+                    Err(e) => e.to_string(),
+                }
+            }
+            Ok(response) if response.status().is_server_error() => {
+                format!("HTTP {}", response.status())
+            }
+            Ok(response) => {
+                return Err(AprilError::FetchFailed {
+                    url: url.to_string(),
+                    reason: format!("HTTP {}", response.status()),
+                });
+            }
+            Err(
+                e @ (ureq::Error::Io(_)
+                | ureq::Error::Timeout(_)
+                | ureq::Error::HostNotFound
+                | ureq::Error::ConnectionFailed),
+            ) => e.to_string(),
+            Err(e) => return Err(e.into()),
+        };
+        if attempt >= config.retries {
+            return Err(AprilError::FetchFailed {
+                url: url.to_string(),
+                reason: format!("after {} attempts: {}", attempt + 1, error),
+            });
+        }
+        attempt += 1;
+        std::thread::sleep(backoff);
+        backoff *= 2;
+    }
+}
+
+/// Use the cached resource only if its content still matches the digest
+fn lookup_cached_resource(cache_path: &Path, digest: &Digest) -> Option<ResourceContent> {
+    let mut file = File::open(cache_path).ok()?;
+    let calculated = hash_reader(&mut file, digest.algorithm).ok()?;
+    if !calculated.eq_ignore_ascii_case(&digest.hex) {
+        // the cached blob is corrupted, download it again
+        std::fs::remove_file(cache_path).ok();
+        return None;
+    }
+
+    Some(ResourceContent::Cached(cache_path.to_path_buf()))
+}
+
+fn store_cached_resource(cache_path: &Path, file: &NamedTempFile) -> Result<()> {
+    let cache_dir = cache_path.parent().ok_or_else(|| {
+        std::io::Error::other(format!(
+            "Invalid resource cache path: {}",
+            cache_path.display()
+        ))
+    })?;
+    std::fs::create_dir_all(cache_dir).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!(
+                "Failed to create the resource cache directory {}: {}",
+                cache_dir.display(),
+                e
+            ),
+        )
+    })?;
+    // write to a temporary file first so that an interrupted write never leaves a partial blob
+    let mut cached = Builder::new().tempfile_in(cache_dir)?;
+    std::io::copy(&mut file.reopen()?, &mut cached)?;
+    cached.persist(cache_path).map_err(|e| e.error)?;
+
+    Ok(())
+}
+
+/// Fetch the resource and verify its digest, compressed resources are verified as transferred
+/// and decompressed while reading
+fn fetch_resource_uri(uri: &str, options: &ReconstructOptions) -> Result<ResourceContent> {
+    log::debug!("Fetching resource {}", uri);
+    let (resolved_uri, compression) = resolve_resource_uri(uri)?;
+    let content = fetch_resolved_resource(resolved_uri, options)?;
+
+    Ok(match compression {
+        Some(compression) => ResourceContent::Compressed(Box::new(content), compression),
+        None => content,
+    })
+}
+
+/// Make sure the external resource may be fetched from the URL
+fn check_external_url(url: &str, config: &FetchConfig) -> Result<()> {
+    // URL schemes are case-insensitive
+    let insecure = url
+        .split_once(':')
+        .is_some_and(|(scheme, _)| scheme.eq_ignore_ascii_case("http"));
+    if !config.allow_insecure_http && insecure {
+        return Err(AprilError::InsecureResourceUri(url.to_string()));
+    }
+
+    Ok(())
+}
+
+fn fetch_external_resource(
+    url: &str,
+    digest: &Digest,
+    config: &FetchConfig,
+) -> Result<ResourceContent> {
+    check_external_url(url, config)?;
+    // the digest becomes a file name, so it has to be a plain hex string
+    let cache_path = config
+        .cache_dir
+        .as_ref()
+        .filter(|_| digest.hex.chars().all(|c| c.is_ascii_hexdigit()))
+        .map(|dir| {
+            dir.join(digest.algorithm.as_str())
+                .join(digest.hex.to_ascii_lowercase())
+        });
+    if let Some(cache_path) = &cache_path
+        && let Some(cached) = lookup_cached_resource(cache_path, digest)
+    {
+        log::debug!("Using cached resource {}", cache_path.display());
+        return Ok(cached);
+    }
+    log::info!("Downloading {}", url);
+    let (file, calculated) = download_resource(url, config, digest.algorithm)?;
+    verify_digest(url, digest.algorithm, &digest.hex, &calculated)?;
+    if let Some(cache_path) = &cache_path
+        && let Err(e) = store_cached_resource(cache_path, &file)
+    {
+        log::warn!("Failed to cache resource {}: {}", url, e);
+    }
+
+    Ok(ResourceContent::File(file))
+}
+
+/// The resource URI used by the action, if any
+fn action_resource_uri(action: &AprilAction) -> Option<&str> {
+    match action {
+        AprilAction::PatchFile {
+            action:
+                AprilFileOperationType::Patch(uri)
+                | AprilFileOperationType::BinaryPatch(uri)
+                | AprilFileOperationType::Overwrite(uri)
+                | AprilFileOperationType::Add(uri)
+                | AprilFileOperationType::AppendContent(uri),
+            ..
+        } => Some(uri),
+        _ => None,
+    }
+}
+
+/// Check that all the resources used by the actions can be resolved, local and bundled resources
+/// are read and their digests verified, but external resources are not fetched.
+/// All the problems are collected into a `ValidationReport`.
+pub fn check_action_resources(actions: &[AprilAction], options: &ReconstructOptions) -> Result<()> {
+    let mut errors = Vec::new();
+    for action in actions {
+        let Some(uri) = action_resource_uri(action) else {
+            continue;
+        };
+        if let AprilAction::PatchFile {
+            action: operation, ..
+        } = action
+            && let Err(e) = check_resource_uri_options(operation, options)
+        {
+            errors.push(format!("Invalid resource for '{}': {}", action, e));
+            continue;
+        }
+        let result = resolve_resource_uri(uri).and_then(|(resolved_uri, _)| match resolved_uri {
+            AprilResourceType::External { url, .. } => check_external_url(&url, &options.fetch),
+            resolved_uri => fetch_resolved_resource(resolved_uri, options).map(drop),
+        });
+        if let Err(e) = result {
+            errors.push(format!("Invalid resource for '{}': {}", action, e));
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(ValidationReport(errors).into());
+    }
+
+    Ok(())
+}
+
+/// Number of external resources downloaded concurrently by `prefetch_resources`
+const PREFETCH_WORKERS: usize = 4;
+
+/// An external resource fetched by the actions, with the digest it is pinned to
+#[derive(Debug, PartialEq)]
+pub struct ExternalResource {
+    pub url: String,
+    /// name of the digest algorithm as written in resource URIs, e.g. `sha256`
+    pub algorithm: &'static str,
+    pub digest: String,
+}
+
+/// Collect the external resources, and the problems of the resource URIs that can't be resolved
+fn collect_external_resources(actions: &[AprilAction]) -> (Vec<(String, Digest)>, Vec<String>) {
+    let mut resources = Vec::new();
+    let mut errors = Vec::new();
+    for uri in actions.iter().filter_map(action_resource_uri) {
+        match resolve_resource_uri(uri) {
+            Ok((AprilResourceType::External { url, digest }, _))
+                if !resources.contains(&(url.clone(), digest.clone())) =>
+            {
+                resources.push((url, digest));
+            }
+            Ok(_) => {}
+            Err(e) => errors.push(format!("Invalid resource URI '{}': {}", uri, e)),
+        }
+    }
+
+    (resources, errors)
+}
+
+/// List the external resources fetched by the actions (in the order they are used, without
+/// duplicates), e.g. for mirroring them before reconstructing packages offline
+pub fn external_resources(actions: &[AprilAction]) -> Result<Vec<ExternalResource>> {
+    let (resources, errors) = collect_external_resources(actions);
+    if !errors.is_empty() {
+        return Err(ValidationReport(errors).into());
+    }
+
+    Ok(resources
+        .into_iter()
+        .map(|(url, digest)| ExternalResource {
+            url,
+            algorithm: digest.algorithm.as_str(),
+            digest: digest.hex,
+        })
+        .collect())
+}
+
+/// Download all the external resources referenced by the actions into the resource cache
+/// concurrently, so that applying the actions in order does not wait for the network.
+/// Failures (including invalid resource URIs) are ignored here, they are reported when the
+/// failing resource is actually used. Nothing is prefetched if the cache is disabled.
+fn prefetch_resources(actions: &[AprilAction], config: &FetchConfig) {
+    if config.cache_dir.is_none() {
+        return;
+    }
+    let (resources, errors) = collect_external_resources(actions);
+    for error in errors {
+        log::debug!("Not prefetching: {}", error);
+    }
+
+    let queue = std::sync::Mutex::new(resources.into_iter());
+    std::thread::scope(|scope| {
+        for _ in 0..PREFETCH_WORKERS {
+            scope.spawn(|| {
+                loop {
+                    let Some((url, digest)) = queue.lock().unwrap().next() else {
+                        break;
+                    };
+                    if let Err(e) = fetch_external_resource(&url, &digest, config) {
+                        log::debug!("Failed to prefetch {}: {}", url, e);
+                    }
+                }
+            });
+        }
+    });
+}
+
+fn fetch_resolved_resource(
+    resolved_uri: AprilResourceType,
+    options: &ReconstructOptions,
+) -> Result<ResourceContent> {
+    match resolved_uri {
+        AprilResourceType::External { url, digest } => {
+            fetch_external_resource(&url, &digest, &options.fetch)
+        }
+        AprilResourceType::Bundled { entry, digest } => {
+            let bundle = options.resource_bundle.as_ref().ok_or_else(|| {
+                AprilError::ResourceUnavailable(format!(
+                    "No resource bundle specified for resource: {}",
+                    entry
+                ))
+            })?;
+            let content = read_bundle_entry(bundle, &entry)?;
+            check_resource_digest(&entry, &content, digest.algorithm, &digest.hex)?;
+            Ok(ResourceContent::Memory(content))
+        }
+        AprilResourceType::Local { path, digest } => {
+            let base_dir = options.resource_base_dir.as_ref().ok_or_else(|| {
+                AprilError::ResourceUnavailable(format!(
+                    "No resource directory specified for resource: {}",
+                    path.display()
+                ))
+            })?;
+            let base_dir = base_dir.canonicalize()?;
+            let file_path = base_dir.join(&path).canonicalize()?;
+            if !file_path.starts_with(&base_dir) {
+                return Err(AprilError::ResourceUnavailable(format!(
+                    "Local resource is outside of the resource directory: {}",
+                    path.display()
+                )));
+            }
+            let content = std::fs::read(&file_path)?;
+            if let Some(digest) = digest {
+                check_resource_digest(
+                    &path.to_string_lossy(),
+                    &content,
+                    digest.algorithm,
+                    &digest.hex,
+                )?;
+            }
+            Ok(ResourceContent::Memory(content))
+        }
+        AprilResourceType::Inline { content } => {
+            // no need to fetch inline resources
+            Ok(ResourceContent::Memory(content))
+        }
+    }
+}
+
+/// Create a new file with the specified content, the file mode is determined by `umask` only
+fn create_file(path: &Path, content: &mut dyn Read, umask: u32) -> Result<()> {
+    let mut f = std::fs::OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .open(path)?;
+    std::io::copy(content, &mut f)?;
+    f.set_permissions(Permissions::from_mode(0o666 & !umask))?;
+
+    Ok(())
+}
+
+/// Copy the file with its mode, and also its ownership if permitted.
+/// `dpkg-deb` records the owner of the files on disk, so without privileges the copy is owned by
+/// the current user just like the rest of the extracted package.
+fn copy_file_preserving(src: &Path, dst: &Path) -> Result<()> {
+    let metadata = std::fs::metadata(src)?;
+    std::fs::copy(src, dst)?;
+    preserve_mode_and_owner(dst, &metadata)
+}
+
+fn preserve_mode_and_owner(dst: &Path, metadata: &std::fs::Metadata) -> Result<()> {
+    std::fs::set_permissions(dst, Permissions::from_mode(metadata.mode()))?;
+    match std::os::unix::fs::chown(dst, Some(metadata.uid()), Some(metadata.gid())) {
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => (),
+        result => result?,
+    }
+
+    Ok(())
+}
+
+/// Copy the directory tree like `copy_file_preserving`, symlinks are copied as symlinks
+fn copy_dir_preserving(src: &Path, dst: &Path) -> Result<()> {
+    let metadata = std::fs::metadata(src)?;
+    std::fs::create_dir(dst)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let dst_entry = dst.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            std::os::unix::fs::symlink(std::fs::read_link(entry.path())?, &dst_entry)?;
+        } else if file_type.is_dir() {
+            copy_dir_preserving(&entry.path(), &dst_entry)?;
+        } else {
+            copy_file_preserving(&entry.path(), &dst_entry)?;
+        }
+    }
+    // the mode is set last, the directory may not be writable
+    preserve_mode_and_owner(dst, &metadata)
+}
+
+/// Create a directory and all of its missing parents, the directory modes are determined by `umask` only
+fn create_dir_all(path: &Path, umask: u32) -> Result<()> {
+    let missing = path
+        .ancestors()
+        .take_while(|dir| !dir.exists())
+        .collect::<Vec<_>>();
+    for dir in missing.into_iter().rev() {
+        match std::fs::create_dir(dir) {
+            // created by a concurrent file operation
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && dir.is_dir() => continue,
+            result => result?,
+        }
+        std::fs::set_permissions(dir, Permissions::from_mode(0o777 & !umask))?;
+    }
+
+    Ok(())
+}
+
+/// Create the missing parent directories of `path` inside `root`, then resolve it like `resolve_path_allow_missing`
+fn resolve_path_create_parent<P: AsRef<Path>>(root: P, path: &str, umask: u32) -> Result<PathBuf> {
+    let root_path = root.as_ref().canonicalize()?;
+    let joined_path = join_root(&root_path, path);
+    let parent = joined_path
+        .parent()
+        .ok_or_else(|| AprilError::PathEscape(path.to_string()))?;
+    // make sure nothing gets created outside of root
+    let existing = parent
+        .ancestors()
+        .find(|dir| dir.exists())
+        .ok_or_else(|| AprilError::PathEscape(path.to_string()))?;
+    let missing = parent
+        .strip_prefix(existing)
+        .map_err(|_| AprilError::PathEscape(path.to_string()))?;
+    if !existing.canonicalize()?.starts_with(&root_path)
+        || missing
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_)))
+    {
+        return Err(AprilError::PathEscape(path.to_string()));
+    }
+    create_dir_all(parent, umask)?;
+
+    resolve_path_allow_missing(&root_path, path)
+}
+
+#[cfg(test)]
+fn apply_file_operation<P: AsRef<Path>>(
+    root: P,
+    path: &str,
+    action: &AprilFileOperationType,
+    options: &ReconstructOptions,
+) -> Result<()> {
+    apply_file_operation_with(root, path, action, options, &OnceCell::new())
+}
+
+/// Fetch the resource of a file operation on its first use, later uses (e.g. on the other files
+/// matched by a glob pattern) get the same content
+fn fetch_resource_once<'a>(
+    resource: &'a OnceCell<ResourceContent>,
+    uri: &str,
+    options: &ReconstructOptions,
+) -> Result<&'a ResourceContent> {
+    if let Some(content) = resource.get() {
+        return Ok(content);
+    }
+    let content = fetch_resource_uri(uri, options)?;
+
+    Ok(resource.get_or_init(|| content))
+}
+
+/// Apply the file operation, `resource` holds the content of the resource once it is fetched
+fn apply_file_operation_with<P: AsRef<Path>>(
+    root: P,
+    path: &str,
+    action: &AprilFileOperationType,
+    options: &ReconstructOptions,
+    resource: &OnceCell<ResourceContent>,
+) -> Result<()> {
+    let file_path = match action {
+        // these operations create the file, so it does not need to exist yet
+        AprilFileOperationType::Add(_)
+        | AprilFileOperationType::Overwrite(_)
+        | AprilFileOperationType::AppendContent(_)
+        | AprilFileOperationType::Mkdir => resolve_path_create_parent(&root, path, options.umask)?,
+        // these operations act on a symlink itself, not on its target
+        AprilFileOperationType::Remove | AprilFileOperationType::Move(_) => {
+            resolve_path_no_follow(&root, path)?
+        }
+        _ => resolve_path(&root, path)?,
+    };
+    check_resource_uri_options(action, options)?;
+
+    match action {
+        AprilFileOperationType::Remove if file_path.symlink_metadata()?.is_dir() => {
+            Ok(std::fs::remove_dir_all(&file_path)?)
+        }
+        AprilFileOperationType::Remove => Ok(std::fs::remove_file(&file_path)?),
         AprilFileOperationType::Move(dst) => {
-            let dst_path = resolve_path(&root, dst)?;
+            let dst_path = resolve_path_create_parent(&root, dst, options.umask)?;
             std::fs::rename(&file_path, &dst_path)?;
             Ok(())
         }
         AprilFileOperationType::Copy(dst) => {
-            let dst_path = resolve_path(&root, dst)?;
-            std::fs::copy(&file_path, &dst_path)?;
-            Ok(())
+            let dst_path = resolve_path_create_parent(&root, dst, options.umask)?;
+            if !file_path.is_dir() {
+                return copy_file_preserving(&file_path, &dst_path);
+            }
+            if dst_path.starts_with(&file_path) {
+                return Err(AprilError::InvalidAction(format!(
+                    "Can not copy directory {} into itself",
+                    path
+                )));
+            }
+            copy_dir_preserving(&file_path, &dst_path)
         }
         AprilFileOperationType::Link(dst) => {
-            let dst_path = resolve_path(&root, dst)?;
-            std::os::unix::fs::symlink(&file_path, &dst_path)?;
+            // `dst` becomes a symlink pointing to `path` as seen from the installed system
+            let dst_path = resolve_path_create_parent(&root, dst, options.umask)?;
+            let target = Path::new("/").join(path.trim_start_matches('/'));
+            std::os::unix::fs::symlink(&target, &dst_path)?;
             Ok(())
         }
         AprilFileOperationType::Patch(url) => {
-            let content = fetch_resource_uri(url)?;
-            let mut command = Command::new("patch")
-                .args(&["-Nt", "-r-"])
-                .arg(&file_path)
-                .stdin(std::process::Stdio::piped())
-                .spawn()?;
-            command.stdin.take().unwrap().write_all(&content)?;
-            let status = command.wait()?;
-
-            if !status.success() {
-                Err(anyhow!("Failed to apply patch: {}", status))
-            } else {
-                Ok(())
+            let content = fetch_resource_once(resource, url, options)?;
+            if options.builtin_patch {
+                return apply_unified_diff(&file_path, path, &mut content.reader()?);
             }
+            let mut command = Command::new("patch");
+            command.args(patch_args(url)?).arg(&file_path);
+            run_tool(command, "apply patch", content, options.tool_timeout)
         }
         AprilFileOperationType::BinaryPatch(url) => {
-            let content = fetch_resource_uri(url)?;
-            let mut command = Command::new("xdelta3")
-                .args(&["-d", "-f", "-s"])
+            let format = binary_patch_format(url)?;
+            let content = fetch_resource_once(resource, url, options)?;
+            if format == BinaryPatchFormat::Bsdiff {
+                let mut patch = Vec::new();
+                content.reader()?.read_to_end(&mut patch)?;
+                let patched =
+                    apply_bsdiff(&std::fs::read(&file_path)?, &patch).map_err(|reason| {
+                        AprilError::PatchRejected {
+                            path: path.to_string(),
+                            reason,
+                        }
+                    })?;
+                // written in place, so that the mode and ownership of the file are kept
+                std::fs::write(&file_path, patched)?;
+                return Ok(());
+            }
+            let mut command = Command::new("xdelta3");
+            command
+                .args(["-d", "-f", "-s"])
                 .arg(&file_path)
                 .arg("/dev/stdin")
-                .arg(&file_path.clone())
-                .stdin(std::process::Stdio::piped())
-                .spawn()?;
-            command.stdin.take().unwrap().write_all(&content)?;
-            let status = command.wait()?;
-
-            if !status.success() {
-                Err(anyhow!("Failed to apply binary patch: {}", status))
-            } else {
-                Ok(())
-            }
+                .arg(&file_path);
+            run_tool(command, "apply binary patch", content, options.tool_timeout)
         }
         AprilFileOperationType::Divert(dst) => todo!(),
         AprilFileOperationType::Track => todo!(),
         AprilFileOperationType::Overwrite(url) => {
-            let content = fetch_resource_uri(url)?;
-            std::fs::write(&file_path, &content)?;
+            let content = fetch_resource_once(resource, url, options)?;
+            if file_path.exists() {
+                std::io::copy(&mut content.reader()?, &mut File::create(&file_path)?)?;
+            } else {
+                create_file(&file_path, &mut content.reader()?, options.umask)?;
+            }
             Ok(())
         }
         AprilFileOperationType::Add(url) => {
-            let content = fetch_resource_uri(url)?;
-            let mut f = std::fs::OpenOptions::new()
-                .create_new(true)
-                .write(true)
-                .open(&file_path)?;
-            f.write_all(&content)?;
+            let content = fetch_resource_once(resource, url, options)?;
+            create_file(&file_path, &mut content.reader()?, options.umask)
+        }
+        AprilFileOperationType::AppendContent(url) => {
+            let content = fetch_resource_once(resource, url, options)?;
+            if !file_path.exists() {
+                return create_file(&file_path, &mut content.reader()?, options.umask);
+            }
+            let mut file = std::fs::OpenOptions::new().append(true).open(&file_path)?;
+            std::io::copy(&mut content.reader()?, &mut file)?;
             Ok(())
         }
-        AprilFileOperationType::Chmod(mode) => {
-            let result = unsafe {
-                libc::chmod(
-                    file_path.as_os_str().as_encoded_bytes().as_ptr() as *const libc::c_char,
-                    *mode as libc::mode_t,
-                )
-            };
+        // the file is truncated in place, so its inode (and mode and ownership) stays the same
+        AprilFileOperationType::Truncate => Ok(std::fs::OpenOptions::new()
+            .write(true)
+            .open(&file_path)?
+            .set_len(0)?),
+        AprilFileOperationType::Chmod(mode) => Ok(std::fs::set_permissions(
+            &file_path,
+            Permissions::from_mode(*mode as u32),
+        )?),
+        // `dpkg-deb -b` takes the ownership from the files, so changing it on disk is enough
+        AprilFileOperationType::Chown { uid, gid } => {
+            match std::os::unix::fs::chown(&file_path, Some(*uid), Some(*gid)) {
+                Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                    Err(AprilError::ChownNotPermitted {
+                        path: path.to_string(),
+                        uid: *uid,
+                        gid: *gid,
+                    })
+                }
+                result => Ok(result?),
+            }
+        }
+        AprilFileOperationType::Mkdir => create_dir_all(&file_path, options.umask),
+        AprilFileOperationType::Substitute {
+            pattern,
+            replacement,
+            regex,
+        } => substitute_file_content(&file_path, path, pattern, replacement, *regex),
+    }
+}
 
-            if result != 0 {
-                let err = std::io::Error::last_os_error();
-                Err(err.into())
-            } else {
-                Ok(())
+/// Read an integer of a `BSDIFF40` patch, stored in sign-magnitude form in little-endian byte order
+fn read_bsdiff_offset(reader: &mut dyn Read) -> std::io::Result<i64> {
+    let mut buffer = [0u8; 8];
+    reader.read_exact(&mut buffer)?;
+    let negative = buffer[7] & 0x80 != 0;
+    buffer[7] &= 0x7f;
+    let magnitude = i64::from_le_bytes(buffer);
+
+    Ok(if negative { -magnitude } else { magnitude })
+}
+
+/// Apply a `BSDIFF40` patch to `old`, returns the new content or why the patch is rejected.
+/// The patch consists of a header, and bzip2-compressed control, diff and extra blocks: each
+/// control entry adds the next `x` bytes of the diff block to the old content, copies the next
+/// `y` bytes of the extra block, then seeks the old content by `z` bytes.
+fn apply_bsdiff(old: &[u8], patch: &[u8]) -> std::result::Result<Vec<u8>, String> {
+    let header = patch
+        .get(..32)
+        .filter(|header| header.starts_with(b"BSDIFF40"))
+        .ok_or("not a BSDIFF40 patch")?;
+    let mut lengths = &header[8..];
+    let mut read_length = || {
+        read_bsdiff_offset(&mut lengths)
+            .ok()
+            .and_then(|length| usize::try_from(length).ok())
+            .ok_or("corrupted patch header")
+    };
+    let (control_len, diff_len, new_len) = (read_length()?, read_length()?, read_length()?);
+    let diff_start = control_len
+        .checked_add(32)
+        .ok_or("corrupted patch header")?;
+    let extra_start = diff_start
+        .checked_add(diff_len)
+        .filter(|start| *start <= patch.len())
+        .ok_or("truncated patch")?;
+    let mut control = bzip2::read::BzDecoder::new(&patch[32..diff_start]);
+    let mut diff = bzip2::read::BzDecoder::new(&patch[diff_start..extra_start]);
+    let mut extra = bzip2::read::BzDecoder::new(&patch[extra_start..]);
+
+    let corrupted = |e: std::io::Error| format!("corrupted patch: {}", e);
+    // the new length comes from the patch, so the buffer only grows with the data actually read
+    let mut new = Vec::with_capacity(new_len.min(old.len().saturating_add(patch.len())));
+    let read_block = |block: &mut dyn Read, len: usize, new: &mut Vec<u8>| {
+        let read = block.take(len as u64).read_to_end(new).map_err(corrupted)?;
+        if read < len {
+            return Err("corrupted patch: unexpected end of block".to_string());
+        }
+        Ok(())
+    };
+    let (mut old_pos, mut new_pos) = (0i64, 0usize);
+    while new_pos < new_len {
+        let diff_copy = read_bsdiff_offset(&mut control).map_err(corrupted)?;
+        let extra_copy = read_bsdiff_offset(&mut control).map_err(corrupted)?;
+        let seek = read_bsdiff_offset(&mut control).map_err(corrupted)?;
+        let in_bounds = |len: i64, new_pos: usize| {
+            usize::try_from(len)
+                .ok()
+                .filter(|len| new_pos + len <= new_len)
+                .ok_or("corrupted patch: control entry out of bounds")
+        };
+        let diff_copy = in_bounds(diff_copy, new_pos)?;
+        read_block(&mut diff, diff_copy, &mut new)?;
+        // the old position comes from the patch, so the block must not overflow it
+        let old_end = i64::try_from(diff_copy)
+            .ok()
+            .and_then(|len| old_pos.checked_add(len))
+            .ok_or("corrupted patch: control entry out of bounds")?;
+        for (pos, byte) in (old_pos..old_end).zip(&mut new[new_pos..new_pos + diff_copy]) {
+            // the old content is treated as zeros outside of its bounds
+            if let Some(old_byte) = usize::try_from(pos).ok().and_then(|pos| old.get(pos)) {
+                *byte = byte.wrapping_add(*old_byte);
+            }
+        }
+        new_pos += diff_copy;
+        old_pos = old_end;
+
+        let extra_copy = in_bounds(extra_copy, new_pos)?;
+        read_block(&mut extra, extra_copy, &mut new)?;
+        new_pos += extra_copy;
+        old_pos = old_pos
+            .checked_add(seek)
+            .ok_or("corrupted patch: control entry out of bounds")?;
+    }
+
+    Ok(new)
+}
+
+/// Run the external program with the content on its standard input. The output is captured and
+/// included in the error if the program fails, and the program is killed after `timeout`.
+fn run_tool(
+    mut command: Command,
+    operation: &'static str,
+    input: &ResourceContent,
+    timeout: Duration,
+) -> Result<()> {
+    let mut child = command
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+    let mut stderr = child.stderr.take().unwrap();
+    let deadline = Instant::now() + timeout;
+    std::thread::scope(|scope| {
+        // the pipes are served by their own threads, so that a hung program can still be killed
+        let writer = scope.spawn(move || -> Result<()> {
+            std::io::copy(&mut input.reader()?, &mut stdin)?;
+            Ok(())
+        });
+        let stdout_reader = scope.spawn(move || {
+            let mut output = Vec::new();
+            stdout.read_to_end(&mut output).map(|_| output)
+        });
+        let stderr_reader = scope.spawn(move || {
+            let mut output = Vec::new();
+            stderr.read_to_end(&mut output).map(|_| output)
+        });
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break Some(status);
+            }
+            if Instant::now() >= deadline {
+                child.kill()?;
+                child.wait()?;
+                break None;
             }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        let written = writer.join().unwrap();
+        let mut output = String::from_utf8_lossy(&stdout_reader.join().unwrap()?).into_owned();
+        output.push_str(&String::from_utf8_lossy(&stderr_reader.join().unwrap()?));
+        let Some(status) = status else {
+            return Err(AprilError::ToolTimedOut { operation, timeout });
+        };
+        if !status.success() {
+            // the program may exit without reading all the input
+            return Err(AprilError::ToolFailed {
+                operation,
+                status,
+                output,
+            });
         }
-        AprilFileOperationType::Mkdir => Ok(std::fs::create_dir_all(&file_path)?),
+        written?;
+        log::debug!("{}", output.trim_end());
+
+        Ok(())
+    })
+}
+
+/// Replace all the matches of the pattern in the file, it is an error if nothing matches
+fn substitute_file_content(
+    file_path: &Path,
+    path: &str,
+    pattern: &str,
+    replacement: &str,
+    regex: bool,
+) -> Result<()> {
+    let matcher = if regex {
+        regex::bytes::Regex::new(pattern)
+    } else {
+        regex::bytes::Regex::new(&regex::escape(pattern))
+    }
+    .map_err(|e| AprilError::InvalidAction(format!("Invalid pattern '{}': {}", pattern, e)))?;
+    let content = std::fs::read(file_path)?;
+    if !matcher.is_match(&content) {
+        return Err(AprilError::SubstitutionNoMatch {
+            path: path.to_string(),
+            pattern: pattern.to_string(),
+        });
+    }
+    let substituted = if regex {
+        matcher.replace_all(&content, replacement.as_bytes())
+    } else {
+        matcher.replace_all(&content, regex::bytes::NoExpand(replacement.as_bytes()))
+    };
+    std::fs::write(file_path, substituted)?;
+
+    Ok(())
+}
+
+/// Apply a unified diff in memory, the file is only written back if all the hunks apply
+fn apply_unified_diff(file_path: &Path, path: &str, patch: &mut dyn Read) -> Result<()> {
+    let rejected = |reason: String| AprilError::PatchRejected {
+        path: path.to_string(),
+        reason,
+    };
+    let mut patch_content = Vec::new();
+    patch.read_to_end(&mut patch_content)?;
+    let patch = diffy::Patch::from_bytes(&patch_content).map_err(|e| rejected(e.to_string()))?;
+    let patched = diffy::apply_bytes(&std::fs::read(file_path)?, &patch)
+        .map_err(|e| rejected(e.to_string()))?;
+    std::fs::write(file_path, patched)?;
+
+    Ok(())
+}
+
+/// Insert the content before the body of the script, after the shebang line if there is one
+fn prepend_script_content(script: &str, content: &str) -> String {
+    let (shebang, body) = match script.split_once('\n') {
+        Some((shebang, body)) if shebang.starts_with("#!") => (Some(shebang), body),
+        None if script.starts_with("#!") => (Some(script), ""),
+        _ => (None, script),
+    };
+    let mut new_script = String::with_capacity(script.len() + content.len() + 2);
+    if let Some(shebang) = shebang {
+        new_script.push_str(shebang);
+        new_script.push('\n');
     }
+    new_script.push_str(content);
+    if !content.ends_with('\n') {
+        new_script.push('\n');
+    }
+    new_script.push_str(body);
+
+    new_script
 }
 
 fn apply_script_actions<P: AsRef<Path>>(
@@ -269,6 +2100,7 @@ fn apply_script_actions<P: AsRef<Path>>(
     content: &Option<String>,
     action: &AprilActionType,
     installed_name: &Option<String>,
+    force_append: bool,
 ) -> Result<()> {
     let filename = match installed_name {
         Some(installed_name) => {
@@ -278,12 +2110,44 @@ fn apply_script_actions<P: AsRef<Path>>(
         }
         None => Cow::Borrowed(file),
     };
-    let file_path = resolve_path(root.as_ref().join("DEBIAN"), &filename)?;
+    let control_dir = root.as_ref().join("DEBIAN");
+    let file_path = match action {
+        // prepending to a missing script creates it
+        AprilActionType::Prepend => resolve_path_allow_missing(control_dir, &filename)?,
+        _ => resolve_path(control_dir, &filename)?,
+    };
 
     match action {
         AprilActionType::Remove => Ok(std::fs::remove_file(&file_path)?),
+        AprilActionType::Prepend => {
+            if let Some(content) = content {
+                let (existing, created) = match std::fs::read_to_string(&file_path) {
+                    Ok(existing) => (existing, false),
+                    // dpkg only runs executable maintainer scripts with an interpreter line
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        ("#!/bin/sh\n".to_string(), true)
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                std::fs::write(&file_path, prepend_script_content(&existing, content))?;
+                if created {
+                    std::fs::set_permissions(&file_path, Permissions::from_mode(0o755))?;
+                }
+            }
+            Ok(())
+        }
         AprilActionType::Append => {
             if let Some(content) = content {
+                // applying the same configuration to an already reconstructed package
+                // should not duplicate the content
+                if !force_append && std::fs::read_to_string(&file_path)?.contains(content.as_str())
+                {
+                    log::warn!(
+                        "Package script {} already contains the appended content, skipping",
+                        file
+                    );
+                    return Ok(());
+                }
                 std::fs::OpenOptions::new()
                     .create(true)
                     .append(true)
@@ -296,160 +2160,4185 @@ fn apply_script_actions<P: AsRef<Path>>(
             if let Some(content) = content {
                 std::fs::write(&file_path, content.as_bytes())?;
             } else {
-                return Err(anyhow!("Missing content for replace action"));
+                return Err(AprilError::InvalidAction(
+                    "Missing content for replace action".to_string(),
+                ));
             }
             Ok(())
         }
     }
 }
 
-pub fn apply_actions_for_reconstruct<P: AsRef<Path>>(
-    deb_path: P,
-    actions: &[AprilAction],
-) -> Result<()> {
-    let deb_path = deb_path.as_ref();
-    let deb_path_dir = deb_path
-        .parent()
-        .ok_or_else(|| anyhow!("Invalid package path: {}", deb_path.display()))?;
-    let tmp_root = Builder::new().tempdir_in(deb_path_dir)?;
-    let status = Command::new("dpkg-deb")
-        .arg("-R")
-        .arg(deb_path)
-        .arg(tmp_root.path())
-        .spawn()?
-        .wait()?;
-    if !status.success() {
-        return Err(anyhow!("Failed to extract package: {}", status));
-    }
-
-    let control_file_path = tmp_root.path().join("DEBIAN/control");
-    let mut control_data = Deb822::from_file(&control_file_path)?;
-
-    for i in actions {
-        match i {
-            AprilAction::PreconfigPackage
-            | AprilAction::UnpackPackage
-            | AprilAction::ExtractPackage
-            | AprilAction::ConfigurePackage
-            | AprilAction::InstallPackage => (),
-            AprilAction::PatchField { .. } => {
-                for mut paragraph in &mut control_data.paragraphs() {
-                    apply_field_patch(&i, &mut paragraph);
-                }
-            }
-            AprilAction::DropControlData => control_data = Deb822::new(),
-            AprilAction::PutControlChunk { data } => {
-                (control_data, _) = Deb822::from_str_relaxed(data);
-            }
-            AprilAction::PatchScript {
-                file,
-                content,
-                action,
-            } => apply_script_actions(&tmp_root, file, content, action, &None)?,
-            AprilAction::PatchFile { path, action } => {
-                apply_file_operation(&tmp_root, path, action)?
-            }
-        }
+/// Read the version of the package using `dpkg-deb`
+pub fn read_package_version<P: AsRef<Path>>(deb_path: P) -> Result<String> {
+    let output = Command::new("dpkg-deb")
+        .arg("-f")
+        .arg(deb_path.as_ref())
+        .arg("Version")
+        .output()?;
+    if !output.status.success() {
+        return Err(AprilError::DpkgCommandFailed {
+            operation: "read package version",
+            detail: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
     }
 
-    std::fs::write(control_file_path, control_data.to_string())?;
-    let new_deb_path = deb_path.with_extension(".repacked.deb");
-    let status = Command::new("dpkg-deb")
-        .arg("-b")
-        .arg(tmp_root.path())
-        .arg(new_deb_path)
-        .spawn()?
-        .wait()?;
-    if !status.success() {
-        return Err(anyhow!("Failed to repack package: {}", status));
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+/// Read the current value of a control field of the package, `None` if the field is not set
+pub fn read_control_field<P: AsRef<Path>>(deb_path: P, field: &str) -> Result<Option<String>> {
+    let output = Command::new("dpkg-deb")
+        .arg("-f")
+        .arg(deb_path.as_ref())
+        .arg(field)
+        .output()?;
+    if !output.status.success() {
+        return Err(AprilError::DpkgCommandFailed {
+            operation: "read control field",
+            detail: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
     }
+    // fields can not have empty values, so an empty output means the field is missing
+    let value = String::from_utf8(output.stdout)?.trim().to_string();
 
-    Ok(())
+    Ok((!value.is_empty()).then_some(value))
 }
 
-#[test]
-fn test_remove_item_from_string_list() {
-    let input = "foo, bar, baz";
-    let item = "bar";
-    let expected = "foo, baz";
-    assert_eq!(remove_item_from_string_list(input, item), expected);
+/// Make sure the input package is the exact build the caller expects
+fn check_package_digest(deb_path: &Path, options: &ReconstructOptions) -> Result<()> {
+    let Some(expected) = &options.expected_sha256 else {
+        return Ok(());
+    };
+    let calculated = hash_reader(&mut File::open(deb_path)?, HashAlgorithm::Sha256)?;
 
-    let input = "foo, bar (>= 1.2.0), baz";
-    let item = "bar";
-    let expected = "foo, baz";
-    assert_eq!(remove_item_from_string_list(input, item), expected);
+    verify_digest(
+        &deb_path.display().to_string(),
+        HashAlgorithm::Sha256,
+        expected,
+        &calculated,
+    )
 }
 
-#[test]
+fn check_package_signature(deb_path: &Path, options: &ReconstructOptions) -> Result<()> {
+    if let Some(verifier) = &options.signature_verifier {
+        verifier.verify(deb_path)?;
+        log::warn!(
+            "Signature of {} verified, but the reconstructed package will be unsigned",
+            deb_path.display()
+        );
+    } else if package_has_signature(deb_path)? {
+        log::warn!(
+            "{} is signed, but the signature is not verified and will be discarded",
+            deb_path.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// `foo_1.0_amd64.deb` is repacked as `foo_1.0_amd64.repacked.deb` in the same directory
+fn repacked_package_path(deb_path: &Path) -> PathBuf {
+    let file_name = deb_path.file_name().unwrap_or_default().to_string_lossy();
+    let stem = file_name.strip_suffix(".deb").unwrap_or(&file_name);
+
+    deb_path.with_file_name(format!("{}.repacked.deb", stem))
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Run the hook command with `sh -c`, `{output}`, `{name}` and `{version}` are replaced with
+/// the (shell-quoted) path, name and version of the reconstructed package
+fn run_post_reconstruct_hook(hook: &str, output: &Path, name: &str, version: &str) -> Result<()> {
+    let command = hook
+        .replace("{output}", &shell_quote(&output.to_string_lossy()))
+        .replace("{name}", &shell_quote(name))
+        .replace("{version}", &shell_quote(version));
+    let status = Command::new("sh").arg("-c").arg(&command).status()?;
+    if !status.success() {
+        return Err(AprilError::CommandFailed {
+            operation: "run post-reconstruct hook",
+            status,
+        });
+    }
+
+    Ok(())
+}
+
+fn action_failed(action: &AprilAction, error: AprilError) -> AprilError {
+    AprilError::ActionFailed {
+        action: action.to_string(),
+        source: Box::new(error),
+    }
+}
+
+/// The conffiles of the original package extracted to `root` (in `DEBIAN/conffiles`) which exist
+/// in the package, flags like `remove-on-upgrade` are stripped from the entries. The conffiles
+/// added by the conffiles override are not included, as dpkg has no older version of them to keep.
+fn original_conffiles(root: &Path) -> Result<Vec<String>> {
+    let list = match std::fs::read_to_string(root.join("DEBIAN/conffiles")) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        result => result?,
+    };
+
+    Ok(list
+        .lines()
+        .filter_map(|line| line.split_whitespace().last())
+        .filter(|conffile| join_root(root, conffile).symlink_metadata().is_ok())
+        .map(|conffile| conffile.to_string())
+        .collect())
+}
+
+/// Whether `pattern` (a path or a glob pattern) refers to `file` or to one of its parent directories
+fn path_pattern_covers(pattern: &str, file: &str) -> bool {
+    let components = |path: &'_ str| {
+        path.split('/')
+            .filter(|c| !c.is_empty() && *c != ".")
+            .map(|c| c.chars().collect::<Vec<_>>())
+            .collect::<Vec<_>>()
+    };
+    let (pattern, file) = (components(pattern), components(file));
+
+    pattern.len() <= file.len()
+        && pattern
+            .iter()
+            .zip(&file)
+            .all(|(pattern, name)| pattern == name || glob_match_component(pattern, name))
+}
+
+/// Check the file operations against the conffiles of the original package, modifying a conffile
+/// breaks the conffile handling of dpkg on upgrades. A warning is logged for each of the operations,
+/// and they are refused if `deny_conffile_edits` is set.
+fn check_conffile_edits(
+    root: &Path,
+    actions: &[AprilAction],
+    options: &ReconstructOptions,
+) -> Result<()> {
+    let conffiles = original_conffiles(root)?;
+    let mut edits = Vec::new();
+    for action in actions {
+        let AprilAction::PatchFile {
+            path,
+            action: operation,
+            ..
+        } = action
+        else {
+            continue;
+        };
+        let mut paths = vec![path.as_str()];
+        if let AprilFileOperationType::Move(dst)
+        | AprilFileOperationType::Copy(dst)
+        | AprilFileOperationType::Link(dst) = operation
+        {
+            paths.push(dst);
+        }
+        for conffile in &conffiles {
+            if paths.iter().any(|path| path_pattern_covers(path, conffile)) {
+                edits.push(format!("'{}' modifies conffile {}", action, conffile));
+            }
+        }
+    }
+    if edits.is_empty() {
+        return Ok(());
+    }
+    if options.deny_conffile_edits {
+        return Err(AprilError::ConffileEdits(edits));
+    }
+    for edit in edits {
+        log::warn!("{}", edit);
+    }
+
+    Ok(())
+}
+
+/// Paths touched by the file operation (inside the package root), `None` if the operation has to be
+/// applied alone: glob patterns may match anything, and new symlinks change what the paths refer to.
+/// Existing paths are also canonicalized, so that paths through symlinks are compared by their targets.
+fn file_action_paths(root: &Path, action: &AprilAction) -> Option<Vec<PathBuf>> {
+    let AprilAction::PatchFile { path, action, .. } = action else {
+        return None;
+    };
+    let mut paths = vec![path.as_str()];
+    match action {
+        AprilFileOperationType::Link(_) => return None,
+        AprilFileOperationType::Move(dst)
+        | AprilFileOperationType::Copy(dst)
+        | AprilFileOperationType::Divert(dst) => paths.push(dst),
+        _ => {}
+    }
+    if paths
+        .iter()
+        .any(|path| is_glob_pattern(path) || path.split('/').any(|c| c == ".."))
+    {
+        return None;
+    }
+
+    let mut touched = Vec::new();
+    for path in paths {
+        let joined_path = join_root(root, path);
+        touched.push(joined_path.clone());
+        // canonicalize the longest existing part of the path
+        if let Some(canonical) = joined_path.ancestors().find_map(|ancestor| {
+            let canonical = ancestor.canonicalize().ok()?;
+            Some(canonical.join(joined_path.strip_prefix(ancestor).ok()?))
+        }) {
+            touched.push(canonical);
+        }
+    }
+
+    Some(touched)
+}
+
+/// Assign the file operations to waves, the operations in the same wave touch no overlapping paths
+/// so they can be applied concurrently, and the waves are applied in order
+fn schedule_file_actions(root: &Path, actions: &[AprilAction]) -> Vec<usize> {
+    let touched = actions
+        .iter()
+        .map(|action| file_action_paths(root, action))
+        .collect::<Vec<_>>();
+    let overlaps = |a: &Option<Vec<PathBuf>>, b: &Option<Vec<PathBuf>>| match (a, b) {
+        (Some(a), Some(b)) => a
+            .iter()
+            .any(|a| b.iter().any(|b| a.starts_with(b) || b.starts_with(a))),
+        _ => true,
+    };
+    let mut waves: Vec<usize> = Vec::with_capacity(actions.len());
+    for (index, paths) in touched.iter().enumerate() {
+        let wave = (0..index)
+            .filter(|&earlier| overlaps(&touched[earlier], paths))
+            .map(|earlier| waves[earlier] + 1)
+            .max()
+            .unwrap_or(0);
+        waves.push(wave);
+    }
+
+    waves
+}
+
+/// Apply the file operations with `options.jobs` threads, the operations touching overlapping paths
+/// are still applied in order. The records of the applied actions and the errors are returned in the
+/// order of the actions. Unless `continue_on_error` is set, no more actions are started after an
+/// action fails.
+fn apply_file_actions_concurrently<'a>(
+    actions: &'a [AprilAction],
+    root: &Path,
+    options: &ReconstructOptions,
+) -> (Vec<ActionRecord<'a>>, Vec<AprilError>) {
+    let waves = schedule_file_actions(root, actions);
+    let wave_count = waves.iter().max().map_or(0, |wave| wave + 1);
+    let mut records = Vec::new();
+    let mut failures = Vec::new();
+    for wave in 0..wave_count {
+        let queue = std::sync::Mutex::new(
+            actions
+                .iter()
+                .zip(&waves)
+                .enumerate()
+                .filter(|(_, (_, action_wave))| **action_wave == wave)
+                .map(|(index, (action, _))| (index, action)),
+        );
+        let applied = std::sync::Mutex::new(Vec::new());
+        let errors = std::sync::Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            for _ in 0..options.jobs {
+                scope.spawn(|| {
+                    let mut control_data = Deb822::new();
+                    loop {
+                        if !options.continue_on_error && !errors.lock().unwrap().is_empty() {
+                            break;
+                        }
+                        let Some((index, action)) = queue.lock().unwrap().next() else {
+                            break;
+                        };
+                        log::debug!("Applying action: {}", action);
+                        let resource = OnceCell::new();
+                        match apply_action_with(action, root, &mut control_data, options, &resource)
+                            .and_then(|files| ActionRecord::new(action, files, resource, options))
+                        {
+                            Ok(record) => applied.lock().unwrap().push((index, record)),
+                            Err(e) => errors
+                                .lock()
+                                .unwrap()
+                                .push((index, action_failed(action, e))),
+                        }
+                    }
+                });
+            }
+        });
+        records.extend(applied.into_inner().unwrap());
+        failures.extend(errors.into_inner().unwrap());
+        if !options.continue_on_error && !failures.is_empty() {
+            break;
+        }
+    }
+    records.sort_by_key(|(index, _)| *index);
+    failures.sort_by_key(|(index, _)| *index);
+
+    (
+        records.into_iter().map(|(_, record)| record).collect(),
+        failures.into_iter().map(|(_, error)| error).collect(),
+    )
+}
+
+/// Record of the changes made by applying the actions, written to `ReconstructOptions::manifest`
+/// for auditing what a reconstruction did without comparing the packages
+#[derive(Debug, Serialize)]
+struct ChangeManifest<'a> {
+    /// the applied actions, in order
+    actions: Vec<ActionRecord<'a>>,
+    /// the control fields changed by the actions
+    fields: Vec<FieldChange>,
+}
+
+#[derive(Debug, Serialize)]
+struct ActionRecord<'a> {
+    description: String,
+    action: &'a AprilAction,
+    /// the resources used by the action, with their digests
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    resources: Vec<ResourceRecord>,
+    /// the files touched by the action, relative to the package root
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    files: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ResourceRecord {
+    uri: String,
+    algorithm: &'static str,
+    digest: String,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct FieldChange {
+    package: Option<String>,
+    field: String,
+    before: Option<String>,
+    after: Option<String>,
+}
+
+impl<'a> ActionRecord<'a> {
+    /// Record an applied action with the content of its resource (if it is fetched), the resource
+    /// digests are only resolved when a manifest is written
+    fn new(
+        action: &'a AprilAction,
+        files: Vec<String>,
+        resource: OnceCell<ResourceContent>,
+        options: &ReconstructOptions,
+    ) -> Result<Self> {
+        let mut resources = Vec::new();
+        if let Some(uri) = action_resource_uri(action).filter(|_| options.manifest.is_some())
+            && let Some(record) = resolve_resource_digest(uri, resource.get())?
+        {
+            resources.push(record);
+        }
+
+        Ok(Self {
+            description: action.to_string(),
+            action,
+            resources,
+            files,
+        })
+    }
+}
+
+/// The digest of the resource as verified when it is fetched, resources without a pinned digest
+/// (inline and unpinned local resources) are hashed with SHA256 from the `content` applied by the
+/// action, they are not recorded if the action did not use them
+fn resolve_resource_digest(
+    uri: &str,
+    content: Option<&ResourceContent>,
+) -> Result<Option<ResourceRecord>> {
+    let (algorithm, digest) = match resolve_resource_uri(uri)?.0 {
+        AprilResourceType::External { digest, .. }
+        | AprilResourceType::Bundled { digest, .. }
+        | AprilResourceType::Local {
+            digest: Some(digest),
+            ..
+        } => (digest.algorithm, digest.hex.to_ascii_lowercase()),
+        _ => {
+            let Some(content) = content else {
+                return Ok(None);
+            };
+            // the digest is of the resource as fetched, before decompressing it
+            let content = match content {
+                ResourceContent::Compressed(content, _) => content,
+                content => content,
+            };
+            let algorithm = HashAlgorithm::Sha256;
+            (algorithm, hash_reader(&mut content.reader()?, algorithm)?)
+        }
+    };
+
+    Ok(Some(ResourceRecord {
+        uri: uri.to_string(),
+        algorithm: algorithm.as_str(),
+        digest,
+    }))
+}
+
+/// The fields that differ between the control data before and after applying the actions,
+/// the paragraphs are compared by their positions
+fn diff_control_fields(before: &Deb822, after: &Deb822) -> Vec<FieldChange> {
+    let mut changes = Vec::new();
+    let before = before.paragraphs().collect::<Vec<_>>();
+    let after = after.paragraphs().collect::<Vec<_>>();
+    for index in 0..before.len().max(after.len()) {
+        let (old, new) = (before.get(index), after.get(index));
+        let package = new.or(old).and_then(|paragraph| paragraph.get("Package"));
+        let mut fields = old
+            .map(|paragraph| paragraph.keys().collect::<Vec<_>>())
+            .unwrap_or_default();
+        for key in new.into_iter().flat_map(|paragraph| paragraph.keys()) {
+            if !fields.contains(&key) {
+                fields.push(key);
+            }
+        }
+        for field in fields {
+            let old_value = old.and_then(|paragraph| paragraph.get(&field));
+            let new_value = new.and_then(|paragraph| paragraph.get(&field));
+            if old_value != new_value {
+                changes.push(FieldChange {
+                    package: package.clone(),
+                    field,
+                    before: old_value,
+                    after: new_value,
+                });
+            }
+        }
+    }
+
+    changes
+}
+
+/// What a control field or a package script is expected to look like after applying the actions
+#[derive(Debug, Clone, PartialEq)]
+enum Expectation {
+    /// the field has the entry (or the script contains the content)
+    Contains(String),
+    /// the field has the relation, or a stricter one on the same package (see `merge_relation_into_list`)
+    Merged(String),
+    /// the field has no entry on the package, in either form `foo` or `foo (...)`
+    Lacks(String),
+    /// the field (or the script) is exactly this, an empty field is expected to be absent
+    Equals(String),
+    /// the script is expected to be removed
+    Absent,
+}
+
+impl Expectation {
+    fn holds(&self, actual: Option<&str>, is_field: bool) -> bool {
+        let entries = || {
+            actual
+                .unwrap_or_default()
+                .split(',')
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+                .collect::<Vec<_>>()
+        };
+        match self {
+            Expectation::Contains(entry) if is_field => entries().contains(&entry.as_str()),
+            Expectation::Contains(content) => actual.is_some_and(|s| s.contains(content.as_str())),
+            // merging the relation again does not change anything
+            Expectation::Merged(relation) => {
+                let entries = entries();
+                entries.contains(&relation.as_str())
+                    || merge_relation_into_list(&entries.join(", "), relation) == entries.join(", ")
+            }
+            Expectation::Lacks(item) => !entries()
+                .iter()
+                .any(|x| *x == item || x.starts_with(&format!("{} (", item))),
+            Expectation::Equals(value) if is_field && value.is_empty() => actual.is_none(),
+            // relationship fields get normalized after patching
+            Expectation::Equals(value) if is_field => actual.is_some_and(|actual| {
+                actual.trim() == value.trim()
+                    || normalize_string_list(actual) == normalize_string_list(value)
+            }),
+            Expectation::Equals(content) => actual == Some(content.as_str()),
+            Expectation::Absent => actual.is_none(),
+        }
+    }
+}
+
+impl Display for Expectation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expectation::Contains(value) => write!(f, "expected to contain {:?}", value),
+            Expectation::Merged(value) => {
+                write!(f, "expected to contain {:?} or a stricter relation", value)
+            }
+            Expectation::Lacks(value) => write!(f, "expected not to contain {:?}", value),
+            Expectation::Equals(value) if value.is_empty() => write!(f, "expected to be absent"),
+            Expectation::Equals(value) => write!(f, "expected to be {:?}", value),
+            Expectation::Absent => write!(f, "expected to be absent"),
+        }
+    }
+}
+
+/// The control field or package script an expectation is about
+#[derive(Debug, PartialEq)]
+enum VerifyTarget<'a> {
+    Field {
+        package: Option<&'a str>,
+        field: &'a str,
+    },
+    Script(&'a str),
+}
+
+impl Display for VerifyTarget<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyTarget::Field {
+                package: Some(package),
+                field,
+            } => write!(f, "field {} of package {}", field, package),
+            VerifyTarget::Field { field, .. } => write!(f, "field {}", field),
+            VerifyTarget::Script(file) => write!(f, "package script {}", file),
+        }
+    }
+}
+
+/// Build the expected state of the control fields and package scripts from the actions. Every
+/// action is expected to still have its effect after all the actions are applied, except that
+/// replacing (or removing) a whole field or script overrides the actions before it.
+fn expected_post_state(actions: &[AprilAction]) -> Vec<(VerifyTarget<'_>, Vec<Expectation>)> {
+    let mut state: Vec<(VerifyTarget, Vec<Expectation>)> = Vec::new();
+    for action in actions {
+        let (target, action, value) = match action {
+            AprilAction::PatchField {
+                field,
+                value,
+                action,
+                package,
+            } => (
+                VerifyTarget::Field {
+                    package: package.as_deref(),
+                    field,
+                },
+                *action,
+                Some(value),
+            ),
+            AprilAction::PatchScript {
+                file,
+                content,
+                action,
+            } => (
+                VerifyTarget::Script(file),
+                AprilFieldActionType::from(*action),
+                content.as_ref(),
+            ),
+            // nothing is known about the fields after the control data is dropped
+            AprilAction::DropControlData => {
+                state.retain(|(target, _)| matches!(target, VerifyTarget::Script(_)));
+                continue;
+            }
+            _ => continue,
+        };
+        let is_field = matches!(target, VerifyTarget::Field { .. });
+        let index = match state.iter().position(|(known, _)| *known == target) {
+            Some(index) => index,
+            None => {
+                state.push((target, Vec::new()));
+                state.len() - 1
+            }
+        };
+        let expectations = &mut state[index].1;
+        let value = value.cloned().unwrap_or_default();
+        match action {
+            AprilFieldActionType::Replace => *expectations = vec![Expectation::Equals(value)],
+            AprilFieldActionType::Remove if is_field => {
+                expectations.push(Expectation::Lacks(value))
+            }
+            AprilFieldActionType::Remove => *expectations = vec![Expectation::Absent],
+            AprilFieldActionType::Append
+            | AprilFieldActionType::Prepend
+            | AprilFieldActionType::Merge => {
+                // the field (or script) is modified further, so only its entries are known
+                *expectations = std::mem::take(expectations)
+                    .into_iter()
+                    .flat_map(|expectation| match expectation {
+                        Expectation::Equals(value) if is_field => value
+                            .split(',')
+                            .map(|s| s.trim())
+                            .filter(|s| !s.is_empty())
+                            .map(|s| Expectation::Contains(s.to_string()))
+                            .collect(),
+                        Expectation::Equals(content) => vec![Expectation::Contains(content)],
+                        Expectation::Absent => vec![],
+                        expectation => vec![expectation],
+                    })
+                    .collect();
+                if value.is_empty() {
+                    continue;
+                }
+                if is_field {
+                    // the package removed by an earlier action is added back
+                    let packages = value
+                        .split(',')
+                        .filter_map(|s| s.split(['(', ' ']).find(|s| !s.is_empty()))
+                        .collect::<Vec<_>>();
+                    expectations.retain(|expectation| {
+                        !matches!(expectation, Expectation::Lacks(item) if packages.contains(&item.as_str()))
+                    });
+                }
+                if is_field && matches!(action, AprilFieldActionType::Merge) {
+                    expectations.push(Expectation::Merged(value));
+                } else if is_field {
+                    expectations.extend(
+                        value
+                            .split(',')
+                            .map(|s| s.trim())
+                            .filter(|s| !s.is_empty())
+                            .map(|s| Expectation::Contains(s.to_string())),
+                    );
+                } else {
+                    expectations.push(Expectation::Contains(value));
+                }
+            }
+        }
+    }
+
+    state
+}
+
+/// Check the patched control data and the package scripts in `root`, and make sure every field and
+/// script action still has its intended effect (e.g. it is not undone by a later action)
+fn verify_applied_actions(
+    root: &Path,
+    control_data: &Deb822,
+    actions: &[AprilAction],
+) -> Result<()> {
+    let mut failures = Vec::new();
+    for (target, expectations) in expected_post_state(actions) {
+        let actual = match &target {
+            VerifyTarget::Field { package, field } => match package {
+                Some(package) => control_data
+                    .paragraphs()
+                    .find(|paragraph| paragraph.get("Package").as_deref() == Some(*package)),
+                None => control_data.paragraphs().next(),
+            }
+            .and_then(|paragraph| paragraph.get(field)),
+            VerifyTarget::Script(file) => {
+                match std::fs::read_to_string(root.join("DEBIAN").join(file)) {
+                    Ok(script) => Some(script),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        };
+        let is_field = matches!(target, VerifyTarget::Field { .. });
+        for expectation in expectations {
+            if !expectation.holds(actual.as_deref(), is_field) {
+                failures.push(format!(
+                    "{} {}, but it is {}",
+                    target,
+                    expectation,
+                    match &actual {
+                        Some(actual) => format!("{:?}", actual),
+                        None => "absent".to_string(),
+                    }
+                ));
+            }
+        }
+    }
+    if !failures.is_empty() {
+        return Err(AprilError::VerificationFailed(failures));
+    }
+
+    Ok(())
+}
+
+/// The content of the control file, which ends with exactly one newline (as expected by dpkg)
+/// even if the original control file does not
+fn control_file_content(control_data: &Deb822) -> String {
+    let mut content = control_data.to_string().trim_end_matches('\n').to_string();
+    content.push('\n');
+
+    content
+}
+
+/// Apply the actions to the package extracted to `root`, and write back the patched control data
+/// (after `process_control` has processed it)
+fn patch_extracted_package(
+    root: &Path,
+    actions: &[AprilAction],
+    options: &ReconstructOptions,
+    process_control: impl FnOnce(&mut Deb822) -> Result<()>,
+) -> Result<Deb822> {
+    let control_file_path = root.join("DEBIAN/control");
+    let mut control_data = Deb822::from_file(&control_file_path)
+        .map_err(|e| AprilError::InvalidControlData(e.to_string()))?;
+
+    check_conffile_edits(root, actions, options)?;
+    // the control data is modified in place, so keep a copy of the original for the manifest
+    let original_control_data = options
+        .manifest
+        .as_ref()
+        .map(|_| Deb822::from_str_relaxed(&control_data.to_string()).0);
+
+    prefetch_resources(actions, &options.fetch);
+    let records = apply_actions(actions, root, &mut control_data, options)?;
+
+    normalize_patched_relationship_fields(actions, &mut control_data)?;
+    process_control(&mut control_data)?;
+    // a control file failing the verification is not written
+    if options.verify {
+        verify_applied_actions(root, &control_data, actions)?;
+    }
+    std::fs::write(control_file_path, control_file_content(&control_data))?;
+    if let (Some(manifest_path), Some(original_control_data)) =
+        (&options.manifest, original_control_data)
+    {
+        let manifest = ChangeManifest {
+            actions: records,
+            fields: diff_control_fields(&original_control_data, &control_data),
+        };
+        log::info!("Writing change manifest to {}", manifest_path.display());
+        serde_json::to_writer_pretty(File::create(manifest_path)?, &manifest)
+            .map_err(std::io::Error::from)?;
+    }
+
+    Ok(control_data)
+}
+
+/// The directory to extract the package in: the configured temporary directory, otherwise `tmpdir`
+/// (the value of `$TMPDIR`), falling back to the directory of the package. Only the extracted files
+/// are put there, the reconstructed package is built next to the output path, so that moving it into
+/// place is still an atomic rename.
+fn extraction_parent_dir(
+    deb_path_dir: &Path,
+    options: &ReconstructOptions,
+    tmpdir: Option<OsString>,
+) -> PathBuf {
+    options
+        .temp_dir
+        .clone()
+        .or_else(|| tmpdir.filter(|dir| !dir.is_empty()).map(PathBuf::from))
+        .unwrap_or_else(|| deb_path_dir.to_path_buf())
+}
+
+/// Apply the actions in place to a package already extracted (by `dpkg-deb -R`) to `root`,
+/// without repacking it. The post-reconstruction hook is not run, and the options about the input
+/// package or the output package are rejected. In dry-run mode only the options are checked.
+pub fn apply_actions_to_tree<P: AsRef<Path>>(
+    root: P,
+    actions: &[AprilAction],
+    options: &ReconstructOptions,
+) -> Result<()> {
+    for (option, is_set) in [
+        ("expected SHA256 digest", options.expected_sha256.is_some()),
+        (
+            "signature verification",
+            options.signature_verifier.is_some(),
+        ),
+        ("output path", options.output.is_some()),
+        ("reproducible build", options.reproducible),
+    ] {
+        if is_set {
+            return Err(AprilError::InvalidOption(format!(
+                "The {} does not apply to an extracted package tree",
+                option
+            )));
+        }
+    }
+    // paths are resolved inside the canonical root
+    let root = root.as_ref().canonicalize()?;
+    if options.dry_run {
+        return Ok(());
+    }
+    check_required_tools(actions, options, false, std::env::var_os("PATH").as_deref())?;
+    patch_extracted_package(&root, actions, options, |_| Ok(()))?;
+    log::info!("Package tree {} patched", root.display());
+
+    Ok(())
+}
+
+/// Read the version of a package already extracted (by `dpkg-deb -R`) to `root`
+pub fn read_tree_package_version<P: AsRef<Path>>(root: P) -> Result<String> {
+    let control_data = Deb822::from_file(root.as_ref().join("DEBIAN/control"))
+        .map_err(|e| AprilError::InvalidControlData(e.to_string()))?;
+
+    control_data
+        .paragraphs()
+        .next()
+        .and_then(|paragraph| paragraph.get("Version"))
+        .ok_or_else(|| AprilError::InvalidControlData("Missing Version field".to_string()))
+}
+
+/// Apply the actions to the extracted package, returns the records of the applied actions
+fn apply_actions<'a>(
+    actions: &'a [AprilAction],
+    root: &Path,
+    control_data: &mut Deb822,
+    options: &ReconstructOptions,
+) -> Result<Vec<ActionRecord<'a>>> {
+    let mut records = Vec::new();
+    let mut failures = Vec::new();
+    let mut record_failure = |error: AprilError| {
+        if !options.continue_on_error {
+            return Err(error);
+        }
+        log::warn!("{}", error);
+        failures.push(error);
+        Ok(())
+    };
+    let mut index = 0;
+    while index < actions.len() {
+        // consecutive file operations (of the same phase) can be applied concurrently
+        let file_actions = actions[index..]
+            .iter()
+            .take_while(|action| matches!(action, AprilAction::PatchFile { .. }))
+            .count();
+        if options.jobs > 1 && file_actions > 1 {
+            let file_actions = &actions[index..index + file_actions];
+            let (applied, errors) = apply_file_actions_concurrently(file_actions, root, options);
+            records.extend(applied);
+            for error in errors {
+                record_failure(error)?;
+            }
+            index += file_actions.len();
+            continue;
+        }
+        let action = &actions[index];
+        log::debug!("Applying action: {}", action);
+        let resource = OnceCell::new();
+        match apply_action_with(action, root, control_data, options, &resource)
+            .and_then(|files| ActionRecord::new(action, files, resource, options))
+        {
+            Ok(record) => records.push(record),
+            Err(e) => record_failure(action_failed(action, e))?,
+        }
+        index += 1;
+    }
+
+    match failures.len() {
+        0 => Ok(records),
+        1 => Err(failures.remove(0)),
+        _ => Err(AprilError::ActionsFailed(failures)),
+    }
+}
+
+/// Apply a single action, returns the files touched by the action (relative to the package root)
+#[cfg(test)]
+fn apply_action(
+    action: &AprilAction,
+    root: &Path,
+    control_data: &mut Deb822,
+    options: &ReconstructOptions,
+) -> Result<Vec<String>> {
+    apply_action_with(action, root, control_data, options, &OnceCell::new())
+}
+
+/// Apply a single action, `resource` holds the content of the resource of a file operation once
+/// it is fetched (see `fetch_resource_once`)
+fn apply_action_with(
+    action: &AprilAction,
+    root: &Path,
+    control_data: &mut Deb822,
+    options: &ReconstructOptions,
+    resource: &OnceCell<ResourceContent>,
+) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    match action {
+        AprilAction::PreconfigPackage
+        | AprilAction::UnpackPackage
+        | AprilAction::ExtractPackage
+        | AprilAction::ConfigurePackage
+        | AprilAction::InstallPackage => (),
+        AprilAction::PatchField { package, .. } => {
+            let mut paragraph = select_control_paragraph(control_data, package.as_deref())?;
+            apply_field_patch(action, &mut paragraph);
+        }
+        AprilAction::DropControlData => *control_data = Deb822::new(),
+        AprilAction::PutControlChunk { data } => {
+            // the paragraphs are appended, the existing control data is kept
+            let (chunk, _) = Deb822::from_str_relaxed(data);
+            for paragraph in chunk.paragraphs() {
+                let mut new_paragraph = control_data.add_paragraph();
+                for (key, value) in paragraph.items() {
+                    new_paragraph.set(&key, &value);
+                }
+            }
+        }
+        AprilAction::PatchScript {
+            file,
+            content,
+            action,
+        } => {
+            apply_script_actions(root, file, content, action, &None, options.force_append)?;
+            files.push(format!("/DEBIAN/{}", file));
+        }
+        AprilAction::PatchFile {
+            path,
+            action,
+            allow_empty,
+            optional,
+        } => {
+            let matches = if is_glob_pattern(path) {
+                expand_glob_pattern(root, path)?
+            } else {
+                vec![path.to_string()]
+            };
+            if matches.is_empty() && !(*allow_empty || *optional) {
+                return Err(AprilError::NoGlobMatch(path.to_string()));
+            }
+            for path in matches {
+                match apply_file_operation_with(root, &path, action, options, resource) {
+                    // only if the file itself is missing, not e.g. a resource or the destination
+                    Err(AprilError::Io(e))
+                        if *optional
+                            && e.kind() == std::io::ErrorKind::NotFound
+                            && root
+                                .join(path.trim_start_matches('/'))
+                                .symlink_metadata()
+                                .is_err() =>
+                    {
+                        log::info!(
+                            "Skipping the optional operation on {}, it does not exist",
+                            path
+                        );
+                        continue;
+                    }
+                    result => result?,
+                }
+                files.push(path);
+                if let AprilFileOperationType::Move(dst)
+                | AprilFileOperationType::Copy(dst)
+                | AprilFileOperationType::Link(dst) = action
+                {
+                    files.push(dst.to_string());
+                }
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// Compression arguments for `dpkg-deb -b`, the compressor and the level are validated here
+fn repack_compression_args(options: &ReconstructOptions) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    let levels = match options.compression.as_deref() {
+        None => None,
+        Some("gzip") => Some(1..=9),
+        Some("xz") => Some(0..=9),
+        Some("zstd") => Some(1..=22),
+        Some("none") => Some(0..=0),
+        Some(compression) => {
+            return Err(AprilError::InvalidOption(format!(
+                "Unsupported compression: {} (expected gzip, xz, zstd or none)",
+                compression
+            )));
+        }
+    };
+    if let Some(compression) = &options.compression {
+        args.push(format!("-Z{}", compression));
+    }
+    if let Some(level) = options.compression_level {
+        if let Some(levels) = levels.filter(|levels| !levels.contains(&level)) {
+            return Err(AprilError::InvalidOption(format!(
+                "Invalid compression level {} for {} (expected {}-{})",
+                level,
+                options.compression.as_deref().unwrap_or_default(),
+                levels.start(),
+                levels.end()
+            )));
+        }
+        args.push(format!("-z{}", level));
+    }
+
+    Ok(args)
+}
+
+/// The timestamp of a reproducible reconstruction: `$SOURCE_DATE_EPOCH` if set, otherwise the
+/// latest modification time of the files in the original package (extracted to `root`), so that
+/// the same package and APRIL configuration always produce the same timestamps
+fn source_date_epoch(root: &Path) -> Result<i64> {
+    if let Some(epoch) = std::env::var_os("SOURCE_DATE_EPOCH") {
+        return epoch
+            .to_str()
+            .and_then(|epoch| epoch.parse().ok())
+            .ok_or_else(|| {
+                AprilError::InvalidOption(format!("Invalid SOURCE_DATE_EPOCH: {:?}", epoch))
+            });
+    }
+
+    fn latest_mtime(dir: &Path) -> Result<i64> {
+        let mut latest = 0;
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            latest = latest.max(metadata.mtime());
+            if metadata.is_dir() {
+                latest = latest.max(latest_mtime(&entry.path())?);
+            }
+        }
+
+        Ok(latest)
+    }
+    // `root` itself is created when extracting, its timestamp is not from the package
+    latest_mtime(root)
+}
+
+/// Set the modification times later than `epoch` to `epoch`, including `root` itself
+fn clamp_mtimes(root: &Path, epoch: i64) -> Result<()> {
+    let metadata = root.symlink_metadata()?;
+    if metadata.is_dir() {
+        for entry in std::fs::read_dir(root)? {
+            clamp_mtimes(&entry?.path(), epoch)?;
+        }
+    }
+    if metadata.mtime() > epoch {
+        let epoch = filetime::FileTime::from_unix_time(epoch, 0);
+        filetime::set_symlink_file_times(root, epoch, epoch)?;
+    }
+
+    Ok(())
+}
+
+/// The external programs needed for applying the actions, and for repacking the package (with the
+/// post-reconstruction hook) if `repack` is true
+fn required_tools(
+    actions: &[AprilAction],
+    options: &ReconstructOptions,
+    repack: bool,
+) -> Vec<&'static str> {
+    let mut tools = if repack { vec!["dpkg-deb"] } else { vec![] };
+    for action in actions {
+        let tool = match action {
+            AprilAction::PatchFile {
+                action: AprilFileOperationType::Patch(_),
+                ..
+            } if !options.builtin_patch => "patch",
+            AprilAction::PatchFile {
+                action: AprilFileOperationType::BinaryPatch(uri),
+                ..
+            } if binary_patch_format(uri).ok() != Some(BinaryPatchFormat::Bsdiff) => "xdelta3",
+            _ => continue,
+        };
+        if !tools.contains(&tool) {
+            tools.push(tool);
+        }
+    }
+    if repack && options.post_reconstruct_hook.is_some() {
+        tools.push("sh");
+    }
+
+    tools
+}
+
+/// Make sure all the required programs can be found in `search_path` (`$PATH`), so that a missing
+/// program is reported before anything is downloaded or modified
+fn check_required_tools(
+    actions: &[AprilAction],
+    options: &ReconstructOptions,
+    repack: bool,
+    search_path: Option<&OsStr>,
+) -> Result<()> {
+    let dirs = search_path
+        .map(|path| std::env::split_paths(path).collect::<Vec<_>>())
+        .unwrap_or_default();
+    let missing = required_tools(actions, options, repack)
+        .into_iter()
+        .filter(|tool| {
+            !dirs.iter().any(|dir| {
+                std::fs::metadata(dir.join(tool))
+                    .is_ok_and(|metadata| metadata.is_file() && metadata.mode() & 0o111 != 0)
+            })
+        })
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Err(AprilError::MissingTools(missing));
+    }
+
+    Ok(())
+}
+
+pub fn apply_actions_for_reconstruct<P: AsRef<Path>>(
+    deb_path: P,
+    actions: &[AprilAction],
+    options: &ReconstructOptions,
+) -> Result<()> {
+    apply_actions_for_reconstruct_with(deb_path, actions, options, |_| Ok(()))?;
+
+    Ok(())
+}
+
+/// The commands and actions `apply_actions_for_reconstruct` runs for the package, one per line
+/// (the directory the package is extracted to is shown as `<root>`)
+pub fn reconstruct_plan<P: AsRef<Path>>(
+    deb_path: P,
+    actions: &[AprilAction],
+    options: &ReconstructOptions,
+) -> Result<Vec<String>> {
+    let deb_path = deb_path.as_ref();
+    let new_deb_path = match &options.output {
+        Some(output) => output.clone(),
+        None => repacked_package_path(deb_path),
+    };
+    let compression_args = repack_compression_args(options)?;
+    let mut plan = vec![format!("dpkg-deb -R {} <root>", deb_path.display())];
+    plan.extend(actions.iter().map(|action| action.to_string()));
+    plan.push(format!(
+        "dpkg-deb {}-b <root> {}",
+        compression_args
+            .iter()
+            .map(|arg| format!("{} ", arg))
+            .collect::<String>(),
+        new_deb_path.display()
+    ));
+
+    Ok(plan)
+}
+
+/// Same as `apply_actions_for_reconstruct`, but `process_control` can inspect or further modify
+/// the patched control data before the package is repacked. Returns the control data of the
+/// reconstructed package, or `None` in dry-run mode.
+pub fn apply_actions_for_reconstruct_with<P, F>(
+    deb_path: P,
+    actions: &[AprilAction],
+    options: &ReconstructOptions,
+    process_control: F,
+) -> Result<Option<Deb822>>
+where
+    P: AsRef<Path>,
+    F: FnOnce(&mut Deb822) -> Result<()>,
+{
+    let deb_path = deb_path.as_ref();
+    let new_deb_path = match &options.output {
+        Some(output) => output.clone(),
+        None => repacked_package_path(deb_path),
+    };
+    let compression_args = repack_compression_args(options)?;
+    if options.dry_run {
+        return Ok(None);
+    }
+
+    check_required_tools(actions, options, true, std::env::var_os("PATH").as_deref())?;
+    check_package_digest(deb_path, options)?;
+    check_package_signature(deb_path, options)?;
+    let deb_path_dir = deb_path.parent().ok_or_else(|| {
+        AprilError::InvalidPackage(format!("Invalid package path: {}", deb_path.display()))
+    })?;
+    let tmp_root = Builder::new()
+        .disable_cleanup(options.keep_temp)
+        .tempdir_in(extraction_parent_dir(
+            deb_path_dir,
+            options,
+            std::env::var_os("TMPDIR"),
+        ))?;
+    if options.keep_temp {
+        log::warn!(
+            "Keeping the extraction directory: {}",
+            tmp_root.path().display()
+        );
+    }
+    log::info!(
+        "Running dpkg-deb -R {} {}",
+        deb_path.display(),
+        tmp_root.path().display()
+    );
+    let status = Command::new("dpkg-deb")
+        .arg("-R")
+        .arg(deb_path)
+        .arg(tmp_root.path())
+        .spawn()?
+        .wait()?;
+    if !status.success() {
+        return Err(AprilError::CommandFailed {
+            operation: "extract package",
+            status,
+        });
+    }
+
+    let epoch = if options.reproducible {
+        Some(source_date_epoch(tmp_root.path())?)
+    } else {
+        None
+    };
+    let control_data = patch_extracted_package(tmp_root.path(), actions, options, process_control)?;
+    if let Some(epoch) = epoch {
+        log::info!("Clamping modification times to {}", epoch);
+        clamp_mtimes(tmp_root.path(), epoch)?;
+    }
+    let output_dir = match new_deb_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    std::fs::create_dir_all(output_dir)?;
+    // build into a temporary file first, so that a rejected package never appears at the output path
+    let tmp_deb = Builder::new().suffix(".deb").tempfile_in(output_dir)?;
+    log::info!(
+        "Running dpkg-deb {:?} -b {} {}",
+        compression_args,
+        tmp_root.path().display(),
+        tmp_deb.path().display()
+    );
+    let mut command = Command::new("dpkg-deb");
+    if let Some(epoch) = epoch {
+        // `dpkg-deb` uses it for the timestamps in the ar archive
+        command.env("SOURCE_DATE_EPOCH", epoch.to_string());
+    }
+    let status = command
+        .args(&compression_args)
+        .arg("-b")
+        .arg(tmp_root.path())
+        .arg(tmp_deb.path())
+        .spawn()?
+        .wait()?;
+    if !status.success() {
+        return Err(AprilError::CommandFailed {
+            operation: "repack package",
+            status,
+        });
+    }
+    if let Some(max_output_size) = options.max_output_size {
+        let size = tmp_deb.as_file().metadata()?.len();
+        if size > max_output_size {
+            return Err(AprilError::OutputTooLarge {
+                size,
+                limit: max_output_size,
+            });
+        }
+    }
+    tmp_deb.persist(&new_deb_path).map_err(|e| e.error)?;
+    log::info!(
+        "Reconstructed package written to {}",
+        new_deb_path.display()
+    );
+
+    if let Some(hook) = &options.post_reconstruct_hook {
+        let paragraph = control_data.paragraphs().next();
+        let field = |name| {
+            paragraph
+                .as_ref()
+                .and_then(|p| p.get(name))
+                .unwrap_or_default()
+        };
+        run_post_reconstruct_hook(hook, &new_deb_path, &field("Package"), &field("Version"))?;
+    }
+
+    Ok(Some(control_data))
+}
+
+#[test]
+fn test_remove_item_from_string_list() {
+    let input = "foo, bar, baz";
+    let item = "bar";
+    let expected = "foo, baz";
+    assert_eq!(remove_item_from_string_list(input, item), expected);
+
+    let input = "foo, bar (>= 1.2.0), baz";
+    let item = "bar";
+    let expected = "foo, baz";
+    assert_eq!(remove_item_from_string_list(input, item), expected);
+}
+
+#[test]
 fn test_apply_field_patch() {
     let mut paragraph = Paragraph::new();
     paragraph.set("Depends", "foo (>= 1.2.0), bar");
 
-    let action = AprilAction::PatchField {
-        field: std::borrow::Cow::Borrowed("Depends"),
-        value: "baz".to_owned(),
-        action: AprilActionType::Remove,
+    let action = AprilAction::PatchField {
+        field: std::borrow::Cow::Borrowed("Depends"),
+        value: "baz".to_owned(),
+        action: AprilFieldActionType::Remove,
+        package: None,
+    };
+    apply_field_patch(&action, &mut paragraph);
+    assert_eq!(paragraph.get("Depends").unwrap(), "foo (>= 1.2.0), bar");
+
+    let action = AprilAction::PatchField {
+        field: std::borrow::Cow::Borrowed("Depends"),
+        value: "baz".to_owned(),
+        action: AprilFieldActionType::Append,
+        package: None,
+    };
+    apply_field_patch(&action, &mut paragraph);
+    assert_eq!(
+        paragraph.get("Depends").unwrap(),
+        "foo (>= 1.2.0), bar, baz"
+    );
+
+    let action = AprilAction::PatchField {
+        field: std::borrow::Cow::Borrowed("Depends"),
+        value: "foo".to_owned(),
+        action: AprilFieldActionType::Replace,
+        package: None,
+    };
+    apply_field_patch(&action, &mut paragraph);
+    assert_eq!(paragraph.get("Depends").unwrap(), "foo");
+
+    let action = AprilAction::PatchField {
+        field: std::borrow::Cow::Borrowed("Depends"),
+        value: "".to_owned(),
+        action: AprilFieldActionType::Replace,
+        package: None,
+    };
+    apply_field_patch(&action, &mut paragraph);
+    assert_eq!(paragraph.get("Depends"), None);
+
+    let action = AprilAction::PatchField {
+        field: std::borrow::Cow::Borrowed("Depends"),
+        value: "baz".to_owned(),
+        action: AprilFieldActionType::Append,
+        package: None,
+    };
+    apply_field_patch(&action, &mut paragraph);
+    assert_eq!(paragraph.get("Depends").unwrap(), "baz");
+}
+
+#[test]
+fn test_normalize_patched_relationship_fields() {
+    let mut control_data: Deb822 =
+        "Package: foo\nDepends: bar,  baz (>= 1.0) ,bar\nDescription: a,  b, a\n"
+            .parse()
+            .unwrap();
+    let mut actions = ["baz (>= 1.0)", "qux", "qux"]
+        .map(|value| AprilAction::PatchField {
+            field: std::borrow::Cow::Borrowed("Depends"),
+            value: value.to_owned(),
+            action: AprilFieldActionType::Append,
+            package: None,
+        })
+        .into_iter()
+        .collect::<Vec<_>>();
+    actions.push(AprilAction::PatchField {
+        field: std::borrow::Cow::Borrowed("Breaks"),
+        value: "old".to_owned(),
+        action: AprilFieldActionType::Remove,
+        package: None,
+    });
+    let mut paragraph = select_control_paragraph(&mut control_data, None).unwrap();
+    paragraph.set("Breaks", "old");
+    for action in &actions {
+        apply_field_patch(action, &mut paragraph);
+    }
+    assert_eq!(paragraph.get("Breaks").unwrap(), "");
+
+    normalize_patched_relationship_fields(&actions, &mut control_data).unwrap();
+    let paragraph = control_data.paragraphs().next().unwrap();
+    assert_eq!(paragraph.get("Depends").unwrap(), "bar, baz (>= 1.0), qux");
+    assert_eq!(paragraph.get("Breaks"), None);
+    // fields not patched by the actions are left alone
+    assert_eq!(paragraph.get("Description").unwrap(), "a,  b, a");
+}
+
+#[test]
+fn test_merge_relation_into_list() {
+    // tightening
+    assert_eq!(
+        merge_relation_into_list("foo (>= 1.0), bar", "foo (>= 2.0)"),
+        "foo (>= 2.0), bar"
+    );
+    assert_eq!(
+        merge_relation_into_list("foo (>= 1.0), foo (<< 3.0)", "foo (<< 2.0)"),
+        "foo (>= 1.0), foo (<< 2.0)"
+    );
+    assert_eq!(
+        merge_relation_into_list("foo (>= 1.0)", "foo (>> 1.0)"),
+        "foo (>> 1.0)"
+    );
+    assert_eq!(
+        merge_relation_into_list("foo", "foo (>= 1.0)"),
+        "foo (>= 1.0)"
+    );
+    // widening keeps the stricter constraint, and the upper bound is not touched
+    assert_eq!(
+        merge_relation_into_list("bar, foo (>= 2.0), foo (<< 3.0)", "foo (>= 1.0)"),
+        "bar, foo (>= 2.0), foo (<< 3.0)"
+    );
+    assert_eq!(
+        merge_relation_into_list("foo (>= 1:1.0)", "foo (>= 2.0)"),
+        "foo (>= 1:1.0)"
+    );
+    assert_eq!(
+        merge_relation_into_list("foo (>= 1.0)", "foo (>= 1.0-0)"),
+        "foo (>= 1.0)"
+    );
+    assert_eq!(
+        merge_relation_into_list("foo (= 1.0)", "foo"),
+        "foo (= 1.0)"
+    );
+    // exact versions replace any constraint
+    assert_eq!(
+        merge_relation_into_list("foo (>= 1.0), foo (<< 3.0)", "foo (= 2.0)"),
+        "foo (= 2.0)"
+    );
+    assert_eq!(
+        merge_relation_into_list("foo (= 1.0)", "foo (>= 2.0)"),
+        "foo (>= 2.0)"
+    );
+    // different packages, architectures and alternatives are kept
+    assert_eq!(
+        merge_relation_into_list("foo:any (>= 1.0), foo | bar", "foo (>= 2.0)"),
+        "foo:any (>= 1.0), foo | bar, foo (>= 2.0)"
+    );
+    assert_eq!(
+        merge_relation_into_list("foo (>= 1.0)", "foo (>= 2.0) | bar"),
+        "foo (>= 1.0), foo (>= 2.0) | bar"
+    );
+    assert_eq!(merge_relation_into_list("", "foo"), "foo");
+
+    let mut paragraph = Paragraph::new();
+    paragraph.set("Depends", "foo (>= 1.0), bar");
+    let patch = |action| AprilAction::PatchField {
+        field: std::borrow::Cow::Borrowed("Depends"),
+        value: "foo (>= 2.0)".to_owned(),
+        action,
+        package: None,
+    };
+    apply_field_patch(&patch(AprilFieldActionType::Merge), &mut paragraph);
+    assert_eq!(paragraph.get("Depends").unwrap(), "foo (>= 2.0), bar");
+    // keeping both
+    apply_field_patch(&patch(AprilFieldActionType::Append), &mut paragraph);
+    assert_eq!(
+        paragraph.get("Depends").unwrap(),
+        "foo (>= 2.0), bar, foo (>= 2.0)"
+    );
+}
+
+#[test]
+fn test_patch_field_selects_paragraph() {
+    let mut control_data: Deb822 = "Package: foo\nVersion: 1.0\n\nPackage: bar\nVersion: 1.0\n"
+        .parse()
+        .unwrap();
+
+    let action = AprilAction::PatchField {
+        field: std::borrow::Cow::Borrowed("Version"),
+        value: "2.0".to_owned(),
+        action: AprilFieldActionType::Replace,
+        package: None,
+    };
+    apply_field_patch(
+        &action,
+        &mut select_control_paragraph(&mut control_data, None).unwrap(),
+    );
+    let versions = control_data
+        .paragraphs()
+        .map(|paragraph| paragraph.get("Version").unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(versions, ["2.0", "1.0"]);
+
+    let action = AprilAction::PatchField {
+        field: std::borrow::Cow::Borrowed("Depends"),
+        value: "baz".to_owned(),
+        action: AprilFieldActionType::Append,
+        package: Some("bar".to_owned()),
+    };
+    let mut paragraph = select_control_paragraph(&mut control_data, Some("bar")).unwrap();
+    apply_field_patch(&action, &mut paragraph);
+    let depends = control_data
+        .paragraphs()
+        .map(|paragraph| paragraph.get("Depends"))
+        .collect::<Vec<_>>();
+    assert_eq!(depends, [None, Some("baz".to_owned())]);
+
+    assert!(select_control_paragraph(&mut control_data, Some("baz")).is_err());
+
+    let mut control_data = Deb822::new();
+    select_control_paragraph(&mut control_data, None).unwrap();
+    assert_eq!(control_data.paragraphs().count(), 1);
+}
+
+#[test]
+fn test_prepend_script() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().canonicalize().unwrap();
+    std::fs::create_dir(root.join("DEBIAN")).unwrap();
+    std::fs::write(
+        root.join("DEBIAN/postinst"),
+        "#!/bin/sh\nset -e\nldconfig\n",
+    )
+    .unwrap();
+    let content = Some("export FOO=1".to_string());
+    apply_script_actions(
+        &root,
+        "postinst",
+        &content,
+        &AprilActionType::Prepend,
+        &None,
+        false,
+    )
+    .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(root.join("DEBIAN/postinst")).unwrap(),
+        "#!/bin/sh\nexport FOO=1\nset -e\nldconfig\n"
+    );
+
+    // the script is created if it does not exist
+    apply_script_actions(
+        &root,
+        "prerm",
+        &content,
+        &AprilActionType::Prepend,
+        &None,
+        false,
+    )
+    .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(root.join("DEBIAN/prerm")).unwrap(),
+        "#!/bin/sh\nexport FOO=1\n"
+    );
+    let metadata = std::fs::metadata(root.join("DEBIAN/prerm")).unwrap();
+    assert_eq!(metadata.mode() & 0o777, 0o755);
+
+    assert_eq!(
+        prepend_script_content("#!/bin/sh", "exit 0\n"),
+        "#!/bin/sh\nexit 0\n"
+    );
+    assert_eq!(
+        prepend_script_content("ldconfig\n", "exit 0\n"),
+        "exit 0\nldconfig\n"
+    );
+}
+
+#[test]
+fn test_append_script_once() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().canonicalize().unwrap();
+    std::fs::create_dir(root.join("DEBIAN")).unwrap();
+    std::fs::write(root.join("DEBIAN/postinst"), "#!/bin/sh\nset -e\n").unwrap();
+    let content = Some("ldconfig\n".to_string());
+    let append = |force_append| {
+        apply_script_actions(
+            &root,
+            "postinst",
+            &content,
+            &AprilActionType::Append,
+            &None,
+            force_append,
+        )
+        .unwrap();
+        std::fs::read_to_string(root.join("DEBIAN/postinst")).unwrap()
+    };
+
+    assert_eq!(append(false), "#!/bin/sh\nset -e\nldconfig\n");
+    // applying the same action again does not duplicate the content
+    assert_eq!(append(false), "#!/bin/sh\nset -e\nldconfig\n");
+    assert_eq!(append(true), "#!/bin/sh\nset -e\nldconfig\nldconfig\n");
+}
+
+#[test]
+fn test_put_control_chunk() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut control_data: Deb822 = "Package: foo\nVersion: 1.0\n".parse().unwrap();
+    let action = AprilAction::PutControlChunk {
+        data: "Package: foo-data\nVersion: 1.0\nArchitecture: all\n".to_string(),
+    };
+    apply_action(
+        &action,
+        dir.path(),
+        &mut control_data,
+        &ReconstructOptions::default(),
+    )
+    .unwrap();
+    let packages = control_data
+        .paragraphs()
+        .map(|paragraph| paragraph.get("Package").unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(packages, ["foo", "foo-data"]);
+    let paragraph = control_data.paragraphs().nth(1).unwrap();
+    assert_eq!(paragraph.get("Architecture").unwrap(), "all");
+    assert_eq!(
+        control_data
+            .paragraphs()
+            .next()
+            .unwrap()
+            .get("Version")
+            .unwrap(),
+        "1.0"
+    );
+}
+
+#[test]
+fn test_out_of_bound_file_operation() {
+    if let Err(e) = resolve_path("/tmp", "..") {
+        assert_eq!(e.to_string(), "Invalid file path: ..");
+    } else {
+        unreachable!();
+    }
+}
+
+#[test]
+fn test_symlink_escape() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("root");
+    std::fs::create_dir(&root).unwrap();
+    // the symlinks point to a sibling directory, which must stay untouched
+    let outside = dir.path().join("outside");
+    std::fs::create_dir(&outside).unwrap();
+    std::fs::write(outside.join("passwd"), "root:x:0:0::/root:/bin/sh\n").unwrap();
+    std::fs::set_permissions(outside.join("passwd"), Permissions::from_mode(0o644)).unwrap();
+    let outside_unchanged = || {
+        let entries = std::fs::read_dir(&outside)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(entries, ["passwd"]);
+        assert_eq!(
+            std::fs::read_to_string(outside.join("passwd")).unwrap(),
+            "root:x:0:0::/root:/bin/sh\n"
+        );
+        let metadata = std::fs::metadata(outside.join("passwd")).unwrap();
+        assert_eq!(metadata.mode() & 0o777, 0o644);
+    };
+    std::os::unix::fs::symlink(&outside, root.join("etc")).unwrap();
+    std::os::unix::fs::symlink("../outside/april-test", root.join("dangling")).unwrap();
+    std::os::unix::fs::symlink(outside.join("april-test"), root.join("absolute")).unwrap();
+    let options = ReconstructOptions::default();
+    let escapes = [
+        ("/etc/passwd", AprilFileOperationType::Chmod(0o777)),
+        ("/etc/passwd", AprilFileOperationType::Truncate),
+        ("/etc/passwd", AprilFileOperationType::Remove),
+        (
+            "/etc/passwd",
+            AprilFileOperationType::Move("/passwd".to_string()),
+        ),
+        (
+            "/etc/april-test",
+            AprilFileOperationType::Add("data:,x".to_string()),
+        ),
+        ("/etc/april-test/foo", AprilFileOperationType::Mkdir),
+        (
+            "/dangling",
+            AprilFileOperationType::Overwrite("data:,x".to_string()),
+        ),
+        (
+            "/absolute",
+            AprilFileOperationType::Add("data:,x".to_string()),
+        ),
+        (
+            "/foo",
+            AprilFileOperationType::Copy("/etc/april-test".to_string()),
+        ),
+    ];
+    std::fs::write(root.join("foo"), "foo").unwrap();
+    for (path, action) in escapes {
+        let e = apply_file_operation(&root, path, &action, &options).unwrap_err();
+        assert!(matches!(e, AprilError::PathEscape(_)), "{}: {}", path, e);
+    }
+    outside_unchanged();
+
+    // the symlinks themselves can still be removed, without touching their targets
+    apply_file_operation(&root, "/etc", &AprilFileOperationType::Remove, &options).unwrap();
+    assert!(root.join("etc").symlink_metadata().is_err());
+    outside_unchanged();
+
+    // a symlink in the root itself does not break the containment check
+    let root_link = dir.path().join("root-link");
+    std::os::unix::fs::symlink(&root, &root_link).unwrap();
+    apply_file_operation(
+        &root_link,
+        "/foo",
+        &AprilFileOperationType::Truncate,
+        &options,
+    )
+    .unwrap();
+    assert_eq!(std::fs::read(root.join("foo")).unwrap(), b"");
+}
+
+#[test]
+fn test_decode_data_uri() {
+    let decode = |uri: &str| decode_data_uri(uri, uri.strip_prefix("data:").unwrap());
+    assert_eq!(decode("data:,Hello%2C%20world!").unwrap(), b"Hello, world!");
+    assert_eq!(
+        decode("data:text/plain;charset=utf-8;base64,SGVsbG8=").unwrap(),
+        b"Hello"
+    );
+    assert_eq!(decode("data:;base64,SGVsbG8=").unwrap(), b"Hello");
+    assert_eq!(decode("data:base64,SGVsbG8=").unwrap(), b"Hello");
+    // base64url
+    assert_eq!(decode("data:;base64,-_8-").unwrap(), [0xfb, 0xff, 0x3e]);
+    assert_eq!(decode("data:;base64,_-8").unwrap(), [0xff, 0xef]);
+    assert_eq!(decode("data:;base64,Zm9vYg").unwrap(), b"foob");
+    assert_eq!(decode("data:;base64,Zm9vYg==").unwrap(), b"foob");
+    assert_eq!(decode("data:;base64,PDw_Pz8-Pg==").unwrap(), b"<<???>>");
+    // line breaks and indentation
+    assert_eq!(
+        decode("data:text/plain;base64,SGVs\n  bG8s\r\n IHdv\tcmxkIQ==\n").unwrap(),
+        b"Hello, world!"
+    );
+
+    // the padding is optional, but a trailing single character can not be decoded
+    let err = decode("data:;base64,SGVsbG8hS").unwrap_err();
+    assert!(err.to_string().contains("bad base64 payload"));
+    let err = decode("data:;base64,SGVs*G8=").unwrap_err();
+    assert!(err.to_string().contains("bad base64 payload"));
+    let err = decode("data:text/plain;base64").unwrap_err();
+    assert!(err.to_string().contains("missing ','"));
+    let err = decode("data:text/plain,100%").unwrap_err();
+    assert!(err.to_string().contains("bad percent-encoding"));
+    let err = decode("data:text plain,foo").unwrap_err();
+    assert!(err.to_string().contains("malformed media type"));
+    let err = decode("data:text/plain;base64;charset=utf-8,foo").unwrap_err();
+    assert!(err.to_string().contains("malformed parameter"));
+}
+
+#[test]
+fn test_resolve_resource_uri() {
+    let sha256 = "abc0".repeat(16);
+    let uri = format!("file::sha256={}::https://example.com/package.deb", sha256);
+    let expected = AprilResourceType::External {
+        url: "https://example.com/package.deb".to_string(),
+        digest: Digest {
+            algorithm: HashAlgorithm::Sha256,
+            hex: sha256.clone(),
+        },
+    };
+    assert_eq!(resolve_resource_uri(&uri).unwrap(), (expected, None));
+
+    let blake2b = "ABC0".repeat(32);
+    let uri = format!("file::blake2b={}::https://example.com/package.deb", blake2b);
+    let expected = AprilResourceType::External {
+        url: "https://example.com/package.deb".to_string(),
+        digest: Digest {
+            algorithm: HashAlgorithm::Blake2b,
+            hex: blake2b,
+        },
+    };
+    assert_eq!(resolve_resource_uri(&uri).unwrap(), (expected, None));
+    assert!(resolve_resource_uri("file::md5=abc::https://example.com/package.deb").is_err());
+    assert!(resolve_resource_uri("file::https://example.com/package.deb").is_err());
+
+    let uri = "file::data:application/octet-stream;base64,SGVsbG8sIHdvcmxkIQ==".to_string();
+    let expected = AprilResourceType::Inline {
+        content: (&b"Hello, world!"[..]).to_vec(),
+    };
+    assert_eq!(resolve_resource_uri(&uri).unwrap(), (expected, None));
+
+    let uri = format!("file::sha256={}::bundle:patches/fix%20foo.patch", sha256);
+    let expected = AprilResourceType::Bundled {
+        entry: "patches/fix foo.patch".to_string(),
+        digest: Digest {
+            algorithm: HashAlgorithm::Sha256,
+            hex: sha256.clone(),
+        },
+    };
+    assert_eq!(resolve_resource_uri(&uri).unwrap(), (expected, None));
+
+    let uri = format!(
+        "file::compression=xz;sha256={}::https://example.com/foo.patch.xz",
+        sha256
+    );
+    let expected = AprilResourceType::External {
+        url: "https://example.com/foo.patch.xz".to_string(),
+        digest: Digest {
+            algorithm: HashAlgorithm::Sha256,
+            hex: sha256.clone(),
+        },
+    };
+    assert_eq!(
+        resolve_resource_uri(&uri).unwrap(),
+        (expected, Some(Compression::Xz))
+    );
+    assert!(resolve_resource_uri("file::compression=lz4::foo.patch.lz4").is_err());
+}
+
+#[test]
+fn test_resolve_malformed_digest() {
+    let resolve = |digest: &str| {
+        resolve_resource_uri(&format!(
+            "file::{}::https://example.com/package.deb",
+            digest
+        ))
+    };
+    // too short
+    let error = resolve("sha256=abc").unwrap_err();
+    assert!(matches!(error, AprilError::InvalidResourceUri(_)));
+    assert!(error.to_string().contains("expected 64 hex digits"));
+    // too long
+    assert!(resolve(&format!("sha256={}", "0".repeat(65))).is_err());
+    // not hex
+    assert!(resolve(&format!("sha256={}", "g".repeat(64))).is_err());
+    // the length depends on the algorithm
+    assert!(resolve(&format!("sha1={}", "0".repeat(64))).is_err());
+    resolve(&format!("sha1={}", "0".repeat(40))).unwrap();
+    resolve(&format!("sha512={}", "0".repeat(128))).unwrap();
+}
+
+#[test]
+fn test_check_action_resources() {
+    let base_dir = tempfile::tempdir().unwrap();
+    std::fs::write(base_dir.path().join("foo.patch"), b"foo").unwrap();
+    let options = ReconstructOptions {
+        resource_base_dir: Some(base_dir.path().to_path_buf()),
+        ..Default::default()
+    };
+    let add = |uri: String| {
+        AprilAction::patch_file("/usr/share/foo/foo", AprilFileOperationType::Add(uri))
+    };
+    let sha256 = HashAlgorithm::Sha256.hash(b"foo");
+    let mut actions = vec![
+        AprilAction::UnpackPackage,
+        add(format!("file::sha256={}::foo.patch", sha256)),
+        add("file::data:,foo".to_string()),
+        // not fetched
+        add(format!("file::sha256={}::https://127.0.0.1:1/foo", sha256)),
+    ];
+    check_action_resources(&actions, &options).unwrap();
+
+    actions.extend([
+        add("file::missing.patch".to_string()),
+        add(format!("file::sha256={}::foo.patch", "0".repeat(64))),
+        add(format!("file::sha256={}::http://127.0.0.1:1/foo", sha256)),
+        add("file::sha256=abc::https://127.0.0.1:1/foo".to_string()),
+    ]);
+    let Err(AprilError::InvalidConfig(report)) = check_action_resources(&actions, &options) else {
+        panic!("expected a validation report");
+    };
+    assert_eq!(report.0.len(), 4);
+    assert!(
+        report.0[0]
+            .starts_with("Invalid resource for 'add /usr/share/foo/foo from file::missing.patch'")
+    );
+}
+
+#[test]
+fn test_external_resources() {
+    let foo = HashAlgorithm::Sha256.hash(b"foo");
+    let bar = HashAlgorithm::Blake2b.hash(b"bar");
+    let input = format!(
+        r#"{{
+            "schema": "0",
+            "name": "foo",
+            "compatible_versions": "*",
+            "overrides": {{}},
+            "files": {{
+                "/usr/bin/foo": [
+                    {{ "action": "patch", "arg": "file::sha256={foo}::https://example.com/foo-${{VERSION}}.patch" }},
+                    {{ "action": "binary-patch", "arg": "file::blake2b={bar}::https://example.com/bar.xdelta" }}
+                ],
+                "/usr/bin/baz": {{ "action": "patch", "arg": "file::sha256={foo}::https://example.com/foo-${{VERSION}}.patch" }},
+                "/usr/share/foo/README": {{ "action": "add", "arg": "file::data:,foo" }}
+            }}
+        }}"#
+    );
+    let mut data: crate::april::AprilPackage = serde_json::from_str(&input).unwrap();
+    crate::april::substitute_placeholders(&mut data, "1.0", |_| None).unwrap();
+    let actions = crate::april::plan_actions_from_april_data(&data).unwrap();
+
+    assert_eq!(
+        external_resources(&actions).unwrap(),
+        [
+            ExternalResource {
+                url: "https://example.com/foo-1.0.patch".to_string(),
+                algorithm: "sha256",
+                digest: foo,
+            },
+            ExternalResource {
+                url: "https://example.com/bar.xdelta".to_string(),
+                algorithm: "blake2b",
+                digest: bar,
+            },
+        ]
+    );
+
+    // every invalid resource URI is reported
+    let invalid = |uri: &str| {
+        AprilAction::patch_file(
+            "/usr/bin/foo",
+            AprilFileOperationType::Patch(uri.to_string()),
+        )
+    };
+    let error = external_resources(&[invalid("file::sha256=::"), invalid("file::md5=::")])
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("file::sha256=::") && error.contains("file::md5=::"));
+}
+
+#[test]
+fn test_signature_verification_failure_aborts() {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    struct StubVerifier(Arc<AtomicUsize>);
+
+    impl SignatureVerifier for StubVerifier {
+        fn verify(&self, _deb_path: &Path) -> Result<()> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Err(AprilError::SignatureVerification(
+                "bad signature".to_string(),
+            ))
+        }
+    }
+
+    let invoked = Arc::new(AtomicUsize::new(0));
+    let options = ReconstructOptions {
+        signature_verifier: Some(Box::new(StubVerifier(invoked.clone()))),
+        ..Default::default()
+    };
+    let dir = tempfile::tempdir().unwrap();
+    let err = apply_actions_for_reconstruct(dir.path().join("foo.deb"), &[], &options).unwrap_err();
+    assert_eq!(err.to_string(), "bad signature");
+    assert_eq!(invoked.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_link_file_operation() {
+    let root = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(root.path().join("usr/lib")).unwrap();
+    std::fs::write(root.path().join("usr/lib/libfoo.so.1"), b"").unwrap();
+
+    apply_file_operation(
+        root.path(),
+        "usr/lib/libfoo.so.1",
+        &AprilFileOperationType::Link("usr/lib/libfoo.so".to_string()),
+        &ReconstructOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        std::fs::read_link(root.path().join("usr/lib/libfoo.so")).unwrap(),
+        Path::new("/usr/lib/libfoo.so.1")
+    );
+}
+
+#[test]
+fn test_chmod_file_operation() {
+    let root = tempfile::tempdir().unwrap();
+    let file_path = root.path().join("foo");
+    std::fs::write(&file_path, b"").unwrap();
+    std::fs::set_permissions(&file_path, Permissions::from_mode(0o600)).unwrap();
+
+    apply_file_operation(
+        root.path(),
+        "foo",
+        &AprilFileOperationType::Chmod(0o755),
+        &ReconstructOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        std::fs::metadata(&file_path).unwrap().permissions().mode() & 0o7777,
+        0o755
+    );
+}
+
+#[test]
+fn test_append_content_file_operation() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().canonicalize().unwrap();
+    std::fs::write(root.join("foo.conf"), "foo=1\n").unwrap();
+    std::fs::set_permissions(root.join("foo.conf"), Permissions::from_mode(0o640)).unwrap();
+    let options = ReconstructOptions::default();
+    let append = |path: &str| {
+        apply_file_operation(
+            &root,
+            path,
+            &AprilFileOperationType::AppendContent("file::data:,bar=1%0A".to_string()),
+            &options,
+        )
+    };
+
+    append("/foo.conf").unwrap();
+    assert_eq!(
+        std::fs::read_to_string(root.join("foo.conf")).unwrap(),
+        "foo=1\nbar=1\n"
+    );
+    let metadata = std::fs::metadata(root.join("foo.conf")).unwrap();
+    assert_eq!(metadata.mode() & 0o777, 0o640);
+
+    // missing files (and their parents) are created
+    append("/foo.d/bar.conf").unwrap();
+    assert_eq!(
+        std::fs::read_to_string(root.join("foo.d/bar.conf")).unwrap(),
+        "bar=1\n"
+    );
+    let metadata = std::fs::metadata(root.join("foo.d/bar.conf")).unwrap();
+    assert_eq!(metadata.mode() & 0o777, 0o644);
+}
+
+#[test]
+fn test_truncate_file_operation() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().canonicalize().unwrap();
+    std::fs::write(root.join("foo.conf"), "foo=1\n").unwrap();
+    std::fs::set_permissions(root.join("foo.conf"), Permissions::from_mode(0o640)).unwrap();
+    let options = ReconstructOptions::default();
+    apply_file_operation(
+        &root,
+        "/foo.conf",
+        &AprilFileOperationType::Truncate,
+        &options,
+    )
+    .unwrap();
+    let metadata = std::fs::metadata(root.join("foo.conf")).unwrap();
+    assert_eq!(metadata.len(), 0);
+    assert_eq!(metadata.mode() & 0o777, 0o640);
+
+    assert!(
+        apply_file_operation(
+            &root,
+            "/missing",
+            &AprilFileOperationType::Truncate,
+            &options
+        )
+        .is_err()
+    );
+}
+
+#[test]
+fn test_chown_file_operation() {
+    let root = tempfile::tempdir().unwrap();
+    let file_path = root.path().join("foo");
+    std::fs::write(&file_path, b"").unwrap();
+    let metadata = std::fs::metadata(&file_path).unwrap();
+
+    // changing to the current owner is always permitted
+    let chown = |uid, gid| {
+        apply_file_operation(
+            root.path(),
+            "foo",
+            &AprilFileOperationType::Chown { uid, gid },
+            &ReconstructOptions::default(),
+        )
+    };
+    chown(metadata.uid(), metadata.gid()).unwrap();
+    if metadata.uid() == 0 {
+        chown(4242, 4343).unwrap();
+        let metadata = std::fs::metadata(&file_path).unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), (4242, 4343));
+    } else {
+        assert!(matches!(
+            chown(0, 0).unwrap_err(),
+            AprilError::ChownNotPermitted { uid: 0, gid: 0, .. }
+        ));
+    }
+}
+
+#[cfg(test)]
+fn build_test_package(dir: &Path) -> PathBuf {
+    build_test_package_with(dir, |_| ())
+}
+
+/// Create the package root `pkgroot` in `dir` with the control file
+#[cfg(test)]
+fn create_test_tree(dir: &Path, control: &str) -> PathBuf {
+    let root = dir.join("pkgroot");
+    std::fs::create_dir_all(root.join("DEBIAN")).unwrap();
+    std::fs::write(root.join("DEBIAN/control"), control).unwrap();
+    root
+}
+
+/// Build the test package, `setup` can add more files to the package root before it is built
+#[cfg(test)]
+fn build_test_package_with(dir: &Path, setup: impl FnOnce(&Path)) -> PathBuf {
+    let root = create_test_tree(
+        dir,
+        "Package: foo\nVersion: 1.0\nArchitecture: all\nMaintainer: Foo <foo@example.com>\nDescription: foo\n",
+    );
+    std::fs::create_dir_all(root.join("usr/share/foo")).unwrap();
+    std::fs::write(root.join("usr/share/foo/data"), "hello world\n").unwrap();
+    setup(&root);
+    let deb_path = dir.join("foo.deb");
+    let status = Command::new("dpkg-deb")
+        .arg("--root-owner-group")
+        .arg("-b")
+        .arg(&root)
+        .arg(&deb_path)
+        .output()
+        .unwrap()
+        .status;
+    assert!(status.success());
+    std::fs::remove_dir_all(root).unwrap();
+
+    deb_path
+}
+
+#[test]
+fn test_apply_actions_for_reconstruct_with() {
+    let dir = tempfile::tempdir().unwrap();
+    let deb_path = build_test_package(dir.path());
+    let actions = [AprilAction::PatchField {
+        field: Cow::Borrowed("Depends"),
+        value: "bar (>= 1.0)".to_string(),
+        action: AprilFieldActionType::Append,
+        package: None,
+    }];
+    let control_data = apply_actions_for_reconstruct_with(
+        &deb_path,
+        &actions,
+        &ReconstructOptions::default(),
+        |control_data| {
+            let mut paragraph = control_data.paragraphs().next().unwrap();
+            assert_eq!(paragraph.get("Depends").unwrap(), "bar (>= 1.0)");
+            paragraph.set("Description", "foo (reconstructed)");
+            Ok(())
+        },
+    )
+    .unwrap()
+    .unwrap();
+    let paragraph = control_data.paragraphs().next().unwrap();
+    assert_eq!(paragraph.get("Depends").unwrap(), "bar (>= 1.0)");
+    let output = repacked_package_path(&deb_path);
+    assert_eq!(
+        read_control_field(&output, "Description").unwrap().unwrap(),
+        "foo (reconstructed)"
+    );
+
+    let options = ReconstructOptions {
+        dry_run: true,
+        ..Default::default()
+    };
+    let control_data =
+        apply_actions_for_reconstruct_with(&deb_path, &actions, &options, |_| unreachable!());
+    assert!(control_data.unwrap().is_none());
+}
+
+#[test]
+fn test_temp_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    let deb_path = build_test_package(dir.path());
+    let temp_dir = dir.path().join("tmp");
+    std::fs::create_dir(&temp_dir).unwrap();
+    let options = ReconstructOptions {
+        temp_dir: Some(temp_dir.clone()),
+        keep_temp: true,
+        ..Default::default()
+    };
+    apply_actions_for_reconstruct(&deb_path, &[], &options).unwrap();
+    let extracted = std::fs::read_dir(&temp_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    assert_eq!(extracted.len(), 1);
+    assert!(extracted[0].join("DEBIAN/control").is_file());
+    assert!(repacked_package_path(&deb_path).is_file());
+
+    let package_dir = Path::new("/srv/packages");
+    let tmpdir = || Some(OsString::from("/var/tmp"));
+    let default = ReconstructOptions::default();
+    assert_eq!(
+        extraction_parent_dir(package_dir, &options, tmpdir()),
+        temp_dir
+    );
+    assert_eq!(
+        extraction_parent_dir(package_dir, &default, tmpdir()),
+        Path::new("/var/tmp")
+    );
+    assert_eq!(
+        extraction_parent_dir(package_dir, &default, Some(OsString::new())),
+        package_dir
+    );
+    assert_eq!(
+        extraction_parent_dir(package_dir, &default, None),
+        package_dir
+    );
+}
+
+#[test]
+fn test_read_package_version() {
+    let dir = tempfile::tempdir().unwrap();
+    let deb_path = build_test_package(dir.path());
+    let version = read_package_version(&deb_path).unwrap();
+    assert_eq!(version, "1.0");
+    // the version read from the package drives the configuration selection
+    assert!(
+        crate::april_version::check_version_compatibility(
+            ">= 1.0 && < 2.0",
+            &version,
+            Some(&deb_path)
+        )
+        .unwrap()
+    );
+    assert!(matches!(
+        read_package_version(dir.path().join("missing.deb")),
+        Err(AprilError::DpkgCommandFailed { .. })
+    ));
+}
+
+#[test]
+fn test_read_control_field() {
+    let dir = tempfile::tempdir().unwrap();
+    let deb_path = build_test_package(dir.path());
+    assert_eq!(
+        read_control_field(&deb_path, "Maintainer")
+            .unwrap()
+            .as_deref(),
+        Some("Foo <foo@example.com>")
+    );
+    // field names are case-insensitive
+    assert_eq!(
+        read_control_field(&deb_path, "architecture")
+            .unwrap()
+            .as_deref(),
+        Some("all")
+    );
+    assert_eq!(read_control_field(&deb_path, "Depends").unwrap(), None);
+    assert!(matches!(
+        read_control_field(dir.path().join("missing.deb"), "Depends"),
+        Err(AprilError::DpkgCommandFailed { .. })
+    ));
+}
+
+#[test]
+fn test_expected_input_digest() {
+    let dir = tempfile::tempdir().unwrap();
+    let deb_path = build_test_package(dir.path());
+    let output = dir.path().join("output.deb");
+    let digest = HashAlgorithm::Sha256.hash(&std::fs::read(&deb_path).unwrap());
+    let options = ReconstructOptions {
+        output: Some(output.clone()),
+        expected_sha256: Some(digest.to_uppercase()),
+        ..Default::default()
+    };
+    apply_actions_for_reconstruct(&deb_path, &[], &options).unwrap();
+    assert!(output.exists());
+    std::fs::remove_file(&output).unwrap();
+
+    let options = ReconstructOptions {
+        output: Some(output.clone()),
+        expected_sha256: Some("0".repeat(64)),
+        ..Default::default()
+    };
+    let error = apply_actions_for_reconstruct(&deb_path, &[], &options).unwrap_err();
+    assert!(matches!(
+        error,
+        AprilError::ResourceDigestMismatch { actual, .. } if actual == digest
+    ));
+    assert!(!output.exists());
+}
+
+#[test]
+fn test_apply_actions_to_tree() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = create_test_tree(
+        dir.path(),
+        "Package: foo\nVersion: 1.0\nArchitecture: all\nDepends: bar\n",
+    );
+    std::fs::create_dir_all(root.join("usr/share/foo")).unwrap();
+    std::fs::write(root.join("usr/share/foo/data"), "hello world\n").unwrap();
+    assert_eq!(read_tree_package_version(&root).unwrap(), "1.0");
+
+    let actions = [
+        AprilAction::PatchField {
+            field: Cow::Borrowed("Depends"),
+            value: "baz (>= 1.0)".to_string(),
+            action: AprilFieldActionType::Append,
+            package: None,
+        },
+        AprilAction::patch_file(
+            "/usr/share/foo/data",
+            AprilFileOperationType::Move("/usr/share/foo/data.txt".to_string()),
+        ),
+    ];
+    apply_actions_to_tree(&root, &actions, &ReconstructOptions::default()).unwrap();
+    let control = std::fs::read_to_string(root.join("DEBIAN/control")).unwrap();
+    assert!(control.contains("Depends: bar, baz (>= 1.0)\n"));
+    assert!(root.join("usr/share/foo/data.txt").exists());
+    assert!(!root.join("usr/share/foo/data").exists());
+
+    // the options about the package file are rejected, even in dry-run mode
+    let options = ReconstructOptions {
+        dry_run: true,
+        output: Some(dir.path().join("foo.deb")),
+        ..Default::default()
+    };
+    assert!(matches!(
+        apply_actions_to_tree(&root, &actions, &options),
+        Err(AprilError::InvalidOption(_))
+    ));
+}
+
+#[test]
+fn test_verify_applied_actions() {
+    let dir = tempfile::tempdir().unwrap();
+    let control = "Package: foo\nVersion: 1.0\nArchitecture: all\nDepends: bar\n";
+    let root = create_test_tree(dir.path(), control);
+    let reset = || {
+        std::fs::write(root.join("DEBIAN/control"), control).unwrap();
+        std::fs::write(root.join("DEBIAN/postinst"), "#!/bin/sh\n").unwrap();
+    };
+    let options = ReconstructOptions {
+        verify: true,
+        ..Default::default()
+    };
+    let patch_field = |value: &str, action| AprilAction::PatchField {
+        field: Cow::Borrowed("Depends"),
+        value: value.to_string(),
+        action,
+        package: None,
+    };
+
+    reset();
+    let actions = [
+        patch_field("bar", AprilFieldActionType::Remove),
+        patch_field("baz (>= 1.0)", AprilFieldActionType::Append),
+        AprilAction::PatchScript {
+            file: "postinst",
+            content: Some("ldconfig\n".to_string()),
+            action: AprilActionType::Append,
+        },
+    ];
+    apply_actions_to_tree(&root, &actions, &options).unwrap();
+
+    // replacing the field overrides the earlier actions on it
+    reset();
+    let actions = [
+        patch_field("baz", AprilFieldActionType::Append),
+        patch_field("qux", AprilFieldActionType::Replace),
+        patch_field("quux", AprilFieldActionType::Append),
+    ];
+    apply_actions_to_tree(&root, &actions, &options).unwrap();
+
+    // a merged relation may be kept out by a stricter one
+    reset();
+    let actions = [
+        patch_field("bar (>= 1.0)", AprilFieldActionType::Merge),
+        patch_field("bar (>= 0.5)", AprilFieldActionType::Merge),
+    ];
+    apply_actions_to_tree(&root, &actions, &options).unwrap();
+
+    // the removed package is added back with a version constraint
+    reset();
+    let actions = [
+        patch_field("bar", AprilFieldActionType::Remove),
+        patch_field("bar (>= 2)", AprilFieldActionType::Append),
+    ];
+    apply_actions_to_tree(&root, &actions, &options).unwrap();
+
+    // the appended relation is removed again by a later action
+    reset();
+    let actions = [
+        patch_field("baz (>= 1.0)", AprilFieldActionType::Append),
+        patch_field("baz", AprilFieldActionType::Remove),
+    ];
+    let error = apply_actions_to_tree(&root, &actions, &options).unwrap_err();
+    let AprilError::VerificationFailed(failures) = error else {
+        panic!("unexpected error: {}", error);
+    };
+    assert_eq!(
+        failures,
+        [r#"field Depends expected to contain "baz (>= 1.0)", but it is "bar""#]
+    );
+    assert_eq!(
+        std::fs::read_to_string(root.join("DEBIAN/control")).unwrap(),
+        "Package: foo\nVersion: 1.0\nArchitecture: all\nDepends: bar\n"
+    );
+    // the same actions are applied without complaints if not verifying
+    reset();
+    apply_actions_to_tree(&root, &actions, &ReconstructOptions::default()).unwrap();
+}
+
+#[test]
+fn test_control_file_trailing_newline() {
+    let dir = tempfile::tempdir().unwrap();
+    let actions = [AprilAction::PatchField {
+        field: Cow::Borrowed("Depends"),
+        value: "bar".to_string(),
+        action: AprilFieldActionType::Append,
+        package: None,
+    }];
+    for control in [
+        "Package: foo\nVersion: 1.0\nArchitecture: all",
+        "Package: foo\nVersion: 1.0\nArchitecture: all\n\n\n",
+    ] {
+        let root = create_test_tree(dir.path(), control);
+        apply_actions_to_tree(&root, &actions, &ReconstructOptions::default()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("DEBIAN/control")).unwrap(),
+            "Package: foo\nVersion: 1.0\nArchitecture: all\nDepends: bar\n"
+        );
+    }
+}
+
+#[test]
+fn test_conffile_edits() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    std::fs::create_dir_all(root.join("DEBIAN")).unwrap();
+    std::fs::create_dir_all(root.join("etc/foo")).unwrap();
+    std::fs::write(root.join("etc/foo/foo.conf"), "foo = 1\n").unwrap();
+    // the conffile to be removed on upgrade is no longer in the package
+    std::fs::write(
+        root.join("DEBIAN/conffiles"),
+        "/etc/foo/foo.conf\nremove-on-upgrade /etc/foo/old.conf\n",
+    )
+    .unwrap();
+    let patch_file = |path: &str, action| AprilAction::patch_file(path, action);
+    let options = ReconstructOptions {
+        deny_conffile_edits: true,
+        ..Default::default()
+    };
+
+    let actions = [
+        patch_file(
+            "/etc/foo/foo.conf",
+            AprilFileOperationType::Substitute {
+                pattern: "foo".to_string(),
+                replacement: "bar".to_string(),
+                regex: false,
+            },
+        ),
+        patch_file("/etc/foo/*.conf", AprilFileOperationType::Chmod(0o600)),
+        patch_file("/usr/bin/foo", AprilFileOperationType::Chmod(0o755)),
+    ];
+    let AprilError::ConffileEdits(edits) =
+        check_conffile_edits(root, &actions, &options).unwrap_err()
+    else {
+        unreachable!();
+    };
+    assert_eq!(
+        edits,
+        [
+            "'substitute 'foo' with 'bar' in /etc/foo/foo.conf' modifies conffile /etc/foo/foo.conf",
+            "'chmod 600 /etc/foo/*.conf' modifies conffile /etc/foo/foo.conf",
+        ]
+    );
+    // only warned about by default
+    assert!(check_conffile_edits(root, &actions, &ReconstructOptions::default()).is_ok());
+
+    // removing the parent directory also removes the conffile, while the conffiles added by the
+    // override can be created
+    let actions = [
+        AprilAction::PatchScript {
+            file: "conffiles",
+            content: Some("/etc/bar.conf".to_string()),
+            action: AprilActionType::Replace,
+        },
+        patch_file("/etc/foo", AprilFileOperationType::Remove),
+        patch_file(
+            "/usr/share/foo/bar.conf",
+            AprilFileOperationType::Copy("/etc/bar.conf".to_string()),
+        ),
+    ];
+    let AprilError::ConffileEdits(edits) =
+        check_conffile_edits(root, &actions, &options).unwrap_err()
+    else {
+        unreachable!();
+    };
+    assert_eq!(
+        edits,
+        ["'remove /etc/foo' modifies conffile /etc/foo/foo.conf"]
+    );
+}
+
+#[test]
+fn test_change_manifest() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = create_test_tree(
+        dir.path(),
+        "Package: foo\nVersion: 1.0\nHomepage: http://example.com\n",
+    );
+    std::fs::create_dir_all(root.join("usr/share/doc/foo")).unwrap();
+    std::fs::write(root.join("usr/share/doc/foo/README"), "foo\n").unwrap();
+
+    let actions = [
+        AprilAction::PatchField {
+            field: Cow::Borrowed("Homepage"),
+            value: "https://example.com".to_string(),
+            action: AprilFieldActionType::Replace,
+            package: None,
+        },
+        AprilAction::patch_file("/usr/share/doc/foo/README", AprilFileOperationType::Remove),
+        AprilAction::patch_file(
+            "/usr/share/doc/foo/NEWS",
+            AprilFileOperationType::Add("file::data:,news".to_string()),
+        ),
+        // the unpinned resource is not read again for the manifest, as nothing uses it
+        AprilAction::PatchFile {
+            path: "/usr/share/doc/foo/*.txt".to_string(),
+            action: AprilFileOperationType::AppendContent("file::missing.txt".to_string()),
+            allow_empty: true,
+            optional: false,
+        },
+    ];
+    let manifest_path = dir.path().join("manifest.json");
+    let options = ReconstructOptions {
+        manifest: Some(manifest_path.clone()),
+        resource_base_dir: Some(dir.path().to_path_buf()),
+        ..Default::default()
+    };
+    apply_actions_to_tree(&root, &actions, &options).unwrap();
+
+    let manifest: serde_json::Value =
+        serde_json::from_reader(File::open(&manifest_path).unwrap()).unwrap();
+    assert_eq!(
+        manifest["fields"],
+        serde_json::json!([{
+            "package": "foo",
+            "field": "Homepage",
+            "before": "http://example.com",
+            "after": "https://example.com",
+        }])
+    );
+    let removal = &manifest["actions"][1];
+    assert_eq!(removal["action"]["type"], "patch_file");
+    assert_eq!(
+        removal["files"],
+        serde_json::json!(["/usr/share/doc/foo/README"])
+    );
+    let addition = &manifest["actions"][2];
+    assert_eq!(
+        addition["resources"][0]["digest"],
+        HashAlgorithm::Sha256.hash(b"news")
+    );
+    assert!(manifest["actions"][3].get("resources").is_none());
+}
+
+#[test]
+fn test_set_exact_relationship_field() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = create_test_tree(
+        dir.path(),
+        "Package: foo\nVersion: 1.0\nDepends: libbar, libc6 (>= 2.17)\nRecommends: baz\n",
+    );
+    let data: crate::april::AprilPackage = serde_json::from_str(
+        r#"{
+            "schema": "0",
+            "name": "foo",
+            "compatible_versions": "*",
+            "overrides": {
+                "depends": ["=libfoo (>= 1)", "=libc6", "=libfoo (>= 1)"],
+                "recommends": ["=qux|quux", "+baz"]
+            }
+        }"#,
+    )
+    .unwrap();
+    crate::april::validate_april_data(&data).unwrap();
+    let actions = crate::april::plan_actions_from_april_data(&data).unwrap();
+    apply_actions_to_tree(&root, &actions, &ReconstructOptions::default()).unwrap();
+    let control_data = Deb822::from_file(root.join("DEBIAN/control")).unwrap();
+    let paragraph = control_data.paragraphs().next().unwrap();
+    assert_eq!(paragraph.get("Depends").unwrap(), "libfoo (>= 1), libc6");
+    assert_eq!(paragraph.get("Recommends").unwrap(), "qux | quux, baz");
+}
+
+#[test]
+fn test_layered_configurations() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = create_test_tree(
+        dir.path(),
+        "Package: foo\nVersion: 1.0\nDepends: libbar, libc6 (>= 2.17)\n",
+    );
+    let config = |overrides: &str| -> crate::april::AprilPackage {
+        serde_json::from_str(&format!(
+            r#"{{ "schema": "0", "name": "foo", "compatible_versions": "*", "overrides": {} }}"#,
+            overrides
+        ))
+        .unwrap()
+    };
+    let base = config(r#"{ "depends": ["-libbar", "+libbaz (>= 1.0)"], "version": "1.0-1" }"#);
+    // the site configuration sees the dependency added by the base configuration
+    let site = config(r#"{ "depends": ["+libbaz (>= 2.0)", "+libqux"], "merge_relations": true }"#);
+    let plans = [base, site]
+        .iter()
+        .map(|data| crate::april::plan_actions_from_april_data(data).unwrap())
+        .collect();
+    let actions = crate::april::layer_planned_actions(plans);
+    assert_eq!(
+        actions
+            .iter()
+            .filter(|action| matches!(action, AprilAction::ExtractPackage))
+            .count(),
+        1
+    );
+    apply_actions_to_tree(&root, &actions, &ReconstructOptions::default()).unwrap();
+    let control_data = Deb822::from_file(root.join("DEBIAN/control")).unwrap();
+    let paragraph = control_data.paragraphs().next().unwrap();
+    assert_eq!(
+        paragraph.get("Depends").unwrap(),
+        "libc6 (>= 2.17), libbaz (>= 2.0), libqux"
+    );
+    assert_eq!(paragraph.get("Version").unwrap(), "1.0-1");
+}
+
+#[test]
+fn test_max_output_size() {
+    let dir = tempfile::tempdir().unwrap();
+    let deb_path = build_test_package(dir.path());
+    let options = ReconstructOptions {
+        max_output_size: Some(1),
+        ..Default::default()
+    };
+    assert!(apply_actions_for_reconstruct(&deb_path, &[], &options).is_err());
+    // only the original package should be left behind
+    let entries = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    assert_eq!(entries, vec![deb_path.clone()]);
+
+    let options = ReconstructOptions {
+        max_output_size: Some(1024 * 1024),
+        ..Default::default()
+    };
+    apply_actions_for_reconstruct(&deb_path, &[], &options).unwrap();
+    assert!(repacked_package_path(&deb_path).exists());
+}
+
+#[test]
+fn test_copy_preserves_mode() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("secret"), b"secret").unwrap();
+    std::fs::set_permissions(dir.path().join("secret"), Permissions::from_mode(0o600)).unwrap();
+
+    let operation = AprilFileOperationType::Copy("/etc/secret".to_string());
+    apply_file_operation(
+        dir.path(),
+        "secret",
+        &operation,
+        &ReconstructOptions::default(),
+    )
+    .unwrap();
+    let metadata = std::fs::metadata(dir.path().join("etc/secret")).unwrap();
+    assert_eq!(metadata.mode() & 0o7777, 0o600);
+    assert_eq!(
+        std::fs::read(dir.path().join("etc/secret")).unwrap(),
+        b"secret"
+    );
+}
+
+#[test]
+fn test_copy_remove_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    let src = dir.path().join("opt/foo");
+    std::fs::create_dir_all(src.join("lib/plugins")).unwrap();
+    std::fs::write(src.join("lib/libfoo.so"), b"foo").unwrap();
+    std::fs::write(src.join("lib/plugins/bar.so"), b"bar").unwrap();
+    std::fs::set_permissions(src.join("lib/plugins"), Permissions::from_mode(0o750)).unwrap();
+    std::fs::set_permissions(src.join("lib/libfoo.so"), Permissions::from_mode(0o755)).unwrap();
+    std::os::unix::fs::symlink("libfoo.so", src.join("lib/libfoo.so.1")).unwrap();
+
+    let copy = |dst: &str| {
+        apply_file_operation(
+            dir.path(),
+            "/opt/foo",
+            &AprilFileOperationType::Copy(dst.to_string()),
+            &ReconstructOptions::default(),
+        )
+    };
+    copy("/usr/share/foo").unwrap();
+    let dst = dir.path().join("usr/share/foo");
+    assert_eq!(
+        std::fs::read(dst.join("lib/plugins/bar.so")).unwrap(),
+        b"bar"
+    );
+    let mode = |path: &str| std::fs::metadata(dst.join(path)).unwrap().mode() & 0o7777;
+    assert_eq!(mode("lib/plugins"), 0o750);
+    assert_eq!(mode("lib/libfoo.so"), 0o755);
+    assert_eq!(
+        std::fs::read_link(dst.join("lib/libfoo.so.1")).unwrap(),
+        Path::new("libfoo.so")
+    );
+    assert!(matches!(
+        copy("/opt/foo/lib/foo").unwrap_err(),
+        AprilError::InvalidAction(_)
+    ));
+
+    apply_file_operation(
+        dir.path(),
+        "/opt/foo",
+        &AprilFileOperationType::Remove,
+        &ReconstructOptions::default(),
+    )
+    .unwrap();
+    assert!(!src.exists());
+    assert!(dst.join("lib/libfoo.so").exists());
+}
+
+#[test]
+fn test_glob_match() {
+    let matches = |pattern: &str, name: &str| {
+        glob_match_component(
+            &pattern.chars().collect::<Vec<_>>(),
+            &name.chars().collect::<Vec<_>>(),
+        )
+    };
+    assert!(matches("*.so", "libfoo.so"));
+    assert!(!matches("*.so", "libfoo.so.1"));
+    assert!(matches("*.so*", "libfoo.so.1"));
+    assert!(matches("lib?oo.so", "libfoo.so"));
+    assert!(matches("lib[a-f]oo.so", "libfoo.so"));
+    assert!(!matches("lib[!a-f]oo.so", "libfoo.so"));
+    assert!(matches("lib[foo.so", "lib[foo.so"));
+    assert!(!matches("*", ".hidden"));
+    assert!(matches(".*", ".hidden"));
+    assert!(matches("a*b*c", "aXbYbZc"));
+    assert!(!matches("a*b*c", "aXbYbZ"));
+    assert!(matches("lib[]]", "lib]"));
+    // escaped glob characters are matched literally
+    assert!(matches("foo\\[1\\].conf", "foo[1].conf"));
+    assert!(!matches("foo\\[1\\].conf", "foo1.conf"));
+    assert!(matches("\\*\\?", "*?"));
+    assert!(!matches("\\*", "foo"));
+    assert_eq!(glob_literal("foo\\[1\\]"), Some("foo[1]".to_string()));
+    assert_eq!(glob_literal("foo[1]"), None);
+    // a pattern with many `*` does not backtrack exponentially
+    let pattern = "a*".repeat(30) + "b";
+    assert!(!matches(&pattern, &"a".repeat(100)));
+}
+
+#[test]
+fn test_optional_file_operation() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    std::fs::create_dir_all(root.join("usr/lib/foo")).unwrap();
+    std::fs::write(root.join("usr/lib/foo/libfoo.so"), "foo").unwrap();
+    let resource_dir = tempfile::tempdir().unwrap();
+    let options = ReconstructOptions {
+        resource_base_dir: Some(resource_dir.path().to_path_buf()),
+        ..Default::default()
+    };
+    let mut control_data = Deb822::new();
+    let mut apply = |path: &str, action, optional| {
+        let action = AprilAction::PatchFile {
+            path: path.to_string(),
+            action,
+            allow_empty: false,
+            optional,
+        };
+        apply_action(&action, root, &mut control_data, &options)
+    };
+
+    // the missing files are skipped
+    let files = apply(
+        "/usr/lib/foo/plugins/libbar.so",
+        AprilFileOperationType::Remove,
+        true,
+    )
+    .unwrap();
+    assert!(files.is_empty());
+    apply(
+        "/usr/lib/foo/plugins/libbar.so",
+        AprilFileOperationType::Chmod(0o644),
+        true,
+    )
+    .unwrap();
+    apply(
+        "/usr/lib/foo/plugins/*.so",
+        AprilFileOperationType::Remove,
+        true,
+    )
+    .unwrap();
+    assert!(
+        apply(
+            "/usr/lib/foo/plugins/libbar.so",
+            AprilFileOperationType::Remove,
+            false,
+        )
+        .is_err()
+    );
+
+    // other missing files are still errors
+    assert!(
+        apply(
+            "/usr/lib/foo/libfoo.so",
+            AprilFileOperationType::Patch("file::missing.patch".to_string()),
+            true,
+        )
+        .is_err()
+    );
+    apply(
+        "/usr/lib/foo/libfoo.so",
+        AprilFileOperationType::Remove,
+        true,
+    )
+    .unwrap();
+    assert!(!root.join("usr/lib/foo/libfoo.so").exists());
+}
+
+#[test]
+fn test_glob_file_operation() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    std::fs::create_dir_all(root.join("usr/lib/foo")).unwrap();
+    for name in ["libfoo.so", "libbar.so", "libfoo.so.1", "foo/libbaz.so"] {
+        std::fs::write(root.join("usr/lib").join(name), name).unwrap();
+    }
+
+    let mut control_data = Deb822::new();
+    let apply = |path: &str, action, allow_empty, control_data: &mut Deb822| {
+        let action = AprilAction::PatchFile {
+            path: path.to_string(),
+            action,
+            allow_empty,
+            optional: false,
+        };
+        apply_action(&action, root, control_data, &ReconstructOptions::default())
+    };
+    apply(
+        "/usr/lib/*.so",
+        AprilFileOperationType::Chmod(0o700),
+        false,
+        &mut control_data,
+    )
+    .unwrap();
+    let mode = |name: &str| {
+        std::fs::metadata(root.join("usr/lib").join(name))
+            .unwrap()
+            .mode()
+            & 0o777
+    };
+    assert_eq!(mode("libfoo.so"), 0o700);
+    assert_eq!(mode("libbar.so"), 0o700);
+    assert_ne!(mode("libfoo.so.1"), 0o700);
+    assert_ne!(mode("foo/libbaz.so"), 0o700);
+
+    apply(
+        "/usr/*/*/*.so",
+        AprilFileOperationType::Remove,
+        false,
+        &mut control_data,
+    )
+    .unwrap();
+    assert!(!root.join("usr/lib/foo/libbaz.so").exists());
+    assert!(root.join("usr/lib/libfoo.so").exists());
+
+    // the pattern has to match something, unless allowed
+    let error = apply(
+        "/usr/lib/*.a",
+        AprilFileOperationType::Remove,
+        false,
+        &mut control_data,
+    )
+    .unwrap_err();
+    assert!(matches!(error, AprilError::NoGlobMatch(_)));
+    apply(
+        "/usr/lib/*.a",
+        AprilFileOperationType::Remove,
+        true,
+        &mut control_data,
+    )
+    .unwrap();
+    assert!(matches!(
+        apply(
+            "/usr/../*",
+            AprilFileOperationType::Remove,
+            true,
+            &mut control_data,
+        )
+        .unwrap_err(),
+        AprilError::PathEscape(_)
+    ));
+
+    // the escaped glob characters are part of the file name
+    std::fs::write(root.join("usr/lib/lib[1].so"), "").unwrap();
+    std::fs::write(root.join("usr/lib/lib1.so"), "").unwrap();
+    let files = apply(
+        "/usr/lib/lib\\[1\\].so",
+        AprilFileOperationType::Remove,
+        false,
+        &mut control_data,
+    )
+    .unwrap();
+    assert_eq!(files, ["/usr/lib/lib[1].so"]);
+    assert!(root.join("usr/lib/lib1.so").exists());
+}
+
+#[test]
+fn test_substitute_file_operation() {
+    let dir = tempfile::tempdir().unwrap();
+    let file_path = dir.path().join("foo.conf");
+    std::fs::write(&file_path, "prefix=/usr/local\nlibdir=/usr/local/lib\n").unwrap();
+    std::fs::set_permissions(&file_path, Permissions::from_mode(0o640)).unwrap();
+    let substitute = |pattern: &str, replacement: &str, regex| {
+        apply_file_operation(
+            dir.path(),
+            "foo.conf",
+            &AprilFileOperationType::Substitute {
+                pattern: pattern.to_string(),
+                replacement: replacement.to_string(),
+                regex,
+            },
+            &ReconstructOptions::default(),
+        )
+    };
+
+    // `$` is not special in the literal mode
+    substitute("/usr/local", "/opt/$foo", false).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&file_path).unwrap(),
+        "prefix=/opt/$foo\nlibdir=/opt/$foo/lib\n"
+    );
+    substitute(r"(?m)^(\w+)=/opt/\$foo", "$1=/usr", true).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&file_path).unwrap(),
+        "prefix=/usr\nlibdir=/usr/lib\n"
+    );
+    assert_eq!(std::fs::metadata(&file_path).unwrap().mode() & 0o777, 0o640);
+
+    assert!(matches!(
+        substitute("/usr/local", "/usr", false).unwrap_err(),
+        AprilError::SubstitutionNoMatch { .. }
+    ));
+    assert!(matches!(
+        substitute("^bindir=", "", true).unwrap_err(),
+        AprilError::SubstitutionNoMatch { .. }
+    ));
+    assert!(matches!(
+        substitute("(", "", true).unwrap_err(),
+        AprilError::InvalidAction(_)
+    ));
+    assert_eq!(
+        std::fs::read_to_string(&file_path).unwrap(),
+        "prefix=/usr\nlibdir=/usr/lib\n"
+    );
+}
+
+#[test]
+fn test_check_required_tools() {
+    let dir = tempfile::tempdir().unwrap();
+    let bin = dir.path().join("bin");
+    std::fs::create_dir(&bin).unwrap();
+    for (tool, mode) in [("dpkg-deb", 0o755), ("patch", 0o644)] {
+        std::fs::write(bin.join(tool), b"").unwrap();
+        std::fs::set_permissions(bin.join(tool), Permissions::from_mode(mode)).unwrap();
+    }
+    let search_path = std::env::join_paths([dir.path().join("missing"), bin]).unwrap();
+    let patch = |action| AprilAction::patch_file("/usr/bin/foo", action);
+    let actions = [
+        patch(AprilFileOperationType::Patch("file::foo.patch".to_string())),
+        patch(AprilFileOperationType::BinaryPatch(
+            "file::foo.xdelta".to_string(),
+        )),
+    ];
+    let mut options = ReconstructOptions::default();
+
+    check_required_tools(&[], &options, true, Some(&search_path)).unwrap();
+    // `patch` is not executable
+    let error = check_required_tools(&actions, &options, true, Some(&search_path)).unwrap_err();
+    assert!(matches!(&error, AprilError::MissingTools(tools) if tools == &["patch", "xdelta3"]));
+    assert_eq!(
+        error.to_string(),
+        "Required programs not found in PATH: patch, xdelta3"
+    );
+    options.builtin_patch = true;
+    let error = check_required_tools(&actions, &options, true, Some(&search_path)).unwrap_err();
+    assert!(matches!(&error, AprilError::MissingTools(tools) if tools == &["xdelta3"]));
+    assert!(matches!(
+        check_required_tools(&[], &options, true, None).unwrap_err(),
+        AprilError::MissingTools(tools) if tools == ["dpkg-deb"]
+    ));
+    // applying to a package tree needs no repacking
+    check_required_tools(&[], &options, false, None).unwrap();
+    let error = check_required_tools(&actions, &options, false, None).unwrap_err();
+    assert!(matches!(&error, AprilError::MissingTools(tools) if tools == &["xdelta3"]));
+}
+
+#[test]
+fn test_repack_compression_args() {
+    let args = |compression: Option<&str>, compression_level| {
+        repack_compression_args(&ReconstructOptions {
+            compression: compression.map(str::to_string),
+            compression_level,
+            ..Default::default()
+        })
+    };
+    assert!(args(None, None).unwrap().is_empty());
+    assert_eq!(args(Some("zstd"), None).unwrap(), ["-Zzstd"]);
+    assert_eq!(args(Some("xz"), Some(9)).unwrap(), ["-Zxz", "-z9"]);
+    assert_eq!(args(Some("zstd"), Some(19)).unwrap(), ["-Zzstd", "-z19"]);
+    assert_eq!(args(None, Some(6)).unwrap(), ["-z6"]);
+    assert!(matches!(
+        args(Some("bzip2"), None).unwrap_err(),
+        AprilError::InvalidOption(_)
+    ));
+    assert!(matches!(
+        args(Some("gzip"), Some(10)).unwrap_err(),
+        AprilError::InvalidOption(_)
+    ));
+    assert!(args(Some("none"), Some(1)).is_err());
+}
+
+#[test]
+fn test_multiple_file_operations_on_path() {
+    let input = r#"{
+        "schema": "0",
+        "name": "foo",
+        "compatible_versions": "*",
+        "overrides": {},
+        "files": {
+            "/usr/share/foo/foo": [
+                { "action": "chmod", "arg": 488 },
+                { "action": "move", "arg": "/usr/bin/foo" }
+            ]
+        }
+    }"#;
+    let data: crate::april::AprilPackage = serde_json::from_str(input).unwrap();
+    let actions = crate::april::plan_actions_from_april_data(&data).unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(dir.path().join("usr/share/foo")).unwrap();
+    std::fs::write(dir.path().join("usr/share/foo/foo"), b"foo").unwrap();
+
+    let mut control_data = Deb822::new();
+    for action in actions
+        .iter()
+        .filter(|action| matches!(action, AprilAction::PatchFile { .. }))
+    {
+        apply_action(
+            action,
+            dir.path(),
+            &mut control_data,
+            &ReconstructOptions::default(),
+        )
+        .unwrap();
+    }
+    assert!(!dir.path().join("usr/share/foo/foo").exists());
+    let metadata = std::fs::metadata(dir.path().join("usr/bin/foo")).unwrap();
+    assert_eq!(metadata.mode() & 0o777, 0o750);
+}
+
+#[test]
+fn test_schedule_file_actions() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(dir.path().join("usr/lib")).unwrap();
+    std::os::unix::fs::symlink("usr/lib", dir.path().join("lib")).unwrap();
+    let patch = |path: &str, action| AprilAction::patch_file(path, action);
+    let actions = [
+        patch("/usr/bin/foo", AprilFileOperationType::Chmod(0o755)),
+        patch("/usr/bin/bar", AprilFileOperationType::Chmod(0o755)),
+        patch(
+            "/usr/bin/foo",
+            AprilFileOperationType::Move("/usr/share/foo".to_string()),
+        ),
+        patch("/usr/share/foo/foo", AprilFileOperationType::Chmod(0o644)),
+        patch("/usr/lib", AprilFileOperationType::Mkdir),
+        // the same as `/usr/lib/libfoo.so`
+        patch("/lib/libfoo.so", AprilFileOperationType::Remove),
+        patch("/usr/lib/*.a", AprilFileOperationType::Remove),
+        patch("/etc/foo", AprilFileOperationType::Remove),
+    ];
+    assert_eq!(
+        schedule_file_actions(dir.path(), &actions),
+        [0, 0, 1, 2, 0, 1, 3, 4]
+    );
+}
+
+#[test]
+fn test_concurrent_file_actions() {
+    let dir = tempfile::tempdir().unwrap();
+    let deb_path = build_test_package(dir.path());
+    let mut actions = (0..100)
+        .map(|i| {
+            AprilAction::patch_file(
+                format!("/usr/share/foo/{}", i),
+                AprilFileOperationType::Add("file::data:,foo".to_string()),
+            )
+        })
+        .collect::<Vec<_>>();
+    actions.extend((0..100).map(|i| {
+        AprilAction::patch_file(
+            format!("/usr/share/foo/{}", i),
+            AprilFileOperationType::Chmod(0o700 + i % 8),
+        )
+    }));
+    let output = dir.path().join("output.deb");
+    let options = ReconstructOptions {
+        output: Some(output.clone()),
+        jobs: 8,
+        ..Default::default()
+    };
+    apply_actions_for_reconstruct(&deb_path, &actions, &options).unwrap();
+
+    let extracted = dir.path().join("extracted");
+    let status = Command::new("dpkg-deb")
+        .arg("-x")
+        .arg(&output)
+        .arg(&extracted)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    for i in 0..100 {
+        let metadata = std::fs::metadata(extracted.join(format!("usr/share/foo/{}", i))).unwrap();
+        assert_eq!(metadata.mode() & 0o777, 0o700 + i % 8);
+    }
+
+    // the first failure in the order of the actions is reported
+    let missing = |name: &str| {
+        AprilAction::patch_file(
+            format!("/usr/share/foo/{}", name),
+            AprilFileOperationType::Chmod(0o755),
+        )
+    };
+    actions.insert(50, missing("missing1"));
+    actions.push(missing("missing2"));
+    let error = apply_actions_for_reconstruct(&deb_path, &actions, &options).unwrap_err();
+    assert!(matches!(
+        error,
+        AprilError::ActionFailed { action, .. } if action == "chmod 755 /usr/share/foo/missing1"
+    ));
+}
+
+#[test]
+fn test_continue_on_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().canonicalize().unwrap();
+    std::fs::write(root.join("foo"), "foo").unwrap();
+    let chmod = |name: &str| {
+        AprilAction::patch_file(format!("/{}", name), AprilFileOperationType::Chmod(0o700))
+    };
+    let actions = [chmod("missing1"), chmod("missing2"), chmod("foo")];
+    let failed_actions = |error: AprilError| match error {
+        AprilError::ActionsFailed(errors) => errors
+            .into_iter()
+            .map(|e| match e {
+                AprilError::ActionFailed { action, .. } => action,
+                e => panic!("unexpected error: {}", e),
+            })
+            .collect::<Vec<_>>(),
+        AprilError::ActionFailed { action, .. } => vec![action],
+        e => panic!("unexpected error: {}", e),
+    };
+
+    // fail fast by default
+    let options = ReconstructOptions::default();
+    let error = apply_actions(&actions, &root, &mut Deb822::new(), &options).unwrap_err();
+    assert_eq!(failed_actions(error), ["chmod 700 /missing1"]);
+    assert_ne!(
+        std::fs::metadata(root.join("foo")).unwrap().mode() & 0o777,
+        0o700
+    );
+
+    for jobs in [1, 4] {
+        let options = ReconstructOptions {
+            continue_on_error: true,
+            jobs,
+            ..Default::default()
+        };
+        std::fs::set_permissions(root.join("foo"), Permissions::from_mode(0o644)).unwrap();
+        let error = apply_actions(&actions, &root, &mut Deb822::new(), &options).unwrap_err();
+        assert!(error.to_string().starts_with("2 actions failed:\n  - "));
+        assert_eq!(
+            failed_actions(error),
+            ["chmod 700 /missing1", "chmod 700 /missing2"]
+        );
+        assert_eq!(
+            std::fs::metadata(root.join("foo")).unwrap().mode() & 0o777,
+            0o700
+        );
+    }
+}
+
+#[test]
+fn test_builtin_patch() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("foo.conf"), "# foo\nenabled = false\n").unwrap();
+    let options = ReconstructOptions {
+        resource_base_dir: Some(dir.path().to_path_buf()),
+        builtin_patch: true,
+        ..Default::default()
+    };
+
+    let patch = "--- a/foo.conf\n+++ b/foo.conf\n@@ -1,2 +1,2 @@\n # foo\n-enabled = false\n+enabled = true\n";
+    std::fs::write(dir.path().join("foo.patch"), patch).unwrap();
+    let operation = AprilFileOperationType::Patch("file::foo.patch".to_string());
+    apply_file_operation(dir.path(), "foo.conf", &operation, &options).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("foo.conf")).unwrap(),
+        "# foo\nenabled = true\n"
+    );
+
+    // the hunk no longer applies, so the file is left untouched
+    let error = apply_file_operation(dir.path(), "foo.conf", &operation, &options).unwrap_err();
+    assert!(matches!(error, AprilError::PatchRejected { .. }));
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("foo.conf")).unwrap(),
+        "# foo\nenabled = true\n"
+    );
+}
+
+/// Build a `BSDIFF40` patch from the control entries (the diff and extra bytes are taken from
+/// `new` and `old` like `bsdiff` does), only for testing the patch applier
+#[cfg(test)]
+fn build_bsdiff_patch(old: &[u8], new: &[u8], entries: &[(usize, usize, i64)]) -> Vec<u8> {
+    let encode_offset = |value: i64| {
+        let mut bytes = value.unsigned_abs().to_le_bytes();
+        if value < 0 {
+            bytes[7] |= 0x80;
+        }
+        bytes
     };
-    apply_field_patch(&action, &mut paragraph);
-    assert_eq!(paragraph.get("Depends").unwrap(), "foo (>= 1.2.0), bar");
+    let compress = |data: &[u8]| {
+        let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    };
+    let (mut control, mut diff, mut extra) = (Vec::new(), Vec::new(), Vec::new());
+    let (mut old_pos, mut new_pos) = (0i64, 0);
+    for &(diff_copy, extra_copy, seek) in entries {
+        control.extend(encode_offset(diff_copy as i64));
+        control.extend(encode_offset(extra_copy as i64));
+        control.extend(encode_offset(seek));
+        for i in 0..diff_copy {
+            let old_byte = usize::try_from(old_pos.wrapping_add(i as i64))
+                .ok()
+                .and_then(|pos| old.get(pos))
+                .map_or(0, |byte| *byte);
+            diff.push(new[new_pos + i].wrapping_sub(old_byte));
+        }
+        extra.extend(&new[new_pos + diff_copy..new_pos + diff_copy + extra_copy]);
+        new_pos += diff_copy + extra_copy;
+        old_pos = old_pos.wrapping_add(diff_copy as i64).wrapping_add(seek);
+    }
+    let (control, diff, extra) = (compress(&control), compress(&diff), compress(&extra));
 
-    let action = AprilAction::PatchField {
-        field: std::borrow::Cow::Borrowed("Depends"),
-        value: "baz".to_owned(),
-        action: AprilActionType::Append,
+    let mut patch = b"BSDIFF40".to_vec();
+    patch.extend(encode_offset(control.len() as i64));
+    patch.extend(encode_offset(diff.len() as i64));
+    patch.extend(encode_offset(new.len() as i64));
+    patch.extend(control);
+    patch.extend(diff);
+    patch.extend(extra);
+
+    patch
+}
+
+#[test]
+fn test_patch_args() {
+    let input = r#"{
+        "schema": "0",
+        "name": "foo",
+        "compatible_versions": "*",
+        "overrides": {},
+        "files": {
+            "/usr/share/foo/data": { "action": "patch", "arg": "file::fuzz=0::foo.patch" }
+        }
+    }"#;
+    let data: crate::april::AprilPackage = serde_json::from_str(input).unwrap();
+    let actions = crate::april::plan_actions_from_april_data(&data).unwrap();
+    let Some(uri) = actions.iter().find_map(|action| match action {
+        AprilAction::PatchFile {
+            action: AprilFileOperationType::Patch(uri),
+            ..
+        } => Some(uri),
+        _ => None,
+    }) else {
+        panic!("unexpected actions: {:?}", actions);
     };
-    apply_field_patch(&action, &mut paragraph);
+    assert_eq!(patch_args(uri).unwrap(), ["-Nt", "-r-", "-F0"]);
+    assert_eq!(patch_args("file::foo.patch").unwrap(), ["-Nt", "-r-"]);
+    for bad_uri in [
+        "file::fuzz=1 -o /etc/passwd::foo.patch",
+        "file::fuzz=+1::foo.patch",
+        "file::fuzz=::foo.patch",
+        "file::fuzz=1;fuzz=2::foo.patch",
+        // the target file is given to `patch`, so stripping the file names has no effect
+        "file::strip=1::foo.patch",
+    ] {
+        assert!(
+            matches!(patch_args(bad_uri), Err(AprilError::InvalidResourceUri(_))),
+            "{}",
+            bad_uri
+        );
+    }
+
+    // git-format patches have the `a/` and `b/` prefixes
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(dir.path().join("usr/share/foo")).unwrap();
+    std::fs::write(dir.path().join("usr/share/foo/data"), "hello\n").unwrap();
+    std::fs::write(
+        dir.path().join("foo.patch"),
+        "diff --git a/data b/data\n--- a/data\n+++ b/data\n@@ -1 +1 @@\n-hello\n+world\n",
+    )
+    .unwrap();
+    let options = ReconstructOptions {
+        resource_base_dir: Some(dir.path().to_path_buf()),
+        ..Default::default()
+    };
+    apply_file_operation(
+        dir.path(),
+        "/usr/share/foo/data",
+        &AprilFileOperationType::Patch(uri.clone()),
+        &options,
+    )
+    .unwrap();
     assert_eq!(
-        paragraph.get("Depends").unwrap(),
-        "foo (>= 1.2.0), bar, baz"
+        std::fs::read_to_string(dir.path().join("usr/share/foo/data")).unwrap(),
+        "world\n"
     );
 
-    let action = AprilAction::PatchField {
-        field: std::borrow::Cow::Borrowed("Depends"),
-        value: "foo".to_owned(),
-        action: AprilActionType::Replace,
+    // the fuzz option is rejected where it has no effect
+    let builtin = ReconstructOptions {
+        builtin_patch: true,
+        ..Default::default()
     };
-    apply_field_patch(&action, &mut paragraph);
-    assert_eq!(paragraph.get("Depends").unwrap(), "foo");
+    let operations = [
+        (AprilFileOperationType::Patch(uri.clone()), &builtin),
+        (AprilFileOperationType::BinaryPatch(uri.clone()), &options),
+        (AprilFileOperationType::Add(uri.clone()), &options),
+    ];
+    for (operation, options) in operations {
+        let result = check_resource_uri_options(&operation, options);
+        assert!(
+            matches!(result, Err(AprilError::InvalidResourceUri(_))),
+            "{:?}",
+            operation
+        );
+    }
+    assert!(
+        check_resource_uri_options(&AprilFileOperationType::Patch(uri.clone()), &options).is_ok()
+    );
+}
 
-    let action = AprilAction::PatchField {
-        field: std::borrow::Cow::Borrowed("Depends"),
-        value: "".to_owned(),
-        action: AprilActionType::Replace,
+#[test]
+fn test_bsdiff_patch() {
+    let old = b"\x7fELF\x02\x01\x01\0 hello world \0\x10\x20\x30 /usr/local/lib/foo.so\0";
+    let new = b"\x7fELF\x02\x01\x01\0 hello there world \0\x11\x20\x30 /usr/lib/foo.so\0\xff";
+    // copy the head with changes, insert "there ", then skip "local/" in the old content
+    let entries = [(15, 6, 0), (16, 0, 6), (11, 1, 0)];
+    let patch = build_bsdiff_patch(old, new, &entries);
+    assert_eq!(apply_bsdiff(old, &patch).unwrap(), new);
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("foo.bin"), old).unwrap();
+    std::fs::set_permissions(dir.path().join("foo.bin"), Permissions::from_mode(0o755)).unwrap();
+    std::fs::write(dir.path().join("foo.bsdiff"), &patch).unwrap();
+    let options = ReconstructOptions {
+        resource_base_dir: Some(dir.path().to_path_buf()),
+        ..Default::default()
     };
-    apply_field_patch(&action, &mut paragraph);
-    assert_eq!(paragraph.get("Depends"), None);
+    let operation =
+        AprilFileOperationType::BinaryPatch("file::format=bsdiff::foo.bsdiff".to_string());
+    apply_file_operation(dir.path(), "foo.bin", &operation, &options).unwrap();
+    assert_eq!(std::fs::read(dir.path().join("foo.bin")).unwrap(), new);
+    let metadata = std::fs::metadata(dir.path().join("foo.bin")).unwrap();
+    assert_eq!(metadata.mode() & 0o777, 0o755);
+    // no external program is needed
+    let action = AprilAction::patch_file("foo.bin", operation);
+    assert_eq!(required_tools(&[action], &options, true), ["dpkg-deb"]);
 
-    let action = AprilAction::PatchField {
-        field: std::borrow::Cow::Borrowed("Depends"),
-        value: "baz".to_owned(),
-        action: AprilActionType::Append,
+    assert!(apply_bsdiff(old, b"BSDIFF41").is_err());
+    assert_eq!(
+        apply_bsdiff(old, &patch[..48]).unwrap_err(),
+        "truncated patch"
+    );
+    let error = apply_bsdiff(old, &build_bsdiff_patch(old, new, &[(15, 6, 0)])).unwrap_err();
+    assert!(error.starts_with("corrupted patch"), "{}", error);
+    // a huge new length in the header is not allocated upfront
+    let mut oversized = patch.clone();
+    oversized[24..32].copy_from_slice(&i64::MAX.to_le_bytes());
+    let error = apply_bsdiff(old, &oversized).unwrap_err();
+    assert!(error.starts_with("corrupted patch"), "{}", error);
+    // seeking the old position to the edge of i64 must not overflow
+    let entries = [(1, 0, i64::MAX - 5), (20, 0, 0)];
+    let overflowing = build_bsdiff_patch(old, &[0; 21], &entries);
+    let error = apply_bsdiff(old, &overflowing).unwrap_err();
+    assert!(error.starts_with("corrupted patch"), "{}", error);
+    assert!(binary_patch_format("file::format=vcdiff::foo.vcdiff").is_err());
+    assert!(binary_patch_format("file::format=bsdiff;format=bsdiff::foo.bsdiff").is_err());
+    assert_eq!(
+        binary_patch_format("file::foo.vcdiff").unwrap(),
+        BinaryPatchFormat::Xdelta3
+    );
+}
+
+#[test]
+fn test_external_patch_output() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("foo.conf"), "# foo\nenabled = maybe\n").unwrap();
+    let options = ReconstructOptions {
+        resource_base_dir: Some(dir.path().to_path_buf()),
+        ..Default::default()
     };
-    apply_field_patch(&action, &mut paragraph);
-    assert_eq!(paragraph.get("Depends").unwrap(), "baz");
+
+    let patch = "--- a/foo.conf\n+++ b/foo.conf\n@@ -1,2 +1,2 @@\n # foo\n-enabled = false\n+enabled = true\n";
+    std::fs::write(dir.path().join("foo.patch"), patch).unwrap();
+    let operation = AprilFileOperationType::Patch("file::foo.patch".to_string());
+    let error = apply_file_operation(dir.path(), "foo.conf", &operation, &options).unwrap_err();
+    let AprilError::ToolFailed { output, .. } = &error else {
+        panic!("unexpected error: {}", error);
+    };
+    assert!(output.contains("1 out of 1 hunk FAILED"), "{}", output);
+    assert!(error.to_string().contains("\n  1 out of 1 hunk FAILED"));
+
+    // the standard error is captured as well
+    let mut command = Command::new("sh");
+    command.args(["-c", "cat > /dev/null; echo 'bad delta' >&2; exit 2"]);
+    let input = ResourceContent::Memory(b"delta".to_vec());
+    let error = run_tool(command, "apply binary patch", &input, DEFAULT_TOOL_TIMEOUT).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Failed to apply binary patch: exit status: 2\n  bad delta"
+    );
 }
 
 #[test]
-fn test_out_of_bound_file_operation() {
-    if let Err(e) = resolve_path("/tmp", "..") {
-        assert_eq!(e.to_string(), "Invalid file path: ..");
-    } else {
-        unreachable!();
+fn test_tool_timeout() {
+    let mut command = Command::new("sleep");
+    command.arg("10");
+    let input = ResourceContent::Memory(Vec::new());
+    let started = Instant::now();
+    let error = run_tool(command, "apply patch", &input, Duration::from_millis(100)).unwrap_err();
+    assert!(matches!(error, AprilError::ToolTimedOut { .. }));
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn test_patch_from_resource_bundle() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("root");
+    std::fs::create_dir(&root).unwrap();
+    std::fs::write(root.join("foo.conf"), "enabled = false\n").unwrap();
+
+    let patch = b"--- a/foo.conf\n+++ b/foo.conf\n@@ -1 +1 @@\n-enabled = false\n+enabled = true\n";
+    let bundle_path = dir.path().join("bundle.tar");
+    let mut bundle = tar::Builder::new(File::create(&bundle_path).unwrap());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(patch.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    bundle
+        .append_data(&mut header, "patches/foo.patch", &patch[..])
+        .unwrap();
+    bundle.finish().unwrap();
+
+    let options = ReconstructOptions {
+        resource_bundle: Some(bundle_path),
+        ..Default::default()
+    };
+    let sha256 = hex::encode(sha2::Sha256::digest(patch));
+    let bad_uri = format!("file::sha256={}::bundle:patches/foo.patch", "0".repeat(64));
+    assert!(
+        apply_file_operation(
+            &root,
+            "foo.conf",
+            &AprilFileOperationType::Patch(bad_uri.to_string()),
+            &options,
+        )
+        .is_err()
+    );
+    let uri = format!("file::sha256={}::bundle:patches/foo.patch", sha256);
+    apply_file_operation(
+        &root,
+        "foo.conf",
+        &AprilFileOperationType::Patch(uri),
+        &options,
+    )
+    .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(root.join("foo.conf")).unwrap(),
+        "enabled = true\n"
+    );
+}
+
+#[test]
+fn test_move_copy_into_missing_directory() {
+    let root = tempfile::tempdir().unwrap();
+    std::fs::write(root.path().join("foo"), b"foo").unwrap();
+    std::fs::write(root.path().join("bar"), b"bar").unwrap();
+
+    apply_file_operation(
+        root.path(),
+        "foo",
+        &AprilFileOperationType::Move("deep/new/dir/foo".to_string()),
+        &ReconstructOptions::default(),
+    )
+    .unwrap();
+    assert!(!root.path().join("foo").exists());
+    assert_eq!(
+        std::fs::read(root.path().join("deep/new/dir/foo")).unwrap(),
+        b"foo"
+    );
+
+    apply_file_operation(
+        root.path(),
+        "bar",
+        &AprilFileOperationType::Copy("other/dir/bar".to_string()),
+        &ReconstructOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        std::fs::read(root.path().join("other/dir/bar")).unwrap(),
+        b"bar"
+    );
+
+    // parent directories must not escape root
+    assert!(
+        apply_file_operation(
+            root.path(),
+            "bar",
+            &AprilFileOperationType::Copy("missing/../../escaped/bar".to_string()),
+            &ReconstructOptions::default(),
+        )
+        .is_err()
+    );
+    assert!(!root.path().join("missing").exists());
+}
+
+#[test]
+fn test_add_file_in_new_directory() {
+    let root = tempfile::tempdir().unwrap();
+    let uri = "file::data:text/plain,enabled%20%3D%20true".to_string();
+
+    apply_file_operation(
+        root.path(),
+        "/usr/share/foo/new.conf",
+        &AprilFileOperationType::Add(uri.clone()),
+        &ReconstructOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(root.path().join("usr/share/foo/new.conf")).unwrap(),
+        "enabled = true"
+    );
+    // Add refuses to replace existing files
+    assert!(
+        apply_file_operation(
+            root.path(),
+            "/usr/share/foo/new.conf",
+            &AprilFileOperationType::Add(uri),
+            &ReconstructOptions::default(),
+        )
+        .is_err()
+    );
+
+    apply_file_operation(
+        root.path(),
+        "/var/lib/foo/state",
+        &AprilFileOperationType::Mkdir,
+        &ReconstructOptions::default(),
+    )
+    .unwrap();
+    assert!(root.path().join("var/lib/foo/state").is_dir());
+}
+
+#[test]
+fn test_post_reconstruct_hook() {
+    let dir = tempfile::tempdir().unwrap();
+    let deb_path = build_test_package(dir.path());
+    let hook_output = dir.path().join("hook's output");
+    let hook = format!(
+        "printf '%s %s %s' {{output}} {{name}} {{version}} > {}",
+        shell_quote(&hook_output.to_string_lossy())
+    );
+
+    // the hook should not run if the reconstruction fails
+    let options = ReconstructOptions {
+        max_output_size: Some(1),
+        post_reconstruct_hook: Some(hook.clone()),
+        ..Default::default()
+    };
+    assert!(apply_actions_for_reconstruct(&deb_path, &[], &options).is_err());
+    assert!(!hook_output.exists());
+
+    let options = ReconstructOptions {
+        post_reconstruct_hook: Some(hook),
+        ..Default::default()
+    };
+    apply_actions_for_reconstruct(&deb_path, &[], &options).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&hook_output).unwrap(),
+        format!("{} foo 1.0", repacked_package_path(&deb_path).display())
+    );
+}
+
+#[test]
+fn test_check_resource_digest() {
+    let content = b"Hello, world!";
+    for (algorithm, digest) in [
+        (
+            HashAlgorithm::Sha1,
+            "943a702d06f34599aee1f8da8ef9f7296031d699",
+        ),
+        (
+            HashAlgorithm::Sha256,
+            "315F5BDB76D078C43B8AC0064E4A0164612B1FCE77C869345BFC94C75894EDD3",
+        ),
+    ] {
+        check_resource_digest("test", content, algorithm, digest).unwrap();
+        assert!(check_resource_digest("test", b"", algorithm, digest).is_err());
     }
+    let sha512 = HashAlgorithm::Sha512.hash(content);
+    assert_eq!(sha512.len(), 128);
+    check_resource_digest("test", content, HashAlgorithm::Sha512, &sha512).unwrap();
+    let blake2b = HashAlgorithm::Blake2b.hash(content);
+    assert_eq!(blake2b.len(), 128);
+    assert_ne!(sha512, blake2b);
+}
+
+#[cfg(test)]
+fn fetch_resource_to_vec(uri: &str, options: &ReconstructOptions) -> Result<Vec<u8>> {
+    let mut content = Vec::new();
+    fetch_resource_uri(uri, options)?
+        .reader()?
+        .read_to_end(&mut content)?;
+
+    Ok(content)
 }
 
 #[test]
-fn test_resolve_resource_uri() {
-    let uri = "file::sha256=abc::https://example.com/package.deb".to_string();
-    let expected = AprilResourceType::External {
-        url: "https://example.com/package.deb".to_string(),
-        sha256: "abc".to_string(),
+fn test_fetch_local_resource() {
+    let dir = tempfile::tempdir().unwrap();
+    let base_dir = dir.path().join("config");
+    std::fs::create_dir_all(base_dir.join("patches")).unwrap();
+    std::fs::write(base_dir.join("patches/foo.patch"), b"foo").unwrap();
+    std::fs::write(dir.path().join("secret"), b"secret").unwrap();
+    let options = ReconstructOptions {
+        resource_base_dir: Some(base_dir.clone()),
+        ..Default::default()
     };
-    assert_eq!(resolve_resource_uri(&uri).unwrap(), expected);
 
-    let uri = "file::data:application/octet-stream;base64,SGVsbG8sIHdvcmxkIQ==".to_string();
-    let expected = AprilResourceType::Inline {
-        content: (&b"Hello, world!"[..]).to_vec(),
+    assert_eq!(
+        fetch_resource_to_vec("file::patches/foo.patch", &options).unwrap(),
+        b"foo"
+    );
+    let uri = format!(
+        "file::sha256={}::file://{}",
+        HashAlgorithm::Sha256.hash(b"foo"),
+        base_dir.join("patches/foo.patch").display()
+    );
+    assert_eq!(fetch_resource_to_vec(&uri, &options).unwrap(), b"foo");
+    let uri = format!("file::sha256={}::patches/foo.patch", "0".repeat(64));
+    assert!(matches!(
+        fetch_resource_uri(&uri, &options).unwrap_err(),
+        AprilError::ResourceDigestMismatch { .. }
+    ));
+
+    // resources outside of the resource directory are rejected
+    assert!(fetch_resource_uri("file::../secret", &options).is_err());
+    let uri = format!("file::file://{}", dir.path().join("secret").display());
+    assert!(fetch_resource_uri(&uri, &options).is_err());
+    assert!(fetch_resource_uri("file::patches/foo.patch", &ReconstructOptions::default()).is_err());
+}
+
+#[test]
+fn test_fetch_compressed_resource() {
+    let payload = b"--- a/foo\n+++ b/foo\n";
+    let gzip = {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(payload).unwrap();
+        encoder.finish().unwrap()
+    };
+    let xz = {
+        let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+        encoder.write_all(payload).unwrap();
+        encoder.finish().unwrap()
+    };
+    let zstd = zstd::encode_all(&payload[..], 0).unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let options = ReconstructOptions {
+        resource_base_dir: Some(dir.path().to_path_buf()),
+        ..Default::default()
+    };
+    for (name, compressed) in [("gzip", gzip), ("xz", xz), ("zstd", zstd)] {
+        std::fs::write(dir.path().join(name), &compressed).unwrap();
+        // the digest covers the compressed content
+        let uri = format!(
+            "file::compression={};sha256={}::{}",
+            name,
+            HashAlgorithm::Sha256.hash(&compressed),
+            name
+        );
+        assert_eq!(fetch_resource_to_vec(&uri, &options).unwrap(), payload);
+        let uri = format!(
+            "file::compression={};sha256={}::{}",
+            name,
+            HashAlgorithm::Sha256.hash(payload),
+            name
+        );
+        assert!(fetch_resource_uri(&uri, &options).is_err());
+    }
+}
+
+#[cfg(test)]
+fn serve_http_responses(responses: Vec<&'static [u8]>) -> (u16, std::thread::JoinHandle<()>) {
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = std::thread::spawn(move || {
+        for response in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            stream.write_all(response).unwrap();
+        }
+    });
+
+    (port, server)
+}
+
+#[test]
+fn test_resolve_proxy() {
+    let env = |vars: &'static [(&'static str, &'static str)]| {
+        move |name: &str| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        }
+    };
+    let https = Url::parse("https://example.com/foo").unwrap();
+    let http = Url::parse("http://mirror.example.org/foo").unwrap();
+
+    assert_eq!(resolve_proxy(&https, None, env(&[])), None);
+    let vars = &[
+        ("HTTPS_PROXY", "http://upper:3128"),
+        ("https_proxy", "http://lower:3128"),
+        ("ALL_PROXY", "socks5://all:1080"),
+    ];
+    assert_eq!(
+        resolve_proxy(&https, None, env(vars)).as_deref(),
+        Some("http://lower:3128")
+    );
+    assert_eq!(
+        resolve_proxy(&http, None, env(vars)).as_deref(),
+        Some("socks5://all:1080")
+    );
+    assert_eq!(
+        resolve_proxy(&https, Some("http://configured:8080"), env(vars)).as_deref(),
+        Some("http://configured:8080")
+    );
+
+    let vars = &[
+        ("http_proxy", "http://proxy:3128"),
+        ("https_proxy", "http://proxy:3128"),
+        ("NO_PROXY", "localhost, .example.org,*.internal"),
+    ];
+    assert_eq!(resolve_proxy(&http, None, env(vars)), None);
+    assert_eq!(
+        resolve_proxy(&http, Some("http://configured:8080"), env(vars)),
+        None
+    );
+    assert!(resolve_proxy(&https, None, env(vars)).is_some());
+    let url = Url::parse("https://git.internal/foo").unwrap();
+    assert_eq!(resolve_proxy(&url, None, env(vars)), None);
+    let url = Url::parse("https://notexample.org/foo").unwrap();
+    assert!(resolve_proxy(&url, None, env(vars)).is_some());
+    let vars = &[("https_proxy", "http://proxy:3128"), ("no_proxy", "*")];
+    assert_eq!(resolve_proxy(&https, None, env(vars)), None);
+}
+
+#[test]
+fn test_fetch_through_proxy() {
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+    };
+
+    // a CONNECT proxy answering the tunneled request by itself
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let proxy = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut requests = Vec::new();
+        for response in [
+            &b"HTTP/1.1 200 Connection established\r\n\r\n"[..],
+            b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\nfoo",
+        ] {
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            requests.push(request.trim_end().to_string());
+            stream.write_all(response).unwrap();
+        }
+
+        requests
+    });
+
+    let mut options = ReconstructOptions::default();
+    options.fetch.allow_insecure_http = true;
+    options.fetch.retries = 0;
+    options.fetch.proxy = Some(format!("http://127.0.0.1:{}", port));
+    // the host can not be resolved, so only the proxy can fetch it
+    let uri = format!(
+        "file::sha256={}::http://april.invalid/foo",
+        HashAlgorithm::Sha256.hash(b"foo")
+    );
+    assert_eq!(fetch_resource_to_vec(&uri, &options).unwrap(), b"foo");
+    assert_eq!(
+        proxy.join().unwrap(),
+        ["CONNECT april.invalid:80 HTTP/1.1", "GET /foo HTTP/1.1"]
+    );
+
+    options.fetch.proxy = Some("ftp://127.0.0.1".to_string());
+    assert!(matches!(
+        fetch_resource_uri(&uri, &options),
+        Err(AprilError::InvalidOption(_))
+    ));
+}
+
+#[test]
+fn test_fetch_retries_transient_failures() {
+    let unavailable: &[u8] =
+        b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+    let (port, server) = serve_http_responses(vec![
+        unavailable,
+        unavailable,
+        b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\nfoo",
+    ]);
+
+    let mut options = ReconstructOptions::default();
+    options.fetch.allow_insecure_http = true;
+    options.fetch.retry_backoff = Duration::from_millis(10);
+    let uri = format!(
+        "file::sha256={}::http://127.0.0.1:{}/foo",
+        HashAlgorithm::Sha256.hash(b"foo"),
+        port
+    );
+    assert_eq!(fetch_resource_to_vec(&uri, &options).unwrap(), b"foo");
+    server.join().unwrap();
+
+    // the server is gone, so all the attempts fail
+    options.fetch.retries = 1;
+    let error = fetch_resource_uri(&uri, &options).unwrap_err();
+    assert!(error.to_string().contains("after 2 attempts"));
+}
+
+#[test]
+fn test_fetch_max_download_size() {
+    let (port, server) = serve_http_responses(vec![
+        b"HTTP/1.1 200 OK\r\nContent-Length: 1073741824\r\nConnection: close\r\n\r\nfoo",
+        b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nfoobar",
+    ]);
+
+    let mut options = ReconstructOptions::default();
+    options.fetch.allow_insecure_http = true;
+    options.fetch.max_download_size = 4;
+    let uri = format!(
+        "file::sha256={}::http://127.0.0.1:{}/foo",
+        HashAlgorithm::Sha256.hash(b"foo"),
+        port
+    );
+    // rejected by the advertised length
+    let error = fetch_resource_uri(&uri, &options).unwrap_err();
+    assert!(matches!(
+        error,
+        AprilError::ResourceTooLarge { limit: 4, .. }
+    ));
+    // rejected while streaming the body without a length
+    let error = fetch_resource_uri(&uri, &options).unwrap_err();
+    assert!(matches!(
+        error,
+        AprilError::ResourceTooLarge { limit: 4, .. }
+    ));
+    server.join().unwrap();
+}
+
+#[test]
+fn test_fetch_insecure_http() {
+    let (port, server) = serve_http_responses(vec![
+        b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\nfoo",
+    ]);
+    let mut options = ReconstructOptions::default();
+    options.fetch.retries = 0;
+    let uri = format!(
+        "file::sha256={}::http://127.0.0.1:{}/foo",
+        HashAlgorithm::Sha256.hash(b"foo"),
+        port
+    );
+    // rejected before connecting to the server
+    let error = fetch_resource_uri(&uri, &options).unwrap_err();
+    assert!(matches!(error, AprilError::InsecureResourceUri(_)));
+    let error = fetch_resource_uri(&uri.replace("::http:", "::HTTP:"), &options).unwrap_err();
+    assert!(matches!(error, AprilError::InsecureResourceUri(_)));
+
+    options.fetch.allow_insecure_http = true;
+    assert_eq!(fetch_resource_to_vec(&uri, &options).unwrap(), b"foo");
+    server.join().unwrap();
+}
+
+#[test]
+fn test_fetch_resource_cache() {
+    let (port, server) = serve_http_responses(vec![
+        b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\nfoo",
+    ]);
+    let cache_dir = tempfile::tempdir().unwrap();
+    let mut options = ReconstructOptions::default();
+    options.fetch.allow_insecure_http = true;
+    options.fetch.retries = 0;
+    options.fetch.cache_dir = Some(cache_dir.path().to_path_buf());
+    let hash = HashAlgorithm::Sha256.hash(b"foo");
+    let uri = format!("file::sha256={}::http://127.0.0.1:{}/foo", hash, port);
+
+    assert_eq!(fetch_resource_to_vec(&uri, &options).unwrap(), b"foo");
+    server.join().unwrap();
+    let cache_path = cache_dir.path().join("sha256").join(&hash);
+    assert_eq!(std::fs::read(&cache_path).unwrap(), b"foo");
+
+    // the server is gone, the resource now comes from the cache
+    assert_eq!(fetch_resource_to_vec(&uri, &options).unwrap(), b"foo");
+
+    // corrupted blobs are not used
+    std::fs::write(&cache_path, b"bar").unwrap();
+    assert!(fetch_resource_uri(&uri, &options).is_err());
+    assert!(!cache_path.exists());
+}
+
+#[test]
+fn test_prefetch_resources() {
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+    };
+
+    // answers by the requested path, since the order of concurrent requests is unknown
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = std::thread::spawn(move || {
+        for _ in 0..2 {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let body = if request.contains("/foo ") {
+                "foo"
+            } else {
+                "bar"
+            };
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\n{}",
+                body
+            );
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+
+    let dir = tempfile::tempdir().unwrap();
+    let deb_path = build_test_package(dir.path());
+    let cache_dir = tempfile::tempdir().unwrap();
+    let mut options = ReconstructOptions::default();
+    options.fetch.allow_insecure_http = true;
+    options.fetch.retries = 0;
+    options.fetch.cache_dir = Some(cache_dir.path().to_path_buf());
+    let uri = |name: &str| {
+        format!(
+            "file::sha256={}::http://127.0.0.1:{}/{}",
+            HashAlgorithm::Sha256.hash(name.as_bytes()),
+            port,
+            name
+        )
+    };
+    let actions = [
+        // fails, so the following operations are never applied
+        AprilAction::patch_file("/usr/share/foo/missing", AprilFileOperationType::Remove),
+        // an invalid resource does not stop prefetching the others
+        AprilAction::patch_file(
+            "/usr/share/foo/baz",
+            AprilFileOperationType::Add("file::sha256=::".to_string()),
+        ),
+        AprilAction::patch_file(
+            "/usr/share/foo/foo",
+            AprilFileOperationType::Add(uri("foo")),
+        ),
+        AprilAction::patch_file(
+            "/usr/share/foo/bar",
+            AprilFileOperationType::Overwrite(uri("bar")),
+        ),
+    ];
+
+    assert!(apply_actions_for_reconstruct(&deb_path, &actions, &options).is_err());
+    server.join().unwrap();
+    for name in ["foo", "bar"] {
+        let cache_path = cache_dir
+            .path()
+            .join("sha256")
+            .join(HashAlgorithm::Sha256.hash(name.as_bytes()));
+        assert_eq!(std::fs::read(cache_path).unwrap(), name.as_bytes());
+    }
+}
+
+#[test]
+fn test_repacked_package_path() {
+    assert_eq!(
+        repacked_package_path(Path::new("libfoo_1.0_amd64.deb")),
+        Path::new("libfoo_1.0_amd64.repacked.deb")
+    );
+    assert_eq!(
+        repacked_package_path(Path::new("/tmp/pkgs/libfoo_1.0_amd64.deb")),
+        Path::new("/tmp/pkgs/libfoo_1.0_amd64.repacked.deb")
+    );
+    assert_eq!(
+        repacked_package_path(Path::new("libfoo")),
+        Path::new("libfoo.repacked.deb")
+    );
+}
+
+#[test]
+fn test_reconstruct_conffiles_override() {
+    let dir = tempfile::tempdir().unwrap();
+    let deb_path = build_test_package_with(dir.path(), |root| {
+        std::fs::create_dir_all(root.join("etc")).unwrap();
+        std::fs::write(root.join("etc/foo.conf"), "foo\n").unwrap();
+        std::fs::write(root.join("etc/bar.conf"), "bar\n").unwrap();
+        std::fs::write(root.join("DEBIAN/conffiles"), "/etc/foo.conf\n").unwrap();
+    });
+    let input = r#"{
+        "schema": "0",
+        "name": "foo",
+        "compatible_versions": "*",
+        "overrides": { "conffiles": ["/etc/foo.conf", "/etc/bar.conf"] }
+    }"#;
+    let data: crate::april::AprilPackage = serde_json::from_str(input).unwrap();
+    let actions = crate::april::plan_actions_from_april_data(&data).unwrap();
+
+    apply_actions_for_reconstruct(&deb_path, &actions, &ReconstructOptions::default()).unwrap();
+    let output = Command::new("dpkg-deb")
+        .arg("-I")
+        .arg(repacked_package_path(&deb_path))
+        .arg("conffiles")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"/etc/foo.conf\n/etc/bar.conf\n");
+}
+
+#[test]
+fn test_reconstruct_prepend_script() {
+    let dir = tempfile::tempdir().unwrap();
+    let deb_path = build_test_package_with(dir.path(), |root| {
+        let postinst = root.join("DEBIAN/postinst");
+        std::fs::write(&postinst, "#!/bin/sh\nset -e\nldconfig\n").unwrap();
+        std::fs::set_permissions(&postinst, Permissions::from_mode(0o755)).unwrap();
+    });
+    let input = r#"{
+        "schema": "0",
+        "name": "foo",
+        "compatible_versions": "*",
+        "overrides": {
+            "scripts": { "prepend": { "postinst": "export FOO=1", "prerm": "exit 0" } }
+        }
+    }"#;
+    let data: crate::april::AprilPackage = serde_json::from_str(input).unwrap();
+    let actions = crate::april::plan_actions_from_april_data(&data).unwrap();
+
+    apply_actions_for_reconstruct(&deb_path, &actions, &ReconstructOptions::default()).unwrap();
+    let script = |name| {
+        let output = Command::new("dpkg-deb")
+            .arg("-I")
+            .arg(repacked_package_path(&deb_path))
+            .arg(name)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
     };
-    assert_eq!(resolve_resource_uri(&uri).unwrap(), expected);
+    assert_eq!(
+        script("postinst"),
+        "#!/bin/sh\nexport FOO=1\nset -e\nldconfig\n"
+    );
+    // the missing script is created
+    assert_eq!(script("prerm"), "#!/bin/sh\nexit 0\n");
+}
+
+#[test]
+fn test_reconstruct_output_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let deb_path = build_test_package(dir.path());
+    let output = dir.path().join("artifacts/debs/foo_1.0_all.deb");
+    let options = ReconstructOptions {
+        output: Some(output.clone()),
+        ..Default::default()
+    };
+
+    apply_actions_for_reconstruct(&deb_path, &[], &options).unwrap();
+    assert!(output.exists());
+    assert!(!repacked_package_path(&deb_path).exists());
+}
+
+#[test]
+fn test_reproducible_reconstruct() {
+    let dir = tempfile::tempdir().unwrap();
+    let deb_path = build_test_package(dir.path());
+    let actions = [AprilAction::patch_file(
+        "/usr/share/foo/added",
+        AprilFileOperationType::Add("file::data:,foo".to_string()),
+    )];
+    let reconstruct = |name: &str| {
+        let output = dir.path().join(name);
+        let options = ReconstructOptions {
+            output: Some(output.clone()),
+            reproducible: true,
+            ..Default::default()
+        };
+        apply_actions_for_reconstruct(&deb_path, &actions, &options).unwrap();
+        HashAlgorithm::Sha256.hash(&std::fs::read(output).unwrap())
+    };
+
+    let first = reconstruct("first.deb");
+    // make sure the files created by the actions get a different timestamp
+    std::thread::sleep(Duration::from_millis(1100));
+    assert_eq!(reconstruct("second.deb"), first);
+}
+
+#[test]
+fn test_failed_action_context() {
+    let dir = tempfile::tempdir().unwrap();
+    let deb_path = build_test_package(dir.path());
+    let actions = [AprilAction::patch_file(
+        "/usr/share/foo/missing",
+        AprilFileOperationType::Chmod(0o755),
+    )];
+
+    let error = apply_actions_for_reconstruct(&deb_path, &actions, &ReconstructOptions::default())
+        .unwrap_err();
+    assert!(matches!(error, AprilError::ActionFailed { .. }));
+    assert!(
+        error
+            .to_string()
+            .starts_with("Failed to chmod 755 /usr/share/foo/missing: ")
+    );
+}
+
+#[test]
+fn test_keep_temp() {
+    let dir = tempfile::tempdir().unwrap();
+    let deb_path = build_test_package(dir.path());
+    let count_entries = || std::fs::read_dir(dir.path()).unwrap().count();
+
+    apply_actions_for_reconstruct(&deb_path, &[], &ReconstructOptions::default()).unwrap();
+    // the original and the reconstructed package
+    assert_eq!(count_entries(), 2);
+
+    let options = ReconstructOptions {
+        keep_temp: true,
+        ..Default::default()
+    };
+    apply_actions_for_reconstruct(&deb_path, &[], &options).unwrap();
+    let kept = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.is_dir())
+        .unwrap();
+    assert!(kept.join("DEBIAN/control").exists());
+}
+
+#[test]
+fn test_dry_run() {
+    let dir = tempfile::tempdir().unwrap();
+    let deb_path = build_test_package(dir.path());
+    let options = ReconstructOptions {
+        dry_run: true,
+        ..Default::default()
+    };
+    let actions = [AprilAction::patch_file(
+        "/usr/share/foo/data",
+        AprilFileOperationType::Remove,
+    )];
+
+    apply_actions_for_reconstruct(&deb_path, &actions, &options).unwrap();
+    let entries = std::fs::read_dir(dir.path()).unwrap().count();
+    assert_eq!(entries, 1);
+
+    let options = ReconstructOptions {
+        compression: Some("xz".to_string()),
+        output: Some(PathBuf::from("/srv/foo.deb")),
+        ..options
+    };
+    assert_eq!(
+        reconstruct_plan(&deb_path, &actions, &options).unwrap(),
+        [
+            format!("dpkg-deb -R {} <root>", deb_path.display()),
+            "remove /usr/share/foo/data".to_string(),
+            "dpkg-deb -Zxz -b <root> /srv/foo.deb".to_string(),
+        ]
+    );
 }
@@ -0,0 +1,239 @@
+//! This module contains the parser for dpkg package relationship fields (Depends, Breaks, Provides, etc.)
+
+use std::fmt::Display;
+
+use crate::error::{AprilError, Result};
+
+const RELATION_OPS: &[&str] = &["<<", "<=", "=", ">=", ">>"];
+
+/// A single package relation like `foo:any (>= 1.0) [amd64] <!nocheck>`
+#[derive(Debug, PartialEq)]
+pub struct Relation {
+    pub name: String,
+    pub arch_qualifier: Option<String>,
+    pub constraint: Option<(String, String)>,
+    pub arch_restrictions: Option<String>,
+    pub profiles: Vec<String>,
+}
+
+fn is_valid_package_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_lowercase() || c.is_ascii_digit())
+        && name.len() >= 2
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "+-.".contains(c))
+}
+
+fn is_valid_version(version: &str) -> bool {
+    !version.is_empty()
+        && version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ".+~:-".contains(c))
+}
+
+/// Take the content enclosed by `open` and `close` from the beginning of `input`
+fn take_enclosed<'a>(
+    input: &'a str,
+    open: char,
+    close: char,
+    entry: &str,
+) -> Result<(&'a str, &'a str)> {
+    let Some(rest) = input.strip_prefix(open) else {
+        return Ok(("", input));
+    };
+    let Some(end) = rest.find(close) else {
+        return Err(AprilError::InvalidRelationship(format!(
+            "Unclosed '{}' in relationship: {}",
+            open, entry
+        )));
+    };
+
+    Ok((rest[..end].trim(), rest[end + 1..].trim_start()))
+}
+
+fn parse_constraint(constraint: &str, entry: &str) -> Result<(String, String)> {
+    let op_len = constraint
+        .find(|c: char| !"<=>".contains(c))
+        .unwrap_or(constraint.len());
+    let (op, version) = constraint.split_at(op_len);
+    let version = version.trim();
+    if !RELATION_OPS.contains(&op) {
+        return Err(AprilError::InvalidRelationship(format!(
+            "Invalid version relation '{}' in relationship: {}",
+            op, entry
+        )));
+    }
+    if !is_valid_version(version) {
+        return Err(AprilError::InvalidRelationship(format!(
+            "Invalid version '{}' in relationship: {}",
+            version, entry
+        )));
+    }
+
+    Ok((op.to_string(), version.to_string()))
+}
+
+impl Relation {
+    /// Parse a single relation (without alternatives)
+    pub fn parse(entry: &str) -> Result<Relation> {
+        let input = entry.trim();
+        let name_end = input
+            .find(|c: char| c.is_whitespace() || "(<>=[".contains(c))
+            .unwrap_or(input.len());
+        let (name, mut rest) = input.split_at(name_end);
+        rest = rest.trim_start();
+        let (name, arch_qualifier) = match name.split_once(':') {
+            Some((name, arch)) => (name, Some(arch.to_string())),
+            None => (name, None),
+        };
+        if !is_valid_package_name(name) {
+            return Err(AprilError::InvalidRelationship(format!(
+                "Invalid package name '{}' in relationship: {}",
+                name, entry
+            )));
+        }
+        if arch_qualifier.as_ref().is_some_and(|arch| arch.is_empty()) {
+            return Err(AprilError::InvalidRelationship(format!(
+                "Empty architecture qualifier in relationship: {}",
+                entry
+            )));
+        }
+
+        // both `foo (>= 1.0)` and the non-canonical `foo >= 1.0` are accepted
+        let constraint = if rest.starts_with('(') {
+            let (constraint, remaining) = take_enclosed(rest, '(', ')', entry)?;
+            rest = remaining;
+            Some(parse_constraint(constraint, entry)?)
+        } else if rest.starts_with(['=', '>']) || rest.starts_with("<<") || rest.starts_with("<=") {
+            // a lone `<` starts a build profile instead
+            let op_len = rest
+                .find(|c: char| !"<=>".contains(c))
+                .unwrap_or(rest.len());
+            let end = rest[op_len..]
+                .find(['[', '<'])
+                .map_or(rest.len(), |i| i + op_len);
+            let (constraint, remaining) = rest.split_at(end);
+            rest = remaining;
+            Some(parse_constraint(constraint.trim(), entry)?)
+        } else {
+            None
+        };
+
+        let (arch_restrictions, remaining) = take_enclosed(rest, '[', ']', entry)?;
+        rest = remaining;
+        let arch_restrictions = if arch_restrictions.is_empty() {
+            None
+        } else {
+            Some(
+                arch_restrictions
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            )
+        };
+
+        let mut profiles = Vec::new();
+        while rest.starts_with('<') {
+            let (profile, remaining) = take_enclosed(rest, '<', '>', entry)?;
+            if profile.is_empty() {
+                return Err(AprilError::InvalidRelationship(format!(
+                    "Empty build profile in relationship: {}",
+                    entry
+                )));
+            }
+            profiles.push(profile.split_whitespace().collect::<Vec<_>>().join(" "));
+            rest = remaining;
+        }
+
+        if !rest.is_empty() {
+            return Err(AprilError::InvalidRelationship(format!(
+                "Unexpected '{}' in relationship: {}",
+                rest, entry
+            )));
+        }
+
+        Ok(Relation {
+            name: name.to_string(),
+            arch_qualifier,
+            constraint,
+            arch_restrictions,
+            profiles,
+        })
+    }
+}
+
+impl Display for Relation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.name)?;
+        if let Some(arch) = &self.arch_qualifier {
+            write!(f, ":{}", arch)?;
+        }
+        if let Some((op, version)) = &self.constraint {
+            write!(f, " ({} {})", op, version)?;
+        }
+        if let Some(archs) = &self.arch_restrictions {
+            write!(f, " [{}]", archs)?;
+        }
+        for profile in &self.profiles {
+            write!(f, " <{}>", profile)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse a relationship field value (comma-separated relations with `|` alternatives)
+pub fn parse_relationships(value: &str) -> Result<Vec<Vec<Relation>>> {
+    value
+        .split(',')
+        .map(|relation| relation.split('|').map(Relation::parse).collect())
+        .collect()
+}
+
+/// Re-emit the relationship field value in the canonical form, e.g. `foo(>=1.0)` becomes `foo (>= 1.0)`
+pub fn canonicalize_relationships(value: &str) -> Result<String> {
+    Ok(parse_relationships(value)?
+        .iter()
+        .map(|alternatives| {
+            alternatives
+                .iter()
+                .map(|relation| relation.to_string())
+                .collect::<Vec<_>>()
+                .join(" | ")
+        })
+        .collect::<Vec<_>>()
+        .join(", "))
+}
+
+#[test]
+fn test_canonicalize_relationships() {
+    assert_eq!(
+        canonicalize_relationships("foo(>=1.0)").unwrap(),
+        "foo (>= 1.0)"
+    );
+    assert_eq!(
+        canonicalize_relationships("foo >= 1.0").unwrap(),
+        "foo (>= 1.0)"
+    );
+    assert_eq!(
+        canonicalize_relationships("libc6:any ( << 2:1.0~rc1-1 ) |bar,baz [amd64  arm64]").unwrap(),
+        "libc6:any (<< 2:1.0~rc1-1) | bar, baz [amd64 arm64]"
+    );
+    assert_eq!(
+        canonicalize_relationships("foo <!nocheck> <stage1>").unwrap(),
+        "foo <!nocheck> <stage1>"
+    );
+    assert_eq!(
+        canonicalize_relationships("foo << 1.0 <!nocheck>").unwrap(),
+        "foo (<< 1.0) <!nocheck>"
+    );
+}
+
+#[test]
+fn test_invalid_relationships() {
+    assert!(canonicalize_relationships("foo (=> 1.0)").is_err());
+    assert!(canonicalize_relationships("foo (>= )").is_err());
+    assert!(canonicalize_relationships("foo (>= 1.0").is_err());
+    assert!(canonicalize_relationships("Foo").is_err());
+    assert!(canonicalize_relationships("foo, ").is_err());
+    assert!(canonicalize_relationships("foo bar").is_err());
+}
@@ -0,0 +1,185 @@
+//! This module handles the original (`_gpgorigin`) signature embedded in dpkg packages
+
+use std::{
+    fs::File,
+    io::{BufReader, Read, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+use tempfile::NamedTempFile;
+
+use crate::error::{AprilError, Result};
+
+const AR_MAGIC: &[u8] = b"!<arch>\n";
+const AR_HEADER_SIZE: usize = 60;
+const ORIGIN_SIGNATURE_MEMBER: &str = "_gpgorigin";
+
+/// Verifies the original signature of a package before it gets reconstructed
+pub trait SignatureVerifier: Send + Sync {
+    fn verify(&self, deb_path: &Path) -> Result<()>;
+}
+
+/// Verifies the origin signature using `gpgv` against the specified keyring
+pub struct GpgVerifier {
+    pub keyring: PathBuf,
+}
+
+struct ArMemberHeader {
+    name: String,
+    size: u64,
+}
+
+fn read_ar_member_header<R: Read>(reader: &mut R) -> Result<Option<ArMemberHeader>> {
+    let mut header = [0u8; AR_HEADER_SIZE];
+    let mut filled = 0;
+    while filled < AR_HEADER_SIZE {
+        let n = reader.read(&mut header[filled..])?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    match filled {
+        0 => return Ok(None),
+        AR_HEADER_SIZE => (),
+        _ => {
+            return Err(AprilError::InvalidPackage(
+                "Truncated ar member header".to_string(),
+            ));
+        }
+    }
+    if &header[58..60] != b"`\n" {
+        return Err(AprilError::InvalidPackage(
+            "Invalid ar member header".to_string(),
+        ));
+    }
+
+    let name = String::from_utf8_lossy(&header[0..16])
+        .trim_end()
+        .trim_end_matches('/')
+        .to_string();
+    let size = std::str::from_utf8(&header[48..58])?
+        .trim_end()
+        .parse::<u64>()
+        .map_err(|_| AprilError::InvalidPackage(format!("Invalid ar member size for {}", name)))?;
+
+    Ok(Some(ArMemberHeader { name, size }))
+}
+
+/// Walk through all the ar members in the package, `visit` receives the member name and a reader for its content
+fn walk_ar_members<P: AsRef<Path>, F: FnMut(&str, &mut dyn Read) -> Result<()>>(
+    deb_path: P,
+    mut visit: F,
+) -> Result<()> {
+    let mut reader = BufReader::new(File::open(deb_path.as_ref())?);
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if magic != AR_MAGIC {
+        return Err(AprilError::InvalidPackage(format!(
+            "Not a valid dpkg package: {}",
+            deb_path.as_ref().display()
+        )));
+    }
+
+    while let Some(member) = read_ar_member_header(&mut reader)? {
+        let mut content = (&mut reader).take(member.size);
+        visit(&member.name, &mut content)?;
+        // drain whatever the visitor did not consume
+        std::io::copy(&mut content, &mut std::io::sink())?;
+        // ar members are aligned to even offsets
+        if member.size % 2 == 1 {
+            reader.read_exact(&mut [0u8; 1])?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Check if the package contains an origin signature
+pub fn package_has_signature<P: AsRef<Path>>(deb_path: P) -> Result<bool> {
+    let mut signed = false;
+    walk_ar_members(deb_path, |name, _| {
+        signed |= name == ORIGIN_SIGNATURE_MEMBER;
+        Ok(())
+    })?;
+
+    Ok(signed)
+}
+
+impl SignatureVerifier for GpgVerifier {
+    fn verify(&self, deb_path: &Path) -> Result<()> {
+        // the origin signature is a detached signature over the concatenation of all the other members
+        let mut signed_data = NamedTempFile::new()?;
+        let mut signature = None;
+        walk_ar_members(deb_path, |name, content| {
+            if name == ORIGIN_SIGNATURE_MEMBER {
+                let mut buf = Vec::new();
+                content.read_to_end(&mut buf)?;
+                signature = Some(buf);
+            } else {
+                std::io::copy(content, &mut signed_data)?;
+            }
+            Ok(())
+        })?;
+        let signature = signature.ok_or_else(|| {
+            AprilError::SignatureVerification(format!(
+                "Package is not signed: {}",
+                deb_path.display()
+            ))
+        })?;
+        let mut signature_file = NamedTempFile::new()?;
+        signature_file.write_all(&signature)?;
+        signed_data.flush()?;
+
+        let status = Command::new("gpgv")
+            .arg("--keyring")
+            .arg(&self.keyring)
+            .arg(signature_file.path())
+            .arg(signed_data.path())
+            .status()?;
+        if !status.success() {
+            return Err(AprilError::SignatureVerification(format!(
+                "Failed to verify the signature of {}: {}",
+                deb_path.display(),
+                status
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_package_has_signature() {
+    fn ar_member(name: &str, content: &[u8]) -> Vec<u8> {
+        let mut member = format!(
+            "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+            name,
+            0,
+            0,
+            0,
+            100644,
+            content.len()
+        )
+        .into_bytes();
+        member.extend_from_slice(content);
+        if content.len() % 2 == 1 {
+            member.push(b'\n');
+        }
+        member
+    }
+
+    let mut unsigned = AR_MAGIC.to_vec();
+    unsigned.extend(ar_member("debian-binary", b"2.0\n"));
+    unsigned.extend(ar_member("control.tar.xz", b"abc"));
+    let mut signed = unsigned.clone();
+    signed.extend(ar_member(ORIGIN_SIGNATURE_MEMBER, b"signature"));
+
+    let dir = tempfile::tempdir().unwrap();
+    let unsigned_path = dir.path().join("unsigned.deb");
+    let signed_path = dir.path().join("signed.deb");
+    std::fs::write(&unsigned_path, unsigned).unwrap();
+    std::fs::write(&signed_path, signed).unwrap();
+    assert!(!package_has_signature(&unsigned_path).unwrap());
+    assert!(package_has_signature(&signed_path).unwrap());
+}
@@ -0,0 +1,31 @@
+use std::process::Command;
+
+#[test]
+fn test_missing_config() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("pkgroot");
+    std::fs::create_dir_all(root.join("DEBIAN")).unwrap();
+    std::fs::write(
+        root.join("DEBIAN/control"),
+        "Package: foo\nVersion: 1.0\nArchitecture: all\n",
+    )
+    .unwrap();
+    let config = dir.path().join("missing.json");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_appam"))
+        .arg("apply")
+        .arg("--root")
+        .arg(&root)
+        .arg("-c")
+        .arg(&config)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with(&format!(
+        "error: Failed to open APRIL configuration file {}: ",
+        config.display()
+    )));
+    assert_eq!(stderr.lines().count(), 1);
+    assert!(!stderr.contains("panicked"));
+}
@@ -0,0 +1,44 @@
+//! The process umask is shared by all the threads, so this test changes it in its own test binary
+
+use std::{os::unix::fs::PermissionsExt, path::Path};
+
+use appam::{AprilPackage, ReconstructOptions};
+
+#[test]
+fn test_created_file_mode_ignores_process_umask() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("pkgroot");
+    std::fs::create_dir_all(root.join("DEBIAN")).unwrap();
+    std::fs::write(
+        root.join("DEBIAN/control"),
+        "Package: foo\nVersion: 1.0\nArchitecture: all\n",
+    )
+    .unwrap();
+    let input = r#"{
+        "schema": "0",
+        "name": "foo",
+        "compatible_versions": "*",
+        "overrides": {},
+        "files": {
+            "/usr/share/foo/data": { "action": "add", "arg": "file::data:,foo" },
+            "/usr/lib/foo/plugins": { "action": "mkdir" }
+        }
+    }"#;
+    let data: AprilPackage = serde_json::from_str(input).unwrap();
+    let actions = appam::plan_actions_from_april_data(&data).unwrap();
+
+    // SAFETY: no other thread creates files while the umask is changed
+    let old_umask = unsafe { libc::umask(0o077) };
+    let result = appam::apply_actions_to_tree(&root, &actions, &ReconstructOptions::default());
+    unsafe { libc::umask(old_umask) };
+    result.unwrap();
+
+    let mode = |path: &str| {
+        let path = root.join(Path::new(path).strip_prefix("/").unwrap());
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    };
+    assert_eq!(mode("/usr/share/foo/data"), 0o644);
+    assert_eq!(mode("/usr/share/foo"), 0o755);
+    assert_eq!(mode("/usr/lib/foo/plugins"), 0o755);
+    assert_eq!(mode("/usr/lib/foo"), 0o755);
+}